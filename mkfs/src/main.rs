@@ -0,0 +1,670 @@
+// build a file system image for the kernel, or check one.
+//
+//   mkfs fs.img files...   format fs.img and copy files into /
+//   mkfs -c fs.img         check fs.img for inconsistencies
+//
+// the on-disk structures come straight from the kernel sources, so the
+// two can't disagree about the layout.
+
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::process::exit;
+
+#[path = "../../src/params.rs"]
+mod params;
+
+#[path = "../../src/fs/layout.rs"]
+mod layout;
+
+use layout::{
+    bblock, iblock, Dinode, Dirent, SuperBlock, BPB, DIRSIZ, FSMAGIC, IPB, MAXFILE, NDIRECT,
+    NINDIRECT, ROOTINO, T_DIR, T_FILE,
+};
+use params::{BSIZE, FSSIZE, LOGSIZE};
+
+// the image is written in host byte order; the kernel reads it as
+// riscv, little-endian.
+#[cfg(target_endian = "big")]
+compile_error!("mkfs writes little-endian images and must run on a little-endian host");
+
+const NINODES: u32 = 200;
+
+// disk layout:
+// [ boot block | sb block | log | inode blocks | free bit map | data blocks ]
+const NBITMAP: u32 = (FSSIZE / BPB + 1) as u32;
+const NINODEBLOCKS: u32 = NINODES / IPB as u32 + 1;
+const NLOG: u32 = LOGSIZE as u32;
+// number of meta blocks (boot, sb, nlog, inode, bitmap)
+const NMETA: u32 = 2 + NLOG + NINODEBLOCKS + NBITMAP;
+// number of data blocks
+const NBLOCKS: u32 = FSSIZE as u32 - NMETA;
+
+type Block = [u8; BSIZE];
+
+fn as_bytes<T: Copy>(v: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v as *const T as *const u8, size_of::<T>()) }
+}
+
+fn from_bytes<T: Copy + Default>(b: &[u8]) -> T {
+    let mut v = T::default();
+    let len = size_of::<T>();
+    unsafe {
+        std::slice::from_raw_parts_mut(&mut v as *mut T as *mut u8, len).copy_from_slice(&b[..len])
+    };
+    v
+}
+
+fn block_u32(buf: &Block, i: usize) -> u32 {
+    u32::from_le_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap())
+}
+
+struct Image {
+    file: File,
+    sb: SuperBlock,
+    freeinode: u32,
+    freeblock: u32,
+}
+
+impl Image {
+    fn wsect(&mut self, sec: u32, buf: &Block) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(sec as u64 * BSIZE as u64))?;
+        self.file.write_all(buf)
+    }
+
+    fn rsect(&mut self, sec: u32) -> io::Result<Block> {
+        let mut buf = [0; BSIZE];
+        self.file.seek(SeekFrom::Start(sec as u64 * BSIZE as u64))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn dinode_off(inum: u32) -> usize {
+        (inum as usize % IPB) * size_of::<Dinode>()
+    }
+
+    fn winode(&mut self, inum: u32, din: &Dinode) -> io::Result<()> {
+        let bn = iblock(inum, &self.sb);
+        let mut buf = self.rsect(bn)?;
+        let off = Self::dinode_off(inum);
+        buf[off..off + size_of::<Dinode>()].copy_from_slice(as_bytes(din));
+        self.wsect(bn, &buf)
+    }
+
+    fn rinode(&mut self, inum: u32) -> io::Result<Dinode> {
+        let buf = self.rsect(iblock(inum, &self.sb))?;
+        Ok(from_bytes(&buf[Self::dinode_off(inum)..]))
+    }
+
+    fn ialloc(&mut self, typ: i16) -> io::Result<u32> {
+        let inum = self.freeinode;
+        if inum >= self.sb.ninodes {
+            return Err(io::Error::other("out of inodes"));
+        }
+        self.freeinode += 1;
+        let din = Dinode {
+            typ,
+            nlink: 1,
+            ..Default::default()
+        };
+        self.winode(inum, &din)?;
+        Ok(inum)
+    }
+
+    fn alloc_block(&mut self) -> io::Result<u32> {
+        if self.freeblock >= self.sb.size {
+            return Err(io::Error::other("out of blocks"));
+        }
+        self.freeblock += 1;
+        Ok(self.freeblock - 1)
+    }
+
+    // mark the blocks handed out so far as in use.
+    fn balloc(&mut self) -> io::Result<()> {
+        let used = self.freeblock;
+        if used as usize >= BPB {
+            return Err(io::Error::other("bitmap spans more than one block"));
+        }
+        let mut buf = [0; BSIZE];
+        for i in 0..used as usize {
+            buf[i / 8] |= 1 << (i % 8);
+        }
+        self.wsect(self.sb.bmapstart, &buf)
+    }
+
+    // append data to the end of inode inum.
+    fn iappend(&mut self, inum: u32, mut data: &[u8]) -> io::Result<()> {
+        let mut din = self.rinode(inum)?;
+        let mut off = din.size as usize;
+        while !data.is_empty() {
+            let fbn = off / BSIZE;
+            if fbn >= MAXFILE {
+                return Err(io::Error::other("file too big"));
+            }
+            let x = if fbn < NDIRECT {
+                if din.addrs[fbn] == 0 {
+                    din.addrs[fbn] = self.alloc_block()?;
+                }
+                din.addrs[fbn]
+            } else {
+                if din.addrs[NDIRECT] == 0 {
+                    din.addrs[NDIRECT] = self.alloc_block()?;
+                }
+                let mut indirect = self.rsect(din.addrs[NDIRECT])?;
+                let i = fbn - NDIRECT;
+                let mut x = block_u32(&indirect, i);
+                if x == 0 {
+                    x = self.alloc_block()?;
+                    indirect[i * 4..i * 4 + 4].copy_from_slice(&x.to_le_bytes());
+                    self.wsect(din.addrs[NDIRECT], &indirect)?;
+                }
+                x
+            };
+            let start = off % BSIZE;
+            let n = data.len().min(BSIZE - start);
+            let mut buf = self.rsect(x)?;
+            buf[start..start + n].copy_from_slice(&data[..n]);
+            self.wsect(x, &buf)?;
+            off += n;
+            data = &data[n..];
+        }
+        din.size = off as u32;
+        self.winode(inum, &din)
+    }
+
+    fn add_dirent(&mut self, dir: u32, inum: u32, name: &[u8]) -> io::Result<()> {
+        let mut de = Dirent {
+            inum: inum as u16,
+            ..Default::default()
+        };
+        de.name[..name.len()].copy_from_slice(name);
+        self.iappend(dir, as_bytes(&de))
+    }
+}
+
+// the name a file gets in /: its base name, minus the leading _ that
+// keeps make from confusing user programs with their sources.
+fn short_name(path: &str) -> Option<Vec<u8>> {
+    let base = Path::new(path).file_name()?.to_str()?;
+    let name = base.strip_prefix('_').unwrap_or(base).as_bytes().to_vec();
+    if name.is_empty() || name.len() > DIRSIZ || name.contains(&b'/') {
+        return None;
+    }
+    Some(name)
+}
+
+fn mkfs(img: &str, files: &[String]) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(img)?;
+
+    let sb = SuperBlock {
+        magic: FSMAGIC,
+        size: FSSIZE as u32,
+        nblocks: NBLOCKS,
+        ninodes: NINODES,
+        nlog: NLOG,
+        logstart: 2,
+        inodestart: 2 + NLOG,
+        bmapstart: 2 + NLOG + NINODEBLOCKS,
+    };
+    println!(
+        "nmeta {} (boot, super, log blocks {} inode blocks {}, bitmap blocks {}) blocks {} total {}",
+        NMETA, NLOG, NINODEBLOCKS, NBITMAP, NBLOCKS, FSSIZE
+    );
+
+    let mut image = Image {
+        file,
+        sb,
+        freeinode: 1,
+        freeblock: NMETA, // the first free block that we can allocate
+    };
+
+    let zeroes = [0; BSIZE];
+    for i in 0..FSSIZE as u32 {
+        image.wsect(i, &zeroes)?;
+    }
+
+    let mut buf = [0; BSIZE];
+    buf[..size_of::<SuperBlock>()].copy_from_slice(as_bytes(&sb));
+    image.wsect(1, &buf)?;
+
+    let rootino = image.ialloc(T_DIR)?;
+    assert_eq!(rootino, ROOTINO);
+    image.add_dirent(rootino, rootino, b".")?;
+    image.add_dirent(rootino, rootino, b"..")?;
+
+    let mut names = HashSet::new();
+    for path in files {
+        let name = short_name(path).ok_or_else(|| {
+            io::Error::other(format!("{}: name must be 1 to {} bytes", path, DIRSIZ))
+        })?;
+        if !names.insert(name.clone()) {
+            return Err(io::Error::other(format!("{}: duplicate name", path)));
+        }
+        let data = std::fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+
+        let inum = image.ialloc(T_FILE)?;
+        image.add_dirent(rootino, inum, &name)?;
+        image.iappend(inum, &data)?;
+    }
+
+    // fix size of root inode dir
+    let mut din = image.rinode(rootino)?;
+    din.size = din.size.div_ceil(BSIZE as u32) * BSIZE as u32;
+    image.winode(rootino, &din)?;
+
+    image.balloc()?;
+    println!(
+        "{} inodes, {} of {} blocks used",
+        image.freeinode - 1,
+        image.freeblock,
+        FSSIZE
+    );
+    Ok(())
+}
+
+// fsck: walk every inode and directory and compare what they use with
+// the bitmap and the link counts.
+struct Checker {
+    image: Image,
+    errors: usize,
+}
+
+impl Checker {
+    fn error(&mut self, msg: String) {
+        eprintln!("{}", msg);
+        self.errors += 1;
+    }
+
+    // the data blocks of an inode, indirect block included.
+    fn blocks(&mut self, inum: u32, din: &Dinode) -> io::Result<Vec<u32>> {
+        let size = self.image.sb.size;
+        let datastart = self.image.sb.bmapstart + self.image.sb.size / BPB as u32 + 1;
+        let mut blocks = Vec::new();
+        let mut check = |checker: &mut Checker, b: u32| {
+            if b < datastart || b >= size {
+                checker.error(format!("inode {}: block {} out of range", inum, b));
+                false
+            } else {
+                blocks.push(b);
+                true
+            }
+        };
+        for &b in &din.addrs[..NDIRECT] {
+            if b != 0 {
+                check(self, b);
+            }
+        }
+        let ind = din.addrs[NDIRECT];
+        if ind != 0 && check(self, ind) {
+            let buf = self.image.rsect(ind)?;
+            for i in 0..NINDIRECT {
+                let b = block_u32(&buf, i);
+                if b != 0 {
+                    check(self, b);
+                }
+            }
+        }
+        if din.size as usize > MAXFILE * BSIZE {
+            self.error(format!("inode {}: size {} too big", inum, din.size));
+        }
+        Ok(blocks)
+    }
+
+    // the contents of a directory, skipping holes and bad blocks.
+    fn dirents(&mut self, din: &Dinode) -> io::Result<Vec<Dirent>> {
+        let size = self.image.sb.size;
+        let indirect = match din.addrs[NDIRECT] {
+            b if b != 0 && b < size => Some(self.image.rsect(b)?),
+            _ => None,
+        };
+        let mut data = Vec::new();
+        for fbn in 0..(din.size as usize).div_ceil(BSIZE).min(MAXFILE) {
+            let b = if fbn < NDIRECT {
+                din.addrs[fbn]
+            } else {
+                indirect.map_or(0, |buf| block_u32(&buf, fbn - NDIRECT))
+            };
+            if b == 0 || b >= size {
+                data.extend_from_slice(&[0; BSIZE]);
+            } else {
+                data.extend_from_slice(&self.image.rsect(b)?);
+            }
+        }
+        data.truncate(din.size as usize);
+        Ok(data
+            .chunks_exact(size_of::<Dirent>())
+            .map(from_bytes::<Dirent>)
+            .collect())
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        let sb = self.image.sb;
+        let nmeta = sb.bmapstart + sb.size / BPB as u32 + 1;
+
+        // every block belongs to at most one inode.
+        let mut inodes = HashMap::new();
+        let mut owner = HashMap::new();
+        for inum in 1..sb.ninodes {
+            let din = self.image.rinode(inum)?;
+            if din.typ == 0 {
+                continue;
+            }
+            if !(T_DIR..=layout::T_DEVICE).contains(&din.typ) {
+                self.error(format!("inode {}: bad type {}", inum, din.typ));
+                continue;
+            }
+            for b in self.blocks(inum, &din)? {
+                if let Some(other) = owner.insert(b, inum) {
+                    self.error(format!("block {} used by inodes {} and {}", b, other, inum));
+                }
+            }
+            inodes.insert(inum, din);
+        }
+
+        // the bitmap marks exactly the meta blocks and the used ones.
+        for b in 0..sb.size {
+            let buf = self.image.rsect(bblock(b, &sb))?;
+            let bi = b as usize % BPB;
+            let marked = buf[bi / 8] & (1 << (bi % 8)) != 0;
+            let used = b < nmeta || owner.contains_key(&b);
+            if used && !marked {
+                self.error(format!("block {} in use but marked free", b));
+            } else if marked && !used {
+                self.error(format!("block {} marked in use but free", b));
+            }
+        }
+
+        // walk the tree from the root, counting links. "." doesn't
+        // count; ".." counts for the parent.
+        match inodes.get(&ROOTINO) {
+            Some(root) if root.typ == T_DIR => {}
+            _ => {
+                self.error("root is not a directory".to_string());
+                return Ok(());
+            }
+        }
+        let mut links: HashMap<u32, i16> = HashMap::new();
+        let mut visited = HashSet::from([ROOTINO]);
+        let mut stack = vec![(ROOTINO, ROOTINO)];
+        while let Some((dir, parent)) = stack.pop() {
+            let din = inodes[&dir];
+            for de in self.dirents(&din)? {
+                if de.inum == 0 {
+                    continue;
+                }
+                let inum = de.inum as u32;
+                let name = String::from_utf8_lossy(de.name()).into_owned();
+                let Some(child) = inodes.get(&inum) else {
+                    self.error(format!(
+                        "dir {}: entry {} refers to free inode {}",
+                        dir, name, inum
+                    ));
+                    continue;
+                };
+                if name == "." {
+                    if inum != dir {
+                        self.error(format!("dir {}: . refers to {}", dir, inum));
+                    }
+                    continue;
+                }
+                if name == ".." && inum != parent {
+                    self.error(format!("dir {}: .. refers to {}, not {}", dir, inum, parent));
+                }
+                *links.entry(inum).or_default() += 1;
+                if name != ".." && child.typ == T_DIR && visited.insert(inum) {
+                    stack.push((inum, dir));
+                }
+            }
+        }
+
+        let mut inums: Vec<_> = inodes.keys().copied().collect();
+        inums.sort();
+        for inum in inums {
+            let nlink = inodes[&inum].nlink;
+            let found = links.get(&inum).copied().unwrap_or(0);
+            if found == 0 {
+                self.error(format!(
+                    "inode {}: allocated but not in any directory",
+                    inum
+                ));
+            } else if nlink != found {
+                self.error(format!(
+                    "inode {}: nlink {} but {} links found",
+                    inum, nlink, found
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn fsck(img: &str) -> io::Result<usize> {
+    let mut file = File::open(img)?;
+    let mut buf = [0; BSIZE];
+    file.seek(SeekFrom::Start(BSIZE as u64))?;
+    file.read_exact(&mut buf)?;
+    let sb: SuperBlock = from_bytes(&buf);
+    if sb.magic != FSMAGIC {
+        return Err(io::Error::other("bad super block magic"));
+    }
+    if file.metadata()?.len() < sb.size as u64 * BSIZE as u64 {
+        return Err(io::Error::other("image shorter than its super block says"));
+    }
+    if sb.size as usize >= BPB {
+        return Err(io::Error::other("bitmap spans more than one block"));
+    }
+
+    let mut checker = Checker {
+        image: Image {
+            file,
+            sb,
+            freeinode: 0,
+            freeblock: 0,
+        },
+        errors: 0,
+    };
+    checker.check()?;
+    Ok(checker.errors)
+}
+
+fn usage() -> ! {
+    eprintln!("usage: mkfs fs.img files...");
+    eprintln!("       mkfs -c fs.img");
+    exit(2);
+}
+
+fn main() {
+    assert_eq!(BSIZE % size_of::<Dinode>(), 0);
+    assert_eq!(BSIZE % size_of::<Dirent>(), 0);
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [flag, img] if flag == "-c" => match fsck(img) {
+            Ok(0) => println!("{}: clean", img),
+            Ok(n) => {
+                eprintln!("{}: {} errors", img, n);
+                exit(1);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", img, e);
+                exit(1);
+            }
+        },
+        [img, files @ ..] if !img.starts_with('-') => {
+            if let Err(e) = mkfs(img, files) {
+                eprintln!("mkfs: {}", e);
+                exit(1);
+            }
+        }
+        _ => usage(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a scratch directory for one test, removed when it is dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("mkfs-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.0.join(name).to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // an image holding one small file and one big enough to need the
+    // indirect block.
+    fn make_image(dir: &TempDir) -> String {
+        let small = dir.path("small");
+        std::fs::write(&small, b"hello\n").unwrap();
+        let big = dir.path("_big");
+        let data: Vec<u8> = (0..(NDIRECT + 3) * BSIZE).map(|i| i as u8).collect();
+        std::fs::write(&big, data).unwrap();
+        let img = dir.path("fs.img");
+        mkfs(&img, &[small, big]).unwrap();
+        img
+    }
+
+    fn open_image(img: &str) -> Image {
+        let mut file = OpenOptions::new().read(true).write(true).open(img).unwrap();
+        let mut buf = [0; BSIZE];
+        file.seek(SeekFrom::Start(BSIZE as u64)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        Image {
+            file,
+            sb: from_bytes(&buf),
+            freeinode: 0,
+            freeblock: 0,
+        }
+    }
+
+    #[test]
+    fn fresh_image_is_clean() {
+        let dir = TempDir::new("clean");
+        let img = make_image(&dir);
+        assert_eq!(fsck(&img).unwrap(), 0);
+    }
+
+    #[test]
+    fn files_read_back() {
+        let dir = TempDir::new("readback");
+        let img = make_image(&dir);
+        let mut checker = Checker {
+            image: open_image(&img),
+            errors: 0,
+        };
+        let root = checker.image.rinode(ROOTINO).unwrap();
+        let names: Vec<Vec<u8>> = checker
+            .dirents(&root)
+            .unwrap()
+            .iter()
+            .filter(|de| de.inum != 0)
+            .map(|de| de.name().to_vec())
+            .collect();
+        assert_eq!(names, [&b"."[..], b"..", b"small", b"big"]);
+        let big = checker.image.rinode(ROOTINO + 2).unwrap();
+        assert_eq!(big.typ, T_FILE);
+        assert_eq!(big.size as usize, (NDIRECT + 3) * BSIZE);
+        assert_ne!(big.addrs[NDIRECT], 0);
+    }
+
+    #[test]
+    fn fsck_finds_free_block_in_use() {
+        let dir = TempDir::new("bitmap");
+        let img = make_image(&dir);
+        let mut image = open_image(&img);
+        // mark the first data block, the root directory's, free.
+        let root = image.rinode(ROOTINO).unwrap();
+        let b = root.addrs[0];
+        let sb = image.sb;
+        let mut buf = image.rsect(bblock(b, &sb)).unwrap();
+        let bi = b as usize % BPB;
+        buf[bi / 8] &= !(1 << (bi % 8));
+        image.wsect(bblock(b, &sb), &buf).unwrap();
+        drop(image);
+        assert_eq!(fsck(&img).unwrap(), 1);
+    }
+
+    // add an empty directory /d by hand, since mkfs only fills /.
+    fn add_subdir(img: &str) -> u32 {
+        let mut image = open_image(img);
+        let sb = image.sb;
+        let buf = image.rsect(sb.bmapstart).unwrap();
+        image.freeblock = (0..sb.size)
+            .find(|&b| buf[b as usize / 8] & (1 << (b % 8)) == 0)
+            .unwrap();
+        image.freeinode = (ROOTINO..sb.ninodes)
+            .find(|&inum| image.rinode(inum).unwrap().typ == 0)
+            .unwrap();
+        let dir = image.ialloc(T_DIR).unwrap();
+        image.add_dirent(dir, dir, b".").unwrap();
+        image.add_dirent(dir, ROOTINO, b"..").unwrap();
+        image.add_dirent(ROOTINO, dir, b"d").unwrap();
+        let mut root = image.rinode(ROOTINO).unwrap();
+        root.nlink += 1;
+        image.winode(ROOTINO, &root).unwrap();
+        image.balloc().unwrap();
+        dir
+    }
+
+    #[test]
+    fn subdirectory_is_clean() {
+        let dir = TempDir::new("subdir");
+        let img = make_image(&dir);
+        add_subdir(&img);
+        assert_eq!(fsck(&img).unwrap(), 0);
+    }
+
+    #[test]
+    fn fsck_finds_bad_dotdot() {
+        let dir = TempDir::new("dotdot");
+        let img = make_image(&dir);
+        let sub = add_subdir(&img);
+        let mut image = open_image(&img);
+        // point /d/.. at /d itself.
+        let din = image.rinode(sub).unwrap();
+        let mut buf = image.rsect(din.addrs[0]).unwrap();
+        let off = size_of::<Dirent>();
+        buf[off..off + 2].copy_from_slice(&(sub as u16).to_le_bytes());
+        image.wsect(din.addrs[0], &buf).unwrap();
+        drop(image);
+        // the bad entry, and the root's and /d's link counts.
+        assert_eq!(fsck(&img).unwrap(), 3);
+    }
+
+    #[test]
+    fn fsck_finds_bad_link_count() {
+        let dir = TempDir::new("nlink");
+        let img = make_image(&dir);
+        let mut image = open_image(&img);
+        let inum = ROOTINO + 1;
+        let mut din = image.rinode(inum).unwrap();
+        din.nlink = 2;
+        image.winode(inum, &din).unwrap();
+        drop(image);
+        assert_eq!(fsck(&img).unwrap(), 1);
+    }
+}
@@ -0,0 +1,174 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::fdt;
+use crate::imsic::{imsic_layout, imsic_present, msi_alloc, msi_free};
+use crate::irq::{handle_irq, NIRQ};
+use crate::proc::cpuid;
+use crate::spin_lock::SpinMutex;
+use crate::{info, warn};
+
+// the advanced platform-level interrupt controller, which takes the
+// place of the plic with qemu -machine virt,aia=aplic-imsic. there is
+// one for machine mode, which owns every wired source, and a child of
+// it for supervisor mode. we hand all sources to the child and run it
+// in msi delivery mode: an asserted source becomes a write to the imsic
+// of its target hart, so wired irqs arrive like any other msi. see
+// chapter 4 of the AIA spec. irq.rs decides what is enabled; this file
+// only knows the registers.
+
+// registers of an interrupt domain.
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG: usize = 0x0004; // source i at SOURCECFG + (i - 1) * 4
+const SMSIADDRCFG: usize = 0x1bc8; // machine domain only
+const SMSIADDRCFGH: usize = 0x1bcc;
+const IN_CLRIP: usize = 0x1d00; // reads the inputs, a bit per source
+const SETIENUM: usize = 0x1edc;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const TARGET: usize = 0x3004; // source i at TARGET + (i - 1) * 4
+
+const DOMAINCFG_IE: u32 = 1 << 8; // interrupts enabled
+const DOMAINCFG_DM: u32 = 1 << 2; // msi delivery mode
+
+const SOURCECFG_D: u32 = 1 << 10; // delegated to child domain 0
+const SOURCECFG_INACTIVE: u32 = 0;
+const SOURCECFG_LEVEL_HIGH: u32 = 6;
+
+const MSIADDRCFGH_LHXS_SHIFT: u32 = 20;
+const TARGET_HART_SHIFT: u32 = 18;
+
+// the supervisor domain's registers, 0 without an aplic.
+static APLIC_BASE: AtomicUsize = AtomicUsize::new(0);
+static APLIC_SIZE: AtomicUsize = AtomicUsize::new(0);
+static APLIC_NUM_SOURCES: AtomicUsize = AtomicUsize::new(0);
+
+// the imsic identity each enabled source is delivered as, 0 for none.
+static APLIC_IDS: SpinMutex<[usize; NIRQ]> = SpinMutex::new([0; NIRQ]);
+
+pub fn aplic_present() -> bool {
+    APLIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+fn read(base: usize, reg: usize) -> u32 {
+    unsafe { read_volatile((base + reg) as *const u32) }
+}
+
+fn write(base: usize, reg: usize, value: u32) {
+    unsafe { write_volatile((base + reg) as *mut u32, value) }
+}
+
+// find the aplics in the device tree, delegate every source from the
+// machine domain to the supervisor one, and point the latter's msis at
+// the imsic. must run after imsic_init() and before paging.
+pub fn aplic_init() {
+    if !imsic_present() {
+        return;
+    }
+    let fdt = match fdt::fdt() {
+        Some(fdt) => fdt,
+        None => return,
+    };
+    let address_cells = fdt.root().property_u32("#address-cells").unwrap_or(2) as usize;
+    let size_cells = fdt.root().property_u32("#size-cells").unwrap_or(2) as usize;
+    let mut machine = None;
+    let mut supervisor = None;
+    for node in fdt.find_all_compatible("riscv,aplic") {
+        let reg = match node.reg(address_cells, size_cells) {
+            Some((base, size)) => (base as usize, size as usize),
+            None => continue,
+        };
+        let num_sources = node.property_u32("riscv,num-sources").unwrap_or(0) as usize;
+        // the machine domain names its children.
+        if node.property("riscv,children").is_some() {
+            machine = Some(reg.0);
+        } else {
+            supervisor = Some((reg, num_sources));
+        }
+    }
+    let ((base, size), num_sources) = match supervisor {
+        Some(s) => s,
+        None => return,
+    };
+
+    if let Some(m) = machine {
+        for i in 1..=num_sources {
+            write(m, SOURCECFG + (i - 1) * 4, SOURCECFG_D);
+        }
+        // hart n's supervisor file is n strides past hart 0's.
+        let (file, stride) = imsic_layout();
+        let lhxs = stride.trailing_zeros() - 12;
+        write(m, SMSIADDRCFG, (file >> 12) as u32);
+        write(m, SMSIADDRCFGH, lhxs << MSIADDRCFGH_LHXS_SHIFT);
+        write(m, DOMAINCFG, DOMAINCFG_DM);
+    } else {
+        warn!("no machine-level aplic; trusting firmware to have delegated");
+    }
+
+    for i in 1..=num_sources {
+        write(base, SOURCECFG + (i - 1) * 4, SOURCECFG_INACTIVE);
+    }
+    write(base, DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+    if read(base, DOMAINCFG) & DOMAINCFG_DM == 0 {
+        warn!("aplic at {:#x} can't deliver msis", base);
+        return;
+    }
+    APLIC_SIZE.store(size, Ordering::Relaxed);
+    APLIC_NUM_SOURCES.store(num_sources, Ordering::Relaxed);
+    APLIC_BASE.store(base, Ordering::Relaxed);
+    info!("supervisor domain at {:#x}, {} sources", base, num_sources);
+}
+
+// where the supervisor domain's registers are, for the kernel page table.
+pub fn aplic_window() -> Option<(usize, usize)> {
+    if !aplic_present() {
+        return None;
+    }
+    let size = APLIC_SIZE.load(Ordering::Relaxed);
+    Some((APLIC_BASE.load(Ordering::Relaxed), size.next_multiple_of(0x1000)))
+}
+
+// an imsic identity of a source arrived.
+fn aplic_msi(irq: usize) {
+    handle_irq(irq);
+    // a level source only sends again once it goes low and high; if it
+    // is still high, the handler left work behind, so ask again.
+    let base = APLIC_BASE.load(Ordering::Relaxed);
+    if read(base, IN_CLRIP + (irq / 32) * 4) & (1 << (irq % 32)) != 0 {
+        write(base, SETIPNUM_LE, irq as u32);
+    }
+}
+
+// deliver source irq as a fresh imsic identity on this hart, or stop.
+pub fn aplic_set_enable(irq: usize, enable: bool) {
+    if !aplic_present() || irq == 0 || irq > APLIC_NUM_SOURCES.load(Ordering::Relaxed) {
+        return;
+    }
+    let base = APLIC_BASE.load(Ordering::Relaxed);
+    let mut ids = APLIC_IDS.lock();
+    if enable == (ids[irq] != 0) {
+        return;
+    }
+    if enable {
+        let message = match msi_alloc(aplic_msi, irq) {
+            Some(message) => message,
+            None => {
+                warn!("irq {}: no msi identity left", irq);
+                return;
+            }
+        };
+        ids[irq] = message.id;
+        write(base, SOURCECFG + (irq - 1) * 4, SOURCECFG_LEVEL_HIGH);
+        write(
+            base,
+            TARGET + (irq - 1) * 4,
+            (cpuid() as u32) << TARGET_HART_SHIFT | message.id as u32,
+        );
+        write(base, SETIENUM, irq as u32);
+    } else {
+        write(base, CLRIENUM, irq as u32);
+        write(base, SOURCECFG + (irq - 1) * 4, SOURCECFG_INACTIVE);
+        msi_free(ids[irq]);
+        ids[irq] = 0;
+    }
+}
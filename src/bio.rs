@@ -0,0 +1,206 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use crate::params::{BSIZE, NBUF};
+use crate::proc::{either_copyin, either_copyout};
+use crate::sleep_lock::{SleepLock, SleepLockGuard};
+use crate::spin_lock::SpinMutex;
+use crate::virtio::virtio_blk::virtio_disk_rw;
+
+// buffer cache.
+//
+// the buffer cache holds cached copies of disk block contents. caching
+// disk blocks in memory reduces the number of disk reads and also
+// provides a synchronization point for disk blocks used by multiple
+// processes.
+//
+// interface:
+// * to get a buffer for a particular disk block, call bread.
+// * after changing buffer data, call bwrite to write it to disk.
+// * when done with the buffer, call brelse (or just drop it).
+// * do not use the buffer after calling brelse.
+// * only one process at a time can use a buffer,
+//     so do not keep them longer than necessary.
+//
+// a device number is the index of a virtio disk.
+
+pub struct BufData {
+    pub valid: bool, // has data been read from disk?
+    pub dev: usize,
+    pub blockno: u32,
+    pub data: [u8; BSIZE],
+}
+
+// which block a buffer holds and who uses it. protected by BCACHE,
+// unlike the contents, which belong to whoever holds the buffer's
+// sleep lock.
+#[derive(Clone, Copy)]
+struct BufMeta {
+    block: Option<(usize, u32)>, // (dev, blockno)
+    refcnt: usize,
+    last_use: u64, // when refcnt last dropped to 0
+}
+
+struct BCache {
+    meta: [BufMeta; NBUF],
+    ticks: u64,
+}
+
+static BCACHE: SpinMutex<BCache> = SpinMutex::new(BCache {
+    meta: [BufMeta {
+        block: None,
+        refcnt: 0,
+        last_use: 0,
+    }; NBUF],
+    ticks: 0,
+});
+
+static BUFS: [SleepLock<BufData>; NBUF] = [const {
+    SleepLock::new(BufData {
+        valid: false,
+        dev: 0,
+        blockno: 0,
+        data: [0; BSIZE],
+    })
+}; NBUF];
+
+// a locked buffer. dropping it is brelse().
+pub struct Buf {
+    index: usize,
+    guard: ManuallyDrop<SleepLockGuard<'static, BufData>>,
+}
+
+impl Deref for Buf {
+    type Target = BufData;
+    fn deref(&self) -> &BufData {
+        &self.guard
+    }
+}
+
+impl DerefMut for Buf {
+    fn deref_mut(&mut self) -> &mut BufData {
+        &mut self.guard
+    }
+}
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        let mut bcache = BCACHE.lock();
+        bcache.ticks += 1;
+        let ticks = bcache.ticks;
+        let meta = &mut bcache.meta[self.index];
+        meta.refcnt -= 1;
+        if meta.refcnt == 0 {
+            meta.last_use = ticks;
+        }
+    }
+}
+
+// look through buffer cache for block on device dev.
+// if not found, allocate a buffer.
+// in either case, return locked buffer.
+fn bget(dev: usize, blockno: u32) -> Buf {
+    let mut bcache = BCACHE.lock();
+
+    // is the block already cached?
+    if let Some(i) = bcache
+        .meta
+        .iter()
+        .position(|m| m.block == Some((dev, blockno)))
+    {
+        bcache.meta[i].refcnt += 1;
+        drop(bcache);
+        return Buf {
+            index: i,
+            guard: ManuallyDrop::new(BUFS[i].lock()),
+        };
+    }
+
+    // not cached.
+    // recycle the least recently used unused buffer.
+    let i = (0..NBUF)
+        .filter(|&i| bcache.meta[i].refcnt == 0)
+        .min_by_key(|&i| bcache.meta[i].last_use)
+        .expect("bget: no buffers");
+    bcache.meta[i].block = Some((dev, blockno));
+    bcache.meta[i].refcnt = 1;
+    // nobody holds or waits for a buffer with refcnt 0, so this
+    // doesn't sleep. taking it before BCACHE is released keeps anyone
+    // who finds the block from seeing the old contents.
+    let mut guard = BUFS[i].lock();
+    drop(bcache);
+    guard.valid = false;
+    guard.dev = dev;
+    guard.blockno = blockno;
+    Buf {
+        index: i,
+        guard: ManuallyDrop::new(guard),
+    }
+}
+
+// return a locked buf with the contents of the indicated block.
+pub fn bread(dev: usize, blockno: u32) -> Buf {
+    let mut b = bget(dev, blockno);
+    if !b.valid {
+        let b = &mut *b;
+        virtio_disk_rw(b.dev, b.blockno, &mut b.data, false);
+        b.valid = true;
+    }
+    b
+}
+
+// write b's contents to disk.
+pub fn bwrite(b: &mut Buf) {
+    let b = &mut **b;
+    virtio_disk_rw(b.dev, b.blockno, &mut b.data, true);
+}
+
+// release a locked buffer.
+pub fn brelse(b: Buf) {
+    drop(b);
+}
+
+// keep b's block in the cache after it is released, until bunpin().
+// the log uses this for blocks it has yet to install.
+pub fn bpin(b: &Buf) {
+    BCACHE.lock().meta[b.index].refcnt += 1;
+}
+
+pub fn bunpin(b: &Buf) {
+    BCACHE.lock().meta[b.index].refcnt -= 1;
+}
+
+// byte-granular access, for file systems whose sectors and blocks
+// aren't BSIZE. off is a byte offset on disk dev.
+
+// copy n bytes between offset off of disk dev and address addr, one
+// buffer at a time, writing the disk if write is true. addr is a user
+// virtual address if user is true, else a kernel address.
+pub fn copy_disk(dev: usize, off: u64, user: bool, addr: usize, n: usize, write: bool) -> bool {
+    let mut done = 0;
+    while done < n {
+        let pos = off + done as u64;
+        let start = (pos % BSIZE as u64) as usize;
+        let m = (n - done).min(BSIZE - start);
+        let mut b = bread(dev, (pos / BSIZE as u64) as u32);
+        if write {
+            if !either_copyin(&mut b.data[start..start + m], user, addr + done) {
+                return false;
+            }
+            bwrite(&mut b);
+        } else if !either_copyout(user, addr + done, &b.data[start..start + m]) {
+            return false;
+        }
+        done += m;
+    }
+    true
+}
+
+pub fn read_disk(dev: usize, off: u64, dst: &mut [u8]) {
+    copy_disk(dev, off, false, dst.as_mut_ptr() as usize, dst.len(), false);
+}
+
+pub fn write_disk(dev: usize, off: u64, src: &[u8]) {
+    copy_disk(dev, off, false, src.as_ptr() as usize, src.len(), true);
+}
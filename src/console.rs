@@ -0,0 +1,186 @@
+use core::ptr::addr_of;
+
+use crate::file::{Devsw, CONSOLE, DEVSW};
+use crate::proc::{either_copyin, either_copyout, kill, killed, myproc, proc, sleep, wakeup};
+use crate::spin_lock::SpinMutex;
+use crate::uart::{uart_init, uartputc, uartputc_sync};
+
+// console input and output, to the uart.
+// reads are line at a time.
+// implements special input characters:
+//   newline -- end of line
+//   control-h -- backspace
+//   control-u -- kill line
+//   control-d -- end of file
+//   control-c -- kill the foreground process
+
+const INPUT_BUF_SIZE: usize = 128;
+
+const fn ctrl(x: u8) -> u8 {
+    x - b'@' // Control-x
+}
+
+const BACKSPACE: u8 = ctrl(b'H');
+const DELETE: u8 = 0x7f;
+
+struct Cons {
+    buf: [u8; INPUT_BUF_SIZE],
+    r: usize, // read index
+    w: usize, // write index
+    e: usize, // edit index
+    // the process that last read the console; control-c kills it.
+    fg_pid: Option<i32>,
+}
+
+static CONS: SpinMutex<Cons> = SpinMutex::new(Cons {
+    buf: [0; INPUT_BUF_SIZE],
+    r: 0,
+    w: 0,
+    e: 0,
+    fg_pid: None,
+});
+
+// readers waiting for a line sleep on this.
+fn read_chan() -> usize {
+    addr_of!(CONS) as usize
+}
+
+// echo one input character, synchronously.
+fn consputc(c: u8) {
+    if c == BACKSPACE {
+        // if the user typed backspace, overwrite with a space.
+        uartputc_sync(b'\x08');
+        uartputc_sync(b' ');
+        uartputc_sync(b'\x08');
+    } else {
+        uartputc_sync(c);
+    }
+}
+
+// user write()s to the console go here.
+fn console_write(_minor: usize, user_src: bool, src: usize, n: usize) -> isize {
+    for i in 0..n {
+        let mut c = [0u8; 1];
+        if !either_copyin(&mut c, user_src, src + i) {
+            return i as isize;
+        }
+        uartputc(c[0]);
+    }
+    n as isize
+}
+
+// user read()s from the console go here.
+// copy (up to) a whole input line to dst.
+// user_dst indicates whether dst is a user or kernel address.
+fn console_read(_minor: usize, user_dst: bool, mut dst: usize, n: usize) -> isize {
+    let proc_index = match myproc() {
+        Some(i) => i,
+        None => return -1,
+    };
+    let target = n;
+    let mut n = n;
+    let mut cons = CONS.lock();
+    cons.fg_pid = Some(unsafe { proc[proc_index].pid });
+    while n > 0 {
+        // wait until interrupt handler has put some
+        // input into cons.buf.
+        while cons.r == cons.w {
+            if killed(proc_index) {
+                return -1;
+            }
+            cons = sleep(read_chan(), cons);
+        }
+
+        let c = cons.buf[cons.r % INPUT_BUF_SIZE];
+        cons.r += 1;
+
+        if c == ctrl(b'D') {
+            // end-of-file
+            if n < target {
+                // save ^D for next time, to make sure
+                // caller gets a 0-byte result.
+                cons.r -= 1;
+            }
+            break;
+        }
+
+        // copy the input byte to the user-space buffer.
+        if !either_copyout(user_dst, dst, &[c]) {
+            break;
+        }
+        dst += 1;
+        n -= 1;
+
+        if c == b'\n' {
+            // a whole line has arrived, return to
+            // the user-level read().
+            break;
+        }
+    }
+    (target - n) as isize
+}
+
+// the console input interrupt handler.
+// uart_intr() calls this for each input character.
+// do erase/kill processing, append to cons.buf,
+// wake up console_read() if a whole line has arrived.
+pub fn console_intr(c: u8) {
+    let mut cons = CONS.lock();
+    match c {
+        c if c == ctrl(b'U') => {
+            // kill line.
+            while cons.e != cons.w && cons.buf[(cons.e - 1) % INPUT_BUF_SIZE] != b'\n' {
+                cons.e -= 1;
+                consputc(BACKSPACE);
+            }
+        }
+        BACKSPACE | DELETE => {
+            if cons.e != cons.w {
+                cons.e -= 1;
+                consputc(BACKSPACE);
+            }
+        }
+        c if c == ctrl(b'C') => {
+            // throw away the line being edited and kill the reader.
+            cons.e = cons.w;
+            consputc(b'^');
+            consputc(b'C');
+            consputc(b'\n');
+            if let Some(pid) = cons.fg_pid.take() {
+                drop(cons);
+                kill(pid);
+            }
+        }
+        _ => {
+            if c != 0 && cons.e - cons.r < INPUT_BUF_SIZE {
+                let c = if c == b'\r' { b'\n' } else { c };
+
+                // echo back to the user.
+                consputc(c);
+
+                // store for consumption by console_read().
+                let e = cons.e;
+                cons.buf[e % INPUT_BUF_SIZE] = c;
+                cons.e += 1;
+
+                if c == b'\n' || c == ctrl(b'D') || cons.e - cons.r == INPUT_BUF_SIZE {
+                    // wake up console_read() if a whole line (or end-of-file)
+                    // has arrived.
+                    cons.w = cons.e;
+                    wakeup(read_chan());
+                }
+            }
+        }
+    }
+}
+
+pub fn console_init() {
+    uart_init();
+
+    // connect read and write system calls
+    // to console_read and console_write.
+    DEVSW.lock()[CONSOLE] = Some(Devsw {
+        read: console_read,
+        write: console_write,
+    });
+}
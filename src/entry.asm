@@ -2,12 +2,12 @@
     .section .text.entry
     .global _entry
 _entry:
+    # qemu leaves the hartid in a0 and the device tree address in a1;
+    # keep them for start().
     la sp, STACK0
-    li a0, 65536
-    csrr a1, mhartid
-    addi a1, a1, 1
-    mul a0, a0, a1
-    add sp, sp, a0
+    li t0, 65536
+    csrr t1, mhartid
+    addi t1, t1, 1
+    mul t0, t0, t1
+    add sp, sp, t0
     call start
-
-
@@ -0,0 +1,4 @@
+// error numbers. a system call that fails with one returns its
+// negation; everything else still fails with a plain -1.
+pub const EINVAL: isize = 22; // invalid argument
+pub const EPIPE: isize = 32; // write to a pipe with no readers
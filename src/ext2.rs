@@ -0,0 +1,453 @@
+use alloc::sync::Arc;
+use alloc::vec;
+
+use crate::bio::{copy_disk, read_disk};
+use crate::fs::layout::{T_DIR, T_FILE};
+use crate::proc::either_copyout;
+use crate::spin_lock::SpinMutex;
+use crate::stat::Stat;
+use crate::vfs::{alloc_fsid, DirEntry, FileOps, FsType, InodeOps, InodeRef, SuperOps};
+use crate::virtio::virtio_blk::{virtio_disk_exists, NDISK};
+
+// ext2, read-only, for images built on the host with mke2fs -d.
+//
+// nothing is cached above the buffer cache: every operation reads the
+// inode it needs from the disk, so with nothing ever written there is
+// no locking to do either.
+
+const EXT2_MAGIC: u16 = 0xef53;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const ROOT_INO: u32 = 2;
+
+const GOOD_OLD_REV: u32 = 0;
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const GROUP_DESC_SIZE: u64 = 32;
+
+// incompatible features we can read. anything else, like extents or a
+// journal that needs recovery, means this isn't plain ext2.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+// file types, in the top bits of i_mode.
+const S_IFMT: u16 = 0xf000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xa000;
+
+const NDIR_BLOCKS: usize = 12;
+const IND_BLOCK: usize = 12;
+const DIND_BLOCK: usize = 13;
+const TIND_BLOCK: usize = 14;
+const N_BLOCKS: usize = 15;
+
+pub struct Ext2Type;
+
+pub static EXT2: Ext2Type = Ext2Type;
+
+// the disks that have an ext2 mounted from them.
+static MOUNTED: SpinMutex<[bool; NDISK]> = SpinMutex::new([false; NDISK]);
+
+impl FsType for Ext2Type {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn mount(&self, dev: usize) -> Result<Arc<dyn SuperOps>, &'static str> {
+        if !virtio_disk_exists(dev) {
+            return Err("no such disk");
+        }
+        if core::mem::replace(&mut MOUNTED.lock()[dev], true) {
+            return Err("disk is already mounted");
+        }
+        match Volume::read(dev) {
+            Ok(vol) => Ok(Arc::new(Ext2Fs { vol: Arc::new(vol) })),
+            Err(e) => {
+                MOUNTED.lock()[dev] = false;
+                Err(e)
+            }
+        }
+    }
+}
+
+// byte order helpers; ext2 is little-endian throughout.
+fn get16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn get32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+// the parts of an on-disk inode we use.
+struct Dinode {
+    mode: u16,
+    size: u64,
+    links_count: u16,
+    blocks: u32, // 512-byte sectors in use
+    block: [u32; N_BLOCKS],
+}
+
+impl Dinode {
+    fn typ(&self) -> u16 {
+        self.mode & S_IFMT
+    }
+
+    // a short symlink keeps its target in block[] itself.
+    fn fast_symlink(&self) -> bool {
+        self.typ() == S_IFLNK && self.blocks == 0
+    }
+}
+
+// a mounted ext2 volume.
+struct Volume {
+    dev: usize,
+    fsid: usize,
+    block_size: u64,
+    blocks_count: u32,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    gdt_start: u64, // disk offset of the group descriptor table
+}
+
+impl Volume {
+    // read and check the superblock of disk dev.
+    fn read(dev: usize) -> Result<Volume, &'static str> {
+        let mut sb = [0u8; 1024];
+        read_disk(dev, SUPERBLOCK_OFFSET, &mut sb);
+        if get16(&sb, 56) != EXT2_MAGIC {
+            return Err("ext2: bad magic");
+        }
+        let inodes_count = get32(&sb, 0);
+        let blocks_count = get32(&sb, 4);
+        let first_data_block = get32(&sb, 20) as u64;
+        let log_block_size = get32(&sb, 24);
+        let blocks_per_group = get32(&sb, 32);
+        let inodes_per_group = get32(&sb, 40);
+        let rev_level = get32(&sb, 76);
+        let (inode_size, incompat) = if rev_level == GOOD_OLD_REV {
+            (GOOD_OLD_INODE_SIZE, 0)
+        } else {
+            (get16(&sb, 88) as u64, get32(&sb, 96))
+        };
+
+        if log_block_size > 2 {
+            return Err("ext2: unsupported block size");
+        }
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err("ext2: unsupported features");
+        }
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || inodes_count < ROOT_INO
+        {
+            return Err("ext2: bad superblock");
+        }
+
+        let block_size = 1024 << log_block_size;
+        Ok(Volume {
+            dev,
+            fsid: alloc_fsid(),
+            block_size,
+            blocks_count,
+            inodes_count,
+            inodes_per_group,
+            inode_size,
+            // the descriptors start in the block after the superblock.
+            gdt_start: (first_data_block + 1) * block_size,
+        })
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size
+    }
+
+    fn read_inode(&self, inum: u32) -> Option<Dinode> {
+        if inum == 0 || inum > self.inodes_count {
+            return None;
+        }
+        let group = (inum - 1) / self.inodes_per_group;
+        let index = (inum - 1) % self.inodes_per_group;
+
+        let mut gd = [0u8; GROUP_DESC_SIZE as usize];
+        read_disk(
+            self.dev,
+            self.gdt_start + group as u64 * GROUP_DESC_SIZE,
+            &mut gd,
+        );
+        let inode_table = get32(&gd, 8);
+        if inode_table == 0 || inode_table >= self.blocks_count {
+            return None;
+        }
+
+        let mut di = [0u8; GOOD_OLD_INODE_SIZE as usize];
+        read_disk(
+            self.dev,
+            self.block_offset(inode_table) + index as u64 * self.inode_size,
+            &mut di,
+        );
+        let mode = get16(&di, 0);
+        let mut size = get32(&di, 4) as u64;
+        if mode & S_IFMT == S_IFREG {
+            // the high half of the size of a large file.
+            size |= (get32(&di, 108) as u64) << 32;
+        }
+        let mut block = [0u32; N_BLOCKS];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = get32(&di, 40 + i * 4);
+        }
+        Some(Dinode {
+            mode,
+            size,
+            links_count: get16(&di, 26),
+            blocks: get32(&di, 28),
+            block,
+        })
+    }
+
+    // entry i of indirect block ind; 0 for a hole.
+    fn indirect(&self, ind: u32, i: u64) -> Option<u32> {
+        if ind == 0 {
+            return Some(0);
+        }
+        if ind >= self.blocks_count {
+            return None;
+        }
+        let mut b = [0u8; 4];
+        read_disk(self.dev, self.block_offset(ind) + i * 4, &mut b);
+        Some(u32::from_le_bytes(b))
+    }
+
+    // the disk block holding block bn of inode di: 0 for a hole, None
+    // if the inode points off the disk.
+    fn bmap(&self, di: &Dinode, bn: u64) -> Option<u32> {
+        let per = self.block_size / 4; // block numbers per indirect block
+        let mut bn = bn;
+        let block = if bn < NDIR_BLOCKS as u64 {
+            di.block[bn as usize]
+        } else {
+            bn -= NDIR_BLOCKS as u64;
+            if bn < per {
+                self.indirect(di.block[IND_BLOCK], bn)?
+            } else {
+                bn -= per;
+                if bn < per * per {
+                    let ind = self.indirect(di.block[DIND_BLOCK], bn / per)?;
+                    self.indirect(ind, bn % per)?
+                } else {
+                    bn -= per * per;
+                    if bn >= per * per * per {
+                        return None;
+                    }
+                    let dind = self.indirect(di.block[TIND_BLOCK], bn / (per * per))?;
+                    let ind = self.indirect(dind, bn / per % per)?;
+                    self.indirect(ind, bn % per)?
+                }
+            }
+        };
+        if block >= self.blocks_count {
+            return None;
+        }
+        Some(block)
+    }
+
+    // copy n bytes at offset off of inode di to dst, stopping early at
+    // the end of the file. returns the number of bytes copied, or -1.
+    fn readi(&self, di: &Dinode, user_dst: bool, dst: usize, off: u64, n: usize) -> isize {
+        if off >= di.size {
+            return 0;
+        }
+        let n = (n as u64).min(di.size - off) as usize;
+
+        if di.fast_symlink() {
+            let mut target = [0u8; N_BLOCKS * 4];
+            for (i, b) in di.block.iter().enumerate() {
+                target[i * 4..i * 4 + 4].copy_from_slice(&b.to_le_bytes());
+            }
+            let off = (off as usize).min(target.len());
+            let n = n.min(target.len() - off);
+            if !either_copyout(user_dst, dst, &target[off..off + n]) {
+                return -1;
+            }
+            return n as isize;
+        }
+
+        let bs = self.block_size;
+        let mut done = 0;
+        while done < n {
+            let pos = off + done as u64;
+            let m = (n - done).min((bs - pos % bs) as usize);
+            let ok = match self.bmap(di, pos / bs) {
+                None => false,
+                // a hole reads as zeroes.
+                Some(0) => (0..m).step_by(ZEROES.len()).all(|i| {
+                    let k = (m - i).min(ZEROES.len());
+                    either_copyout(user_dst, dst + done + i, &ZEROES[..k])
+                }),
+                Some(block) => copy_disk(
+                    self.dev,
+                    self.block_offset(block) + pos % bs,
+                    user_dst,
+                    dst + done,
+                    m,
+                    false,
+                ),
+            };
+            if !ok {
+                return -1;
+            }
+            done += m;
+        }
+        done as isize
+    }
+
+    // call f on each entry of directory di with its inode number and
+    // name, until it returns Some. entries form a linked list in each
+    // block, each one's rec_len leading to the next.
+    fn scan<T>(&self, di: &Dinode, mut f: impl FnMut(u32, &[u8]) -> Option<T>) -> Option<T> {
+        let bs = self.block_size;
+        let mut buf = vec![0u8; bs as usize];
+        for bn in 0..di.size.div_ceil(bs) {
+            let block = match self.bmap(di, bn)? {
+                0 => continue,
+                block => block,
+            };
+            read_disk(self.dev, self.block_offset(block), &mut buf);
+            let mut off = 0;
+            while off + 8 <= buf.len() {
+                let inum = get32(&buf, off);
+                let rec_len = get16(&buf, off + 4) as usize;
+                let name_len = buf[off + 6] as usize;
+                if rec_len < 8 || off + rec_len > buf.len() || 8 + name_len > rec_len {
+                    break; // damaged; skip the rest of the block
+                }
+                if inum != 0 {
+                    let found = f(inum, &buf[off + 8..off + 8 + name_len]);
+                    if found.is_some() {
+                        return found;
+                    }
+                }
+                off += rec_len;
+            }
+        }
+        None
+    }
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        MOUNTED.lock()[self.dev] = false;
+    }
+}
+
+static ZEROES: [u8; 1024] = [0; 1024];
+
+struct Ext2Fs {
+    vol: Arc<Volume>,
+}
+
+impl SuperOps for Ext2Fs {
+    fn root(&self) -> InodeRef {
+        Arc::new(Ext2Inode {
+            vol: self.vol.clone(),
+            inum: ROOT_INO,
+        })
+    }
+}
+
+struct Ext2Inode {
+    vol: Arc<Volume>,
+    inum: u32,
+}
+
+impl Ext2Inode {
+    fn dinode(&self) -> Option<Dinode> {
+        self.vol.read_inode(self.inum)
+    }
+
+    fn dir(&self) -> Option<Dinode> {
+        self.dinode().filter(|di| di.typ() == S_IFDIR)
+    }
+}
+
+impl FileOps for Ext2Inode {
+    fn read_at(&self, user_dst: bool, dst: usize, off: u64, n: usize) -> isize {
+        match self.dinode() {
+            Some(di) if di.typ() != S_IFDIR => self.vol.readi(&di, user_dst, dst, off, n),
+            _ => -1,
+        }
+    }
+
+    fn write_at(&self, _user_src: bool, _src: usize, _off: u64, _n: usize) -> isize {
+        -1
+    }
+
+    fn readdir(&self, index: usize) -> Option<DirEntry> {
+        let di = self.dir()?;
+        let mut i = 0;
+        self.vol.scan(&di, |inum, name| {
+            if i < index {
+                i += 1;
+                return None;
+            }
+            Some(DirEntry {
+                ino: inum as u64,
+                name: name.to_vec(),
+            })
+        })
+    }
+}
+
+impl InodeOps for Ext2Inode {
+    fn id(&self) -> (usize, u64) {
+        (self.vol.fsid, self.inum as u64)
+    }
+
+    // symlinks aren't followed, and read as their target. devices and
+    // the like read as empty files.
+    fn stat(&self) -> Stat {
+        let (typ, nlink, size) = match self.dinode() {
+            Some(di) if di.typ() == S_IFDIR => (T_DIR, di.links_count, di.size),
+            Some(di) if di.typ() == S_IFREG || di.typ() == S_IFLNK => {
+                (T_FILE, di.links_count, di.size)
+            }
+            Some(di) => (T_FILE, di.links_count, 0),
+            None => (T_FILE, 0, 0),
+        };
+        Stat {
+            dev: self.vol.dev as i32,
+            ino: self.inum,
+            typ,
+            nlink: nlink as i16,
+            size,
+        }
+    }
+
+    fn lookup(&self, name: &[u8]) -> Option<InodeRef> {
+        let di = self.dir()?;
+        let inum = self
+            .vol
+            .scan(&di, |inum, entry| (entry == name).then_some(inum))?;
+        Some(Arc::new(Ext2Inode {
+            vol: self.vol.clone(),
+            inum,
+        }))
+    }
+
+    fn create(
+        &self,
+        _name: &[u8],
+        _typ: i16,
+        _major: i16,
+        _minor: i16,
+    ) -> Result<InodeRef, &'static str> {
+        Err("read-only file system")
+    }
+
+    fn truncate(&self) -> Result<(), &'static str> {
+        Err("read-only file system")
+    }
+}
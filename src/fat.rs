@@ -0,0 +1,981 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::bio::{copy_disk, read_disk, write_disk};
+use crate::fs::layout::{T_DIR, T_FILE};
+use crate::params::BSIZE;
+use crate::sleep_lock::SleepLock;
+use crate::spin_lock::SpinMutex;
+use crate::stat::Stat;
+use crate::vfs::{alloc_fsid, DirEntry, FileOps, FsType, InodeOps, InodeRef, SuperOps};
+use crate::virtio::virtio_blk::{virtio_disk_exists, NDISK};
+
+// FAT32, for images built on the host with mkfs.vfat and mcopy.
+//
+// FAT has no inodes: a file is its short directory entry, and its ino
+// is that entry's position on the disk, counted in entries. its size
+// and first cluster are read from the entry every time they are needed,
+// so all the InodeRefs of one file agree. the root directory has no
+// entry and is ino 1.
+//
+// there is no log. the FAT is written before the directory entries
+// that point into it, so a crash can leak clusters but not share them.
+// one sleep lock per file system serializes everything.
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+const DIRENT_SIZE: u64 = 32;
+const FREE_ENTRY: u8 = 0xe5; // first name byte of a deleted entry
+const LAST_LONG_ENTRY: u8 = 0x40; // ord of the last long name entry
+const LONG_NAME_CHARS: usize = 13; // UCS-2 characters per long entry
+const NAME_MAX: usize = 255; // UCS-2 characters in a long name
+
+// lower case flags in the NT reserved byte of a short entry.
+const LCASE_BASE: u8 = 0x08;
+const LCASE_EXT: u8 = 0x10;
+
+const FAT_MASK: u32 = 0x0fff_ffff; // the top 4 bits are reserved
+const FAT_BAD: u32 = 0x0fff_fff7;
+const FAT_EOC: u32 = 0x0fff_ffff; // end of chain, as we write it
+
+// there is no clock, so every date is 1980-01-01.
+const FAT_DATE: u16 = (1 << 5) | 1;
+
+const ROOT_INO: u64 = 1;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUC_SIG: u32 = 0x6141_7272;
+const FREE_UNKNOWN: u32 = 0xffff_ffff;
+
+pub struct FatType;
+
+pub static FAT32: FatType = FatType;
+
+// the disks that have a FAT mounted from them.
+static MOUNTED: SpinMutex<[bool; NDISK]> = SpinMutex::new([false; NDISK]);
+
+impl FsType for FatType {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn mount(&self, dev: usize) -> Result<Arc<dyn SuperOps>, &'static str> {
+        if !virtio_disk_exists(dev) {
+            return Err("no such disk");
+        }
+        if core::mem::replace(&mut MOUNTED.lock()[dev], true) {
+            return Err("disk is already mounted");
+        }
+        match Volume::read(dev) {
+            Ok(vol) => Ok(Arc::new(FatFs { vol: Arc::new(vol) })),
+            Err(e) => {
+                MOUNTED.lock()[dev] = false;
+                Err(e)
+            }
+        }
+    }
+}
+
+// byte order helpers; FAT is little-endian throughout.
+fn get16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn get32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+fn put16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+// a 32-byte directory entry.
+type Dirent = [u8; DIRENT_SIZE as usize];
+
+fn attr(de: &Dirent) -> u8 {
+    de[11]
+}
+
+fn is_dir_entry(de: &Dirent) -> bool {
+    attr(de) & ATTR_DIRECTORY != 0
+}
+
+fn first_cluster(de: &Dirent) -> u32 {
+    ((get16(de, 20) as u32) << 16 | get16(de, 26) as u32) & FAT_MASK
+}
+
+fn set_first_cluster(de: &mut Dirent, cluster: u32) {
+    put16(de, 20, (cluster >> 16) as u16);
+    put16(de, 26, cluster as u16);
+}
+
+fn file_size(de: &Dirent) -> u32 {
+    get32(de, 28)
+}
+
+// the checksum of a short name, kept in each of its long entries.
+fn checksum(short: &[u8]) -> u8 {
+    short[..11]
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+// "FOO     TXT" becomes "FOO.TXT", lower cased as the NT byte says.
+fn short_name(de: &Dirent) -> Vec<u8> {
+    let lower = |c: u8, flag: u8| {
+        if de[12] & flag != 0 {
+            c.to_ascii_lowercase()
+        } else {
+            c
+        }
+    };
+    let mut name: Vec<u8> = de[..8]
+        .iter()
+        .take_while(|&&c| c != b' ')
+        .map(|&c| lower(c, LCASE_BASE))
+        .collect();
+    // 0x05 stands for a first byte of 0xe5.
+    if name.first() == Some(&0x05) {
+        name[0] = FREE_ENTRY;
+    }
+    let ext: Vec<u8> = de[8..11]
+        .iter()
+        .take_while(|&&c| c != b' ')
+        .map(|&c| lower(c, LCASE_EXT))
+        .collect();
+    if !ext.is_empty() {
+        name.push(b'.');
+        name.extend_from_slice(&ext);
+    }
+    name
+}
+
+// the UCS-2 characters of one long entry.
+fn long_chars(de: &Dirent) -> [u16; LONG_NAME_CHARS] {
+    let mut chars = [0u16; LONG_NAME_CHARS];
+    let offsets = (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2));
+    for (c, off) in chars.iter_mut().zip(offsets) {
+        *c = get16(de, off);
+    }
+    chars
+}
+
+fn set_long_chars(de: &mut Dirent, chars: &[u16; LONG_NAME_CHARS]) {
+    let offsets = (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2));
+    for (&c, off) in chars.iter().zip(offsets) {
+        put16(de, off, c);
+    }
+}
+
+// names are UTF-8 outside and UCS-2 on the disk.
+fn ucs2_to_utf8(chars: &[u16]) -> Vec<u8> {
+    let mut name = Vec::new();
+    for c in char::decode_utf16(chars.iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        let mut buf = [0u8; 4];
+        name.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+    name
+}
+
+fn utf8_to_ucs2(name: &[u8]) -> Option<Vec<u16>> {
+    let name = core::str::from_utf8(name).ok()?;
+    let mut chars = Vec::new();
+    for c in name.chars() {
+        chars.push(u16::try_from(c as u32).ok()?);
+    }
+    Some(chars)
+}
+
+// can name be a long name?
+fn valid_long_name(name: &[u8]) -> bool {
+    !name.is_empty()
+        && !name.ends_with(b".")
+        && !name.ends_with(b" ")
+        && name
+            .iter()
+            .all(|&c| c >= 0x20 && !b"\"*/:<>?\\|".contains(&c))
+}
+
+// characters allowed in a short name, besides upper case letters,
+// digits and anything past 0x7f.
+fn short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || c > 0x7f || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+// the 11-byte short entry name for name, if name already is a valid
+// upper case 8.3 name.
+fn exact_short_name(name: &[u8]) -> Option<[u8; 11]> {
+    let (base, ext) = match name.iter().position(|&c| c == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &[][..]),
+    };
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base.iter().chain(ext).all(|&c| c < 0x80 && short_char(c))
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base);
+    short[8..8 + ext.len()].copy_from_slice(ext);
+    Some(short)
+}
+
+// the short name that goes with long name name: its base and extension
+// upper cased and cut to 8.3, with "~n" in the base.
+fn numbered_short_name(name: &[u8], n: u32) -> [u8; 11] {
+    let squash = |part: &[u8]| -> Vec<u8> {
+        part.iter()
+            .filter(|&&c| c != b' ' && c != b'.')
+            .map(|&c| {
+                let c = c.to_ascii_uppercase();
+                if c < 0x80 && short_char(c) {
+                    c
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+    let name = &name[name.iter().position(|&c| c != b'.').unwrap_or(0)..];
+    let (base, ext) = match name.iter().rposition(|&c| c == b'.') {
+        Some(dot) => (squash(&name[..dot]), squash(&name[dot + 1..])),
+        None => (squash(name), Vec::new()),
+    };
+
+    let mut tail = [0u8; 8];
+    let mut len = 0;
+    let mut n = n;
+    while n > 0 {
+        tail[7 - len] = b'0' + (n % 10) as u8;
+        n /= 10;
+        len += 1;
+    }
+    tail[7 - len] = b'~';
+    let tail = &tail[7 - len..];
+
+    let mut short = [b' '; 11];
+    let keep = base.len().min(8 - tail.len());
+    short[..keep].copy_from_slice(&base[..keep]);
+    short[keep..keep + tail.len()].copy_from_slice(tail);
+    let elen = ext.len().min(3);
+    short[8..8 + elen].copy_from_slice(&ext[..elen]);
+    short
+}
+
+// a mounted FAT32 volume.
+struct Volume {
+    dev: usize,
+    fsid: usize,
+    cluster_size: u64, // bytes
+    fat_start: u64,    // disk offset of the first FAT
+    fat_size: u64,     // bytes per FAT
+    nfats: u64,
+    data_start: u64, // disk offset of cluster 2
+    nclusters: u32,  // data clusters are 2..nclusters+2
+    root_cluster: u32,
+    fsinfo: Option<u64>, // disk offset of the FSInfo sector
+    state: SleepLock<FatState>,
+}
+
+// what the lock protects besides the disk.
+struct FatState {
+    free_count: u32, // FREE_UNKNOWN if not known
+    next_free: u32,  // where to start looking for a free cluster
+    dirty: bool,     // FSInfo needs writing
+}
+
+impl Volume {
+    // read and check the boot sector of disk dev.
+    fn read(dev: usize) -> Result<Volume, &'static str> {
+        let mut bs = [0u8; 512];
+        read_disk(dev, 0, &mut bs);
+        if bs[510] != 0x55 || bs[511] != 0xaa {
+            return Err("fat32: no boot sector signature");
+        }
+        let bytes_per_sector = get16(&bs, 11) as u64;
+        let sectors_per_cluster = bs[13] as u64;
+        let reserved = get16(&bs, 14) as u64;
+        let nfats = bs[16] as u64;
+        let root_entries = get16(&bs, 17);
+        let fat_size16 = get16(&bs, 22);
+        let total = match get16(&bs, 19) {
+            0 => get32(&bs, 32) as u64,
+            n => n as u64,
+        };
+        let fat_size = get32(&bs, 36) as u64;
+        let root_cluster = get32(&bs, 44);
+        let fsinfo = get16(&bs, 48) as u64;
+
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || nfats == 0
+        {
+            return Err("fat32: bad BPB");
+        }
+        if root_entries != 0 || fat_size16 != 0 || fat_size == 0 {
+            return Err("fat32: not a FAT32 volume");
+        }
+        let data_sectors = total
+            .checked_sub(reserved + nfats * fat_size)
+            .ok_or("fat32: bad BPB")?;
+        // the FAT may be longer than the data needs, never shorter.
+        let nclusters = (data_sectors / sectors_per_cluster)
+            .min(fat_size * bytes_per_sector / 4 - 2)
+            .min((FAT_BAD - 2) as u64) as u32;
+        if root_cluster < 2 || root_cluster >= nclusters + 2 {
+            return Err("fat32: bad root cluster");
+        }
+
+        // the FSInfo sector only holds hints, so a bad one is ignored.
+        let mut state = FatState {
+            free_count: FREE_UNKNOWN,
+            next_free: 2,
+            dirty: false,
+        };
+        let mut fsinfo_off = None;
+        if fsinfo != 0 && fsinfo != 0xffff && fsinfo < reserved {
+            let off = fsinfo * bytes_per_sector;
+            let mut fi = [0u8; 512];
+            read_disk(dev, off, &mut fi);
+            if get32(&fi, 0) == FSINFO_LEAD_SIG && get32(&fi, 484) == FSINFO_STRUC_SIG {
+                let free = get32(&fi, 488);
+                if free <= nclusters {
+                    state.free_count = free;
+                }
+                let next = get32(&fi, 492);
+                if next >= 2 && next < nclusters + 2 {
+                    state.next_free = next;
+                }
+                fsinfo_off = Some(off);
+            }
+        }
+
+        Ok(Volume {
+            dev,
+            fsid: alloc_fsid(),
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_start: reserved * bytes_per_sector,
+            fat_size: fat_size * bytes_per_sector,
+            nfats,
+            data_start: (reserved + nfats * fat_size) * bytes_per_sector,
+            nclusters,
+            root_cluster,
+            fsinfo: fsinfo_off,
+            state: SleepLock::new(state),
+        })
+    }
+
+    fn read_bytes(&self, off: u64, dst: &mut [u8]) {
+        read_disk(self.dev, off, dst);
+    }
+
+    fn write_bytes(&self, off: u64, src: &[u8]) {
+        write_disk(self.dev, off, src);
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.cluster_size
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.nclusters + 2
+    }
+
+    fn fat_get(&self, cluster: u32) -> u32 {
+        let mut b = [0u8; 4];
+        self.read_bytes(self.fat_start + cluster as u64 * 4, &mut b);
+        u32::from_le_bytes(b) & FAT_MASK
+    }
+
+    // set the FAT entry for cluster in every copy of the FAT.
+    fn fat_set(&self, cluster: u32, value: u32) {
+        for i in 0..self.nfats {
+            let off = self.fat_start + i * self.fat_size + cluster as u64 * 4;
+            let mut b = [0u8; 4];
+            self.read_bytes(off, &mut b);
+            let old = u32::from_le_bytes(b);
+            let new = (old & !FAT_MASK) | (value & FAT_MASK);
+            self.write_bytes(off, &new.to_le_bytes());
+        }
+    }
+
+    // the clusters of the chain starting at first. stops at anything
+    // that isn't a data cluster, and after nclusters steps, so a
+    // damaged FAT can't loop forever.
+    fn chain(&self, first: u32) -> Vec<u32> {
+        let mut clusters = Vec::new();
+        let mut c = first;
+        while self.valid_cluster(c) && clusters.len() < self.nclusters as usize {
+            clusters.push(c);
+            c = self.fat_get(c);
+        }
+        clusters
+    }
+
+    // take a free cluster and end a chain with it. it is not linked
+    // to anything yet.
+    fn alloc_cluster(&self, state: &mut FatState) -> Option<u32> {
+        if state.free_count == 0 {
+            return None;
+        }
+        for i in 0..self.nclusters {
+            let c = 2 + (state.next_free - 2 + i) % self.nclusters;
+            if self.fat_get(c) == 0 {
+                self.fat_set(c, FAT_EOC);
+                if state.free_count != FREE_UNKNOWN {
+                    state.free_count -= 1;
+                }
+                state.next_free = c + 1;
+                if state.next_free >= self.nclusters + 2 {
+                    state.next_free = 2;
+                }
+                state.dirty = true;
+                return Some(c);
+            }
+        }
+        state.free_count = 0;
+        None
+    }
+
+    // a cluster of zeroes, for a directory.
+    fn zero_cluster(&self, cluster: u32) {
+        let zeroes = [0u8; BSIZE];
+        let off = self.cluster_offset(cluster);
+        let mut done = 0;
+        while done < self.cluster_size {
+            let n = (self.cluster_size - done).min(BSIZE as u64);
+            self.write_bytes(off + done, &zeroes[..n as usize]);
+            done += n;
+        }
+    }
+
+    fn free_chain(&self, state: &mut FatState, first: u32) {
+        for c in self.chain(first) {
+            self.fat_set(c, 0);
+            if state.free_count != FREE_UNKNOWN {
+                state.free_count += 1;
+            }
+        }
+        state.dirty = true;
+    }
+
+    // the disk offsets of every entry slot of the directory starting
+    // at cluster dir, in order.
+    fn dir_slots(&self, dir: u32) -> Vec<u64> {
+        let per_cluster = self.cluster_size / DIRENT_SIZE;
+        self.chain(dir)
+            .into_iter()
+            .flat_map(|c| {
+                let base = self.cluster_offset(c);
+                (0..per_cluster).map(move |i| base + i * DIRENT_SIZE)
+            })
+            .collect()
+    }
+
+    fn read_entry(&self, off: u64) -> Dirent {
+        let mut de = [0u8; DIRENT_SIZE as usize];
+        self.read_bytes(off, &mut de);
+        de
+    }
+
+    // call f on each name in the directory starting at cluster dir
+    // until it returns Some. f gets the name, its short entry and that
+    // entry's disk offset.
+    fn scan<T>(&self, dir: u32, mut f: impl FnMut(&Name) -> Option<T>) -> Option<T> {
+        let slots = self.dir_slots(dir);
+        let mut long: Vec<u16> = Vec::new();
+        let mut expect = 0u8; // ord of the next long entry, 0 if none
+        let mut sum = 0u8;
+        for &off in slots.iter() {
+            let de = self.read_entry(off);
+            if de[0] == 0 {
+                break; // end of directory
+            }
+            if de[0] == FREE_ENTRY {
+                long.clear();
+                expect = 0;
+                continue;
+            }
+            if attr(&de) & 0x3f == ATTR_LONG_NAME {
+                let ord = de[0] & !LAST_LONG_ENTRY;
+                if de[0] & LAST_LONG_ENTRY != 0 && ord >= 1 && ord as usize <= 20 {
+                    long = alloc::vec![0xffff; ord as usize * LONG_NAME_CHARS];
+                    sum = de[13];
+                } else if ord == 0 || ord != expect || de[13] != sum {
+                    // a broken sequence; the short name will do.
+                    long.clear();
+                    expect = 0;
+                    continue;
+                }
+                let at = (ord as usize - 1) * LONG_NAME_CHARS;
+                long[at..at + LONG_NAME_CHARS].copy_from_slice(&long_chars(&de));
+                expect = ord - 1;
+                continue;
+            }
+            let has_long = expect == 0 && !long.is_empty() && checksum(&de) == sum;
+            if attr(&de) & ATTR_VOLUME_ID == 0 {
+                let name = if has_long {
+                    let len = long.iter().position(|&c| c == 0 || c == 0xffff);
+                    ucs2_to_utf8(&long[..len.unwrap_or(long.len())])
+                } else {
+                    short_name(&de)
+                };
+                let found = f(&Name {
+                    name,
+                    entry: de,
+                    off,
+                });
+                if found.is_some() {
+                    return found;
+                }
+            }
+            long.clear();
+            expect = 0;
+        }
+        None
+    }
+
+    fn ino_of(off: u64) -> u64 {
+        off / DIRENT_SIZE
+    }
+
+    // the cluster a directory starts at, from its "..", which says 0
+    // for the root.
+    fn dir_cluster(&self, cluster: u32) -> u32 {
+        if cluster == 0 {
+            self.root_cluster
+        } else {
+            cluster
+        }
+    }
+
+    // the ino of the parent of the directory starting at cluster dir.
+    // FAT only records the parent's cluster, so find the entry for it
+    // in the grandparent.
+    fn parent(&self, dir: u32) -> u64 {
+        let dotdot =
+            |dir: u32| self.scan(dir, |n| (n.name == b"..").then(|| first_cluster(&n.entry)));
+        let parent = match dotdot(dir) {
+            Some(c) => self.dir_cluster(c),
+            None => return ROOT_INO,
+        };
+        if parent == self.root_cluster {
+            return ROOT_INO;
+        }
+        let grandparent = match dotdot(parent) {
+            Some(c) => self.dir_cluster(c),
+            None => return ROOT_INO,
+        };
+        self.scan(grandparent, |n| {
+            (is_dir_entry(&n.entry)
+                && first_cluster(&n.entry) == parent
+                && n.name != b"."
+                && n.name != b"..")
+                .then(|| Volume::ino_of(n.off))
+        })
+        .unwrap_or(ROOT_INO)
+    }
+
+    // write the free cluster hints back to the FSInfo sector.
+    fn write_fsinfo(&self, state: &mut FatState) {
+        if let Some(off) = self.fsinfo {
+            if state.dirty {
+                let mut b = [0u8; 8];
+                put32(&mut b, 0, state.free_count);
+                put32(&mut b, 4, state.next_free);
+                self.write_bytes(off + 488, &b);
+            }
+        }
+        state.dirty = false;
+    }
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        MOUNTED.lock()[self.dev] = false;
+    }
+}
+
+// one name in a directory, as scan() finds it.
+struct Name {
+    name: Vec<u8>,
+    entry: Dirent, // the short entry
+    off: u64,      // disk offset of the short entry
+}
+
+fn same_name(a: &[u8], b: &[u8]) -> bool {
+    // FAT names are case-insensitive, at least for ASCII.
+    a.eq_ignore_ascii_case(b)
+}
+
+struct FatFs {
+    vol: Arc<Volume>,
+}
+
+impl SuperOps for FatFs {
+    fn root(&self) -> InodeRef {
+        Arc::new(FatInode {
+            vol: self.vol.clone(),
+            ino: ROOT_INO,
+        })
+    }
+
+    fn sync(&self) {
+        let mut state = self.vol.state.lock();
+        self.vol.write_fsinfo(&mut state);
+    }
+}
+
+struct FatInode {
+    vol: Arc<Volume>,
+    ino: u64, // ROOT_INO, or the disk offset of the short entry / 32
+}
+
+impl FatInode {
+    fn inode(&self, ino: u64) -> InodeRef {
+        Arc::new(FatInode {
+            vol: self.vol.clone(),
+            ino,
+        })
+    }
+
+    // the short entry and its disk offset; None for the root.
+    fn entry(&self) -> Option<(u64, Dirent)> {
+        if self.ino == ROOT_INO {
+            return None;
+        }
+        let off = self.ino * DIRENT_SIZE;
+        Some((off, self.vol.read_entry(off)))
+    }
+
+    // the first cluster of a directory.
+    fn dir(&self) -> Option<u32> {
+        match self.entry() {
+            None => Some(self.vol.root_cluster),
+            Some((_, de)) if is_dir_entry(&de) => Some(self.vol.dir_cluster(first_cluster(&de))),
+            Some(_) => None,
+        }
+    }
+
+    // the entry of a regular file.
+    fn file(&self) -> Option<(u64, Dirent)> {
+        self.entry().filter(|(_, de)| !is_dir_entry(de))
+    }
+}
+
+impl FileOps for FatInode {
+    fn read_at(&self, user_dst: bool, dst: usize, off: u64, n: usize) -> isize {
+        let _state = self.vol.state.lock();
+        let (_, de) = match self.file() {
+            Some(file) => file,
+            None => return -1,
+        };
+        let size = file_size(&de) as u64;
+        if off >= size {
+            return 0;
+        }
+        let n = (n as u64).min(size - off) as usize;
+
+        let cs = self.vol.cluster_size;
+        let chain = self.vol.chain(first_cluster(&de));
+        let mut done = 0;
+        while done < n {
+            let pos = off + done as u64;
+            let cluster = match chain.get((pos / cs) as usize) {
+                Some(&c) => c,
+                None => break, // chain shorter than the file
+            };
+            let m = (n - done).min((cs - pos % cs) as usize);
+            let from = self.vol.cluster_offset(cluster) + pos % cs;
+            if !copy_disk(self.vol.dev, from, user_dst, dst + done, m, false) {
+                return -1;
+            }
+            done += m;
+        }
+        done as isize
+    }
+
+    fn write_at(&self, user_src: bool, src: usize, off: u64, n: usize) -> isize {
+        let mut state = self.vol.state.lock();
+        let (eoff, mut de) = match self.file() {
+            Some(file) => file,
+            None => return -1,
+        };
+        let size = file_size(&de) as u64;
+        let end = match off.checked_add(n as u64) {
+            Some(end) if off <= size && end <= u32::MAX as u64 => end,
+            _ => return -1,
+        };
+        if n == 0 {
+            return 0;
+        }
+
+        // grow the chain to cover the write, as far as the disk allows.
+        let cs = self.vol.cluster_size;
+        let mut chain = self.vol.chain(first_cluster(&de));
+        while (chain.len() as u64) < end.div_ceil(cs) {
+            let c = match self.vol.alloc_cluster(&mut state) {
+                Some(c) => c,
+                None => break,
+            };
+            match chain.last() {
+                Some(&last) => self.vol.fat_set(last, c),
+                None => set_first_cluster(&mut de, c),
+            }
+            chain.push(c);
+        }
+
+        let room = (chain.len() as u64 * cs).saturating_sub(off);
+        let n = (n as u64).min(room) as usize;
+        let mut done = 0;
+        while done < n {
+            let pos = off + done as u64;
+            let cluster = chain[(pos / cs) as usize];
+            let m = (n - done).min((cs - pos % cs) as usize);
+            let to = self.vol.cluster_offset(cluster) + pos % cs;
+            if !copy_disk(self.vol.dev, to, user_src, src + done, m, true) {
+                break;
+            }
+            done += m;
+        }
+
+        let new_size = size.max(off + done as u64) as u32;
+        put32(&mut de, 28, new_size);
+        put16(&mut de, 24, FAT_DATE); // write date
+        de[11] |= ATTR_ARCHIVE;
+        self.vol.write_bytes(eoff, &de);
+
+        if done == 0 {
+            -1
+        } else {
+            done as isize
+        }
+    }
+
+    fn readdir(&self, index: usize) -> Option<DirEntry> {
+        let _state = self.vol.state.lock();
+        let dir = self.dir()?;
+        // the root has no "." and "..", so make them up.
+        let index = if self.ino == ROOT_INO {
+            if let Some(name) = [&b"."[..], b".."].get(index) {
+                return Some(DirEntry {
+                    ino: ROOT_INO,
+                    name: name.to_vec(),
+                });
+            }
+            index - 2
+        } else {
+            index
+        };
+        let mut i = 0;
+        self.vol.scan(dir, |n| {
+            if i < index {
+                i += 1;
+                return None;
+            }
+            let ino = match &n.name[..] {
+                b"." => self.ino,
+                b".." => self.vol.parent(dir),
+                _ => Volume::ino_of(n.off),
+            };
+            Some(DirEntry {
+                ino,
+                name: n.name.clone(),
+            })
+        })
+    }
+}
+
+impl InodeOps for FatInode {
+    fn id(&self) -> (usize, u64) {
+        (self.vol.fsid, self.ino)
+    }
+
+    fn stat(&self) -> Stat {
+        let _state = self.vol.state.lock();
+        let (typ, size) = match self.entry() {
+            None => (T_DIR, 0),
+            Some((_, de)) if is_dir_entry(&de) => (T_DIR, 0),
+            Some((_, de)) => (T_FILE, file_size(&de) as u64),
+        };
+        Stat {
+            dev: self.vol.dev as i32,
+            ino: self.ino as u32,
+            typ,
+            nlink: 1,
+            size,
+        }
+    }
+
+    fn lookup(&self, name: &[u8]) -> Option<InodeRef> {
+        let _state = self.vol.state.lock();
+        let dir = self.dir()?;
+        let ino = if name == b".." {
+            self.vol.parent(dir)
+        } else {
+            self.vol.scan(dir, |n| {
+                same_name(&n.name, name).then(|| Volume::ino_of(n.off))
+            })?
+        };
+        Some(self.inode(ino))
+    }
+
+    fn create(
+        &self,
+        name: &[u8],
+        typ: i16,
+        _major: i16,
+        _minor: i16,
+    ) -> Result<InodeRef, &'static str> {
+        if typ != T_FILE && typ != T_DIR {
+            return Err("fat32 only has files and directories");
+        }
+        let mut state = self.vol.state.lock();
+        let vol = &self.vol;
+        let dir = self.dir().ok_or("not a directory")?;
+
+        if !valid_long_name(name) {
+            return Err("invalid name");
+        }
+        let long = utf8_to_ucs2(name).ok_or("invalid name")?;
+        if long.len() > NAME_MAX {
+            return Err("name too long");
+        }
+        // a name that is already upper case 8.3 needs no long entries.
+        let exact = exact_short_name(name);
+        let taken = |n: &Name| {
+            same_name(&n.name, name) || exact.is_some_and(|short| n.entry[..11] == short)
+        };
+        if vol.scan(dir, |n| taken(n).then_some(())).is_some() {
+            return Err("file exists");
+        }
+        let (short, nlong) = match exact {
+            Some(short) => (short, 0),
+            None => {
+                let short = (1..1_000_000)
+                    .map(|i| numbered_short_name(name, i))
+                    .find(|short| {
+                        vol.scan(dir, |n| (n.entry[..11] == short[..]).then_some(()))
+                            .is_none()
+                    })
+                    .ok_or("no free short name")?;
+                (short, long.len().div_ceil(LONG_NAME_CHARS))
+            }
+        };
+
+        // find nlong + 1 free slots in a row, growing the directory if
+        // there are none.
+        let mut slots = vol.dir_slots(dir);
+        let mut run = 0;
+        let mut start = None;
+        for (i, &off) in slots.iter().enumerate() {
+            let first = vol.read_entry(off)[0];
+            run = if first == 0 || first == FREE_ENTRY {
+                run + 1
+            } else {
+                0
+            };
+            if run == nlong + 1 {
+                start = Some(i + 1 - run);
+                break;
+            }
+        }
+        let start = match start {
+            Some(start) => start,
+            None => {
+                let start = slots.len() - run;
+                while slots.len() < start + nlong + 1 {
+                    let c = vol.alloc_cluster(&mut state).ok_or("out of disk space")?;
+                    vol.zero_cluster(c);
+                    vol.fat_set(*vol.chain(dir).last().unwrap(), c);
+                    let base = vol.cluster_offset(c);
+                    slots.extend(
+                        (0..vol.cluster_size / DIRENT_SIZE).map(|i| base + i * DIRENT_SIZE),
+                    );
+                }
+                start
+            }
+        };
+
+        let mut de: Dirent = [0; DIRENT_SIZE as usize];
+        de[..11].copy_from_slice(&short);
+        de[11] = if typ == T_DIR {
+            ATTR_DIRECTORY
+        } else {
+            ATTR_ARCHIVE
+        };
+        put16(&mut de, 16, FAT_DATE); // creation date
+        put16(&mut de, 18, FAT_DATE); // access date
+        put16(&mut de, 24, FAT_DATE); // write date
+
+        if typ == T_DIR {
+            // a directory starts with "." and "..", where ".." of a
+            // child of the root says cluster 0.
+            let c = vol.alloc_cluster(&mut state).ok_or("out of disk space")?;
+            vol.zero_cluster(c);
+            set_first_cluster(&mut de, c);
+            let mut dot = de;
+            dot[..11].copy_from_slice(b".          ");
+            vol.write_bytes(vol.cluster_offset(c), &dot);
+            let mut dotdot = de;
+            dotdot[..11].copy_from_slice(b"..         ");
+            set_first_cluster(&mut dotdot, if dir == vol.root_cluster { 0 } else { dir });
+            vol.write_bytes(vol.cluster_offset(c) + DIRENT_SIZE, &dotdot);
+        }
+
+        // long entries go in reverse order, the last part first.
+        let sum = checksum(&short);
+        for k in 0..nlong {
+            let ord = (nlong - k) as u8;
+            let mut chars = [0xffffu16; LONG_NAME_CHARS];
+            let part = &long[(ord as usize - 1) * LONG_NAME_CHARS..];
+            let len = part.len().min(LONG_NAME_CHARS);
+            chars[..len].copy_from_slice(&part[..len]);
+            if len < LONG_NAME_CHARS {
+                chars[len] = 0;
+            }
+            let mut le: Dirent = [0; DIRENT_SIZE as usize];
+            le[0] = if k == 0 { ord | LAST_LONG_ENTRY } else { ord };
+            le[11] = ATTR_LONG_NAME;
+            le[13] = sum;
+            set_long_chars(&mut le, &chars);
+            vol.write_bytes(slots[start + k], &le);
+        }
+        let off = slots[start + nlong];
+        vol.write_bytes(off, &de);
+        Ok(self.inode(Volume::ino_of(off)))
+    }
+
+    fn truncate(&self) -> Result<(), &'static str> {
+        let mut state = self.vol.state.lock();
+        let (off, mut de) = self.file().ok_or("is a directory")?;
+        let first = first_cluster(&de);
+        set_first_cluster(&mut de, 0);
+        put32(&mut de, 28, 0);
+        put16(&mut de, 24, FAT_DATE);
+        // drop the chain from the entry before freeing it.
+        self.vol.write_bytes(off, &de);
+        self.vol.free_chain(&mut state, first);
+        Ok(())
+    }
+}
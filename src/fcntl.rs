@@ -0,0 +1,11 @@
+// open() modes.
+pub const O_RDONLY: i32 = 0x000;
+pub const O_WRONLY: i32 = 0x001;
+pub const O_RDWR: i32 = 0x002;
+pub const O_CREATE: i32 = 0x200;
+pub const O_TRUNC: i32 = 0x400;
+
+// lseek() whence.
+pub const SEEK_SET: i32 = 0; // from the start of the file
+pub const SEEK_CUR: i32 = 1; // from the current offset
+pub const SEEK_END: i32 = 2; // from the end of the file
@@ -0,0 +1,282 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// a minimal reader for the flattened device tree qemu passes in a1.
+// see the devicetree specification, chapter 5.
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+// physical address of the device tree blob, saved by start().
+static DTB_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_dtb_addr(addr: usize) {
+    DTB_ADDR.store(addr, Ordering::Relaxed);
+}
+
+pub fn dtb_addr() -> usize {
+    DTB_ADDR.load(Ordering::Relaxed)
+}
+
+#[repr(C)]
+struct FdtHeader {
+    magic: u32,
+    totalsize: u32,
+    off_dt_struct: u32,
+    off_dt_strings: u32,
+    off_mem_rsvmap: u32,
+    version: u32,
+    last_comp_version: u32,
+    boot_cpuid_phys: u32,
+    size_dt_strings: u32,
+    size_dt_struct: u32,
+}
+
+pub struct Fdt {
+    base: usize,
+    size: usize,
+    struct_off: usize,
+    strings_off: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct FdtNode<'a> {
+    fdt: &'a Fdt,
+    offset: usize, // of the FDT_BEGIN_NODE token in the structure block
+}
+
+// two nodes are the same if they start at the same place.
+impl PartialEq for FdtNode<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+    }
+}
+
+enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(&'a str, &'a [u8]),
+    End,
+}
+
+// the device tree qemu gave us, if there is a valid one.
+pub fn fdt() -> Option<Fdt> {
+    let base = dtb_addr();
+    if base == 0 {
+        return None;
+    }
+    let header = unsafe { &*(base as *const FdtHeader) };
+    if u32::from_be(header.magic) != FDT_MAGIC {
+        return None;
+    }
+    Some(Fdt {
+        base,
+        size: u32::from_be(header.totalsize) as usize,
+        struct_off: u32::from_be(header.off_dt_struct) as usize,
+        strings_off: u32::from_be(header.off_dt_strings) as usize,
+    })
+}
+
+pub fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// read a number that is `cells` 32-bit cells long.
+pub fn read_cells(bytes: &[u8], cells: usize) -> u64 {
+    let mut v = 0;
+    for i in 0..cells {
+        v = (v << 32) | be32(&bytes[i * 4..]) as u64;
+    }
+    v
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+impl Fdt {
+    // the blob occupies [base, base + size) of physical memory.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn struct_u32(&self, off: usize) -> u32 {
+        u32::from_be(unsafe { *((self.base + self.struct_off + off) as *const u32) })
+    }
+
+    fn cstr(&self, addr: usize) -> &str {
+        let mut len = 0;
+        while unsafe { *((addr + len) as *const u8) } != 0 {
+            len += 1;
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+
+    // decode the token at off, return it with the offset of the next one.
+    fn token(&self, mut off: usize) -> (Token<'_>, usize) {
+        loop {
+            let token = self.struct_u32(off);
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = self.cstr(self.base + self.struct_off + off);
+                    return (Token::BeginNode(name), off + align4(name.len() + 1));
+                }
+                FDT_END_NODE => return (Token::EndNode, off),
+                FDT_PROP => {
+                    let len = self.struct_u32(off) as usize;
+                    let nameoff = self.struct_u32(off + 4) as usize;
+                    let name = self.cstr(self.base + self.strings_off + nameoff);
+                    let value = unsafe {
+                        core::slice::from_raw_parts(
+                            (self.base + self.struct_off + off + 8) as *const u8,
+                            len,
+                        )
+                    };
+                    return (Token::Prop(name, value), off + 8 + align4(len));
+                }
+                FDT_NOP => continue,
+                _ => return (Token::End, off),
+            }
+        }
+    }
+
+    pub fn root(&self) -> FdtNode<'_> {
+        FdtNode {
+            fdt: self,
+            offset: 0,
+        }
+    }
+
+    // the nodes whose "compatible" list contains compat, in tree order.
+    pub fn find_all_compatible<'a>(&'a self, compat: &'a str) -> impl Iterator<Item = FdtNode<'a>> {
+        let mut off = 0;
+        let mut current = 0;
+        core::iter::from_fn(move || loop {
+            let (token, next) = self.token(off);
+            let this = off;
+            off = next;
+            match token {
+                Token::BeginNode(_) => current = this,
+                Token::Prop("compatible", value) => {
+                    if value.split(|&c| c == 0).any(|s| s == compat.as_bytes()) {
+                        return Some(FdtNode {
+                            fdt: self,
+                            offset: current,
+                        });
+                    }
+                }
+                Token::End => {
+                    off = this; // stay at the end
+                    return None;
+                }
+                _ => {}
+            }
+        })
+    }
+
+    // the node at an absolute path like "/soc/serial@10000000". a
+    // component without a unit address also matches a name with one.
+    pub fn find_node(&self, path: &str) -> Option<FdtNode<'_>> {
+        let path = path.trim_matches('/');
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let mut want = components.next();
+        // depth of the node being looked at, and how many of its
+        // ancestors (the root included) are on the path.
+        let mut depth = 0;
+        let mut matched = 0;
+        let mut off = 0;
+        loop {
+            let (token, next) = self.token(off);
+            let this = off;
+            off = next;
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    let on_path = matched == depth - 1
+                        && (depth == 1
+                            || want.is_some_and(|w| {
+                                name == w || name.split_once('@').is_some_and(|(n, _)| n == w)
+                            }));
+                    if !on_path {
+                        continue;
+                    }
+                    if depth > 1 {
+                        want = components.next();
+                    }
+                    matched = depth;
+                    if want.is_none() {
+                        return Some(FdtNode {
+                            fdt: self,
+                            offset: this,
+                        });
+                    }
+                }
+                Token::EndNode => {
+                    if matched == depth {
+                        // left a node on the path without finding the rest.
+                        return None;
+                    }
+                    depth -= 1;
+                }
+                Token::Prop(_, _) => {}
+                Token::End => return None,
+            }
+        }
+    }
+
+    pub fn find_compatible<'a>(&'a self, compat: &'a str) -> Option<FdtNode<'a>> {
+        self.find_all_compatible(compat).next()
+    }
+}
+
+impl<'a> FdtNode<'a> {
+    pub fn name(&self) -> &'a str {
+        match self.fdt.token(self.offset).0 {
+            Token::BeginNode(name) => name,
+            _ => "",
+        }
+    }
+
+    // properties come before the subnodes of a node.
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        let (_, mut off) = self.fdt.token(self.offset);
+        loop {
+            let (token, next) = self.fdt.token(off);
+            match token {
+                Token::Prop(n, value) if n == name => return Some(value),
+                Token::Prop(_, _) => off = next,
+                _ => return None,
+            }
+        }
+    }
+
+    // a string property, without its terminating nul.
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        let value = self.property(name)?;
+        let value = value.split(|&c| c == 0).next()?;
+        core::str::from_utf8(value).ok()
+    }
+
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        self.property(name).filter(|v| v.len() >= 4).map(be32)
+    }
+
+    // the first (address, size) pair of the "reg" property, given the
+    // #address-cells and #size-cells of the parent.
+    pub fn reg(&self, address_cells: usize, size_cells: usize) -> Option<(u64, u64)> {
+        let reg = self.property("reg")?;
+        if reg.len() < (address_cells + size_cells) * 4 {
+            return None;
+        }
+        Some((
+            read_cells(reg, address_cells),
+            read_cells(&reg[address_cells * 4..], size_cells),
+        ))
+    }
+}
@@ -0,0 +1,288 @@
+use core::mem::size_of;
+
+use crate::fcntl::{SEEK_CUR, SEEK_END, SEEK_SET};
+use crate::fs::layout::{Dirent, DIRSIZ, T_DIR};
+use crate::params::{NDEV, NFILE};
+use crate::pipe::{pipeclose, piperead, pipewrite};
+use crate::proc::either_copyout;
+use crate::sleep_lock::SleepLock;
+use crate::spin_lock::SpinMutex;
+use crate::stat::Stat;
+use crate::vfs::InodeRef;
+
+// support functions for system calls that involve file descriptors.
+
+// map major device number to device functions.
+// addr is a user virtual address if user is true, else a kernel address.
+// both return the number of bytes moved, or -1.
+#[derive(Clone, Copy)]
+pub struct Devsw {
+    pub read: fn(minor: usize, user_dst: bool, dst: usize, n: usize) -> isize,
+    pub write: fn(minor: usize, user_src: bool, src: usize, n: usize) -> isize,
+}
+
+pub const CONSOLE: usize = 1;
+pub const VIRTIO_CONSOLE: usize = 2; // minor is the port number
+
+pub static DEVSW: SpinMutex<[Option<Devsw>; NDEV]> = SpinMutex::new([None; NDEV]);
+
+pub fn devsw(major: usize) -> Option<Devsw> {
+    DEVSW.lock().get(major).copied().flatten()
+}
+
+// what an open file refers to.
+#[derive(Clone)]
+pub enum FileKind {
+    Inode {
+        ip: InodeRef,
+        off: u64, // protected by the file's sleep lock
+    },
+    Device {
+        ip: Option<InodeRef>, // None for a device opened without an inode
+        major: usize,
+        minor: usize,
+    },
+    Pipe {
+        pi: usize, // index in the pipe table
+    },
+}
+
+#[derive(Clone)]
+struct File {
+    refcnt: usize, // reference count
+    readable: bool,
+    writable: bool,
+    kind: FileKind,
+}
+
+// the open file table. a free slot is None.
+// dropping a File may need the disk, so never do it with FTABLE held.
+static FTABLE: SpinMutex<[Option<File>; NFILE]> = SpinMutex::new([const { None }; NFILE]);
+
+// held across i/o on an inode file, so that its offset moves
+// atomically with the read or write.
+static FILE_LOCKS: [SleepLock<()>; NFILE] = [const { SleepLock::new(()) }; NFILE];
+
+// a counted reference to an open file, what xv6 calls a struct file *.
+// get one from filealloc() or filedup(), give it back with fileclose().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fp(usize);
+
+fn file(f: Fp) -> File {
+    FTABLE.lock()[f.0].clone().expect("file: not open")
+}
+
+// allocate a file structure.
+pub fn filealloc(readable: bool, writable: bool, kind: FileKind) -> Option<Fp> {
+    let mut ftable = FTABLE.lock();
+    let i = match ftable.iter().position(|f| f.is_none()) {
+        Some(i) => i,
+        None => {
+            drop(ftable);
+            // kind is dropped here, without the lock.
+            return None;
+        }
+    };
+    ftable[i] = Some(File {
+        refcnt: 1,
+        readable,
+        writable,
+        kind,
+    });
+    Some(Fp(i))
+}
+
+// increment ref count for file f.
+pub fn filedup(f: Fp) -> Fp {
+    let mut ftable = FTABLE.lock();
+    match &mut ftable[f.0] {
+        Some(file) => file.refcnt += 1,
+        None => panic!("filedup"),
+    }
+    f
+}
+
+// close file f. (decrement ref count, close when reaches 0.)
+pub fn fileclose(f: Fp) {
+    let ff = {
+        let mut ftable = FTABLE.lock();
+        let file = ftable[f.0].as_mut().expect("fileclose");
+        file.refcnt -= 1;
+        if file.refcnt > 0 {
+            return;
+        }
+        ftable[f.0].take().unwrap()
+    };
+
+    if let FileKind::Pipe { pi } = ff.kind {
+        pipeclose(pi, ff.writable);
+    }
+    // dropping ff gives back its inode.
+}
+
+// is any open file on file system fsid?
+pub fn files_on(fsid: usize) -> bool {
+    FTABLE.lock().iter().flatten().any(|f| match &f.kind {
+        FileKind::Inode { ip, .. } | FileKind::Device { ip: Some(ip), .. } => ip.id().0 == fsid,
+        _ => false,
+    })
+}
+
+// get metadata about file f.
+// addr is a user virtual address, pointing to a struct stat.
+pub fn filestat(f: Fp, addr: usize) -> i32 {
+    let st = match file(f).kind {
+        FileKind::Inode { ip, .. } | FileKind::Device { ip: Some(ip), .. } => ip.stat(),
+        FileKind::Device { ip: None, .. } | FileKind::Pipe { .. } => return -1,
+    };
+    let bytes =
+        unsafe { core::slice::from_raw_parts(&st as *const Stat as *const u8, size_of::<Stat>()) };
+    if !either_copyout(true, addr, bytes) {
+        return -1;
+    }
+    0
+}
+
+fn set_off(f: Fp, new_off: u64) {
+    if let Some(File {
+        kind: FileKind::Inode { off, .. },
+        ..
+    }) = &mut FTABLE.lock()[f.0]
+    {
+        *off = new_off;
+    }
+}
+
+// the offset of f, which the caller keeps steady by holding
+// FILE_LOCKS[f.0].
+fn get_off(f: Fp) -> u64 {
+    match file(f).kind {
+        FileKind::Inode { off, .. } => off,
+        _ => 0,
+    }
+}
+
+// the inum a Dirent shows for inode number ino. other file systems
+// number their inodes past what a u16 holds, so those are folded into
+// 1..=0xffff: 0 would read as an empty slot.
+fn dirent_inum(ino: u64) -> u16 {
+    if ino <= u16::MAX as u64 {
+        ino as u16
+    } else {
+        (ino % u16::MAX as u64) as u16 + 1
+    }
+}
+
+// directories read as a sequence of native Dirents, whatever file
+// system they are on, and off counts bytes of those. a name longer
+// than DIRSIZ doesn't fit one, and cutting it would give a name that
+// can't be opened, so the read stops there, failing if it got nothing.
+fn read_dir(ip: &InodeRef, addr: usize, off: u64, n: usize) -> isize {
+    let desz = size_of::<Dirent>();
+    let mut index = off as usize / desz;
+    let mut tot = 0;
+    while tot + desz <= n {
+        let entry = match ip.readdir(index) {
+            Some(entry) => entry,
+            None => break,
+        };
+        if entry.name.len() > DIRSIZ {
+            if tot == 0 {
+                return -1;
+            }
+            break;
+        }
+        let mut de = Dirent {
+            inum: dirent_inum(entry.ino),
+            ..Default::default()
+        };
+        de.name[..entry.name.len()].copy_from_slice(&entry.name);
+        let bytes = unsafe { core::slice::from_raw_parts(&de as *const Dirent as *const u8, desz) };
+        if !either_copyout(true, addr + tot, bytes) {
+            return -1;
+        }
+        tot += desz;
+        index += 1;
+    }
+    tot as isize
+}
+
+// read from file f.
+// addr is a user virtual address.
+pub fn fileread(f: Fp, addr: usize, n: usize) -> isize {
+    let ff = file(f);
+    if !ff.readable {
+        return -1;
+    }
+
+    match ff.kind {
+        FileKind::Device { major, minor, .. } => match devsw(major) {
+            Some(dev) => (dev.read)(minor, true, addr, n),
+            None => -1,
+        },
+        FileKind::Pipe { pi } => piperead(pi, addr, n),
+        FileKind::Inode { ip, .. } => {
+            let _lock = FILE_LOCKS[f.0].lock();
+            let off = get_off(f);
+            let r = if ip.stat().typ == T_DIR {
+                read_dir(&ip, addr, off, n)
+            } else {
+                ip.read_at(true, addr, off, n)
+            };
+            if r > 0 {
+                set_off(f, off + r as u64);
+            }
+            r
+        }
+    }
+}
+
+// write to file f.
+// addr is a user virtual address.
+pub fn filewrite(f: Fp, addr: usize, n: usize) -> isize {
+    let ff = file(f);
+    if !ff.writable {
+        return -1;
+    }
+
+    match ff.kind {
+        FileKind::Device { major, minor, .. } => match devsw(major) {
+            Some(dev) => (dev.write)(minor, true, addr, n),
+            None => -1,
+        },
+        FileKind::Pipe { pi } => pipewrite(pi, addr, n),
+        FileKind::Inode { ip, .. } => {
+            let _lock = FILE_LOCKS[f.0].lock();
+            let off = get_off(f);
+            let r = ip.write_at(true, addr, off, n);
+            if r > 0 {
+                set_off(f, off + r as u64);
+            }
+            r
+        }
+    }
+}
+
+// move the offset of f. files have no holes, so the offset has to
+// stay within the file.
+// returns the new offset, or -1.
+pub fn fileseek(f: Fp, offset: i64, whence: i32) -> i64 {
+    let ip = match file(f).kind {
+        FileKind::Inode { ip, .. } => ip,
+        _ => return -1,
+    };
+    let _lock = FILE_LOCKS[f.0].lock();
+    let size = ip.stat().size as i64;
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => get_off(f) as i64,
+        SEEK_END => size,
+        _ => return -1,
+    };
+    let new_off = match base.checked_add(offset) {
+        Some(off) if (0..=size).contains(&off) => off,
+        _ => return -1,
+    };
+    set_off(f, new_off as u64);
+    new_off
+}
@@ -0,0 +1,621 @@
+use core::mem::size_of;
+use core::ptr::{read_unaligned, write_unaligned};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::bio::{bread, brelse, Buf};
+use crate::log::{initlog, log_write};
+use crate::params::{BSIZE, NINODE};
+use crate::proc::{either_copyin, either_copyout};
+use crate::sleep_lock::{SleepLock, SleepLockGuard};
+use crate::spin_lock::SpinMutex;
+use crate::stat::Stat;
+use crate::{info, warn};
+
+pub mod layout;
+pub mod vnode;
+
+use layout::{
+    bblock, iblock, Dinode, Dirent, SuperBlock, BPB, DIRSIZ, FSMAGIC, MAXFILE, NDIRECT, NINDIRECT,
+    T_DIR,
+};
+
+// file system implementation. five layers:
+//   + blocks: allocator for raw disk blocks.
+//   + log: crash recovery for multi-step updates.
+//   + files: inode allocator, reading, writing, metadata.
+//   + directories: inode with special contents (list of other inodes!)
+//   + names: paths like /usr/rtm/xv6/fs.c for convenient naming.
+//
+// this file contains the low-level file system manipulation
+// routines. vnode.rs puts them behind the vfs, which does the
+// path names for every file system.
+
+// there should be one superblock per disk device, but we run with
+// only one device
+static SB: SpinMutex<SuperBlock> = SpinMutex::new(SuperBlock {
+    magic: 0,
+    size: 0,
+    nblocks: 0,
+    ninodes: 0,
+    nlog: 0,
+    logstart: 0,
+    inodestart: 0,
+    bmapstart: 0,
+});
+
+fn sb() -> SuperBlock {
+    *SB.lock()
+}
+
+// read the super block.
+fn readsb(dev: usize) -> SuperBlock {
+    let bp = bread(dev, 1);
+    unsafe { read_unaligned(bp.data.as_ptr() as *const SuperBlock) }
+}
+
+static FS_STARTED: AtomicBool = AtomicBool::new(false);
+
+// init fs. it reads the disk, so it has to run in a process; the
+// first one to get here does it.
+pub fn fsinit(dev: usize) {
+    if FS_STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let sb = readsb(dev);
+    if sb.magic != FSMAGIC {
+        panic!("invalid file system");
+    }
+    *SB.lock() = sb;
+    initlog(dev, sb.logstart, sb.nlog);
+    info!(
+        "{} blocks, {} inodes, {} log blocks",
+        sb.size, sb.ninodes, sb.nlog
+    );
+}
+
+// a u32 in a block, as the disk stores them.
+fn block_u32(bp: &Buf, i: usize) -> u32 {
+    u32::from_le_bytes(bp.data[i * 4..i * 4 + 4].try_into().unwrap())
+}
+
+fn set_block_u32(bp: &mut Buf, i: usize, value: u32) {
+    bp.data[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// zero a block.
+fn bzero(dev: usize, bno: u32) {
+    let mut bp = bread(dev, bno);
+    bp.data.fill(0);
+    log_write(&bp);
+    brelse(bp);
+}
+
+// blocks.
+
+// allocate a zeroed disk block.
+// returns 0 if out of disk space.
+fn balloc(dev: usize) -> u32 {
+    let sb = sb();
+    let mut b = 0;
+    while b < sb.size {
+        let mut bp = bread(dev, bblock(b, &sb));
+        let mut bi = 0;
+        while bi < BPB as u32 && b + bi < sb.size {
+            let m = 1 << (bi % 8);
+            let byte = (bi / 8) as usize;
+            if bp.data[byte] & m == 0 {
+                // is block free?
+                bp.data[byte] |= m; // mark block in use.
+                log_write(&bp);
+                brelse(bp);
+                bzero(dev, b + bi);
+                return b + bi;
+            }
+            bi += 1;
+        }
+        brelse(bp);
+        b += BPB as u32;
+    }
+    warn!("balloc: out of blocks");
+    0
+}
+
+// free a disk block.
+fn bfree(dev: usize, b: u32) {
+    let sb = sb();
+    let mut bp = bread(dev, bblock(b, &sb));
+    let bi = b % BPB as u32;
+    let m = 1 << (bi % 8);
+    let byte = (bi / 8) as usize;
+    if bp.data[byte] & m == 0 {
+        panic!("freeing free block");
+    }
+    bp.data[byte] &= !m;
+    log_write(&bp);
+    brelse(bp);
+}
+
+// inodes.
+//
+// an inode describes a single unnamed file.
+// the inode disk structure holds metadata: the file's type,
+// its size, the number of links referring to it, and the
+// list of blocks holding the file's content.
+//
+// the inodes are laid out sequentially on disk at block
+// sb.inodestart. each inode has a number, indicating its
+// position on the disk.
+//
+// the kernel keeps a table of in-use inodes in memory
+// to provide a place for synchronizing access
+// to inodes used by multiple processes. the in-memory
+// inodes include book-keeping information that is
+// not stored on disk: refcnt and valid.
+//
+// an inode and its in-memory representation go through a
+// sequence of states before they can be used by the
+// rest of the file system code.
+//
+// * allocation: an inode is allocated if its type (on disk)
+//   is non-zero. ialloc() allocates, and iput() frees if
+//   the reference and link counts have fallen to zero.
+//
+// * referencing in table: an entry in the inode table
+//   is free if refcnt is zero. otherwise refcnt tracks
+//   the number of in-memory handles (Ip) to the entry:
+//   open files and current directories. iget() finds or
+//   creates a table entry and increments its ref; iput()
+//   decrements ref.
+//
+// * valid: the information (type, size, &c) in an inode
+//   table entry is only correct when valid is true.
+//   ilock() reads the inode from the disk and sets valid,
+//   while iput() clears valid if refcnt has fallen to zero.
+//
+// * locked: file system code may only examine and modify
+//   the information in an inode and its content if it
+//   has first locked the inode.
+//
+// thus a typical sequence is:
+//   let ip = iget(dev, inum);
+//   let mut inode = ilock(ip);
+//   ... examine and modify inode ...
+//   drop(inode);
+//   iput(ip);
+//
+// ilock() is separate from iget() so that system calls can
+// get a long-term reference to an inode (as for an open file)
+// and only lock it for short periods (e.g., in read()).
+// the separation also helps avoid deadlock and races during
+// pathname lookup. iget() increments refcnt so that the inode
+// stays in the table and pointers to it remain valid.
+
+// in-memory copy of an inode. the sleep lock around it protects
+// everything here.
+pub struct Inode {
+    pub dev: usize,  // device number
+    pub inum: u32,   // inode number
+    pub valid: bool, // inode has been read from disk?
+
+    // copy of disk inode
+    pub typ: i16,
+    pub major: i16,
+    pub minor: i16,
+    pub nlink: i16,
+    pub size: u32,
+    pub addrs: [u32; NDIRECT + 1],
+}
+
+// which inode a table entry holds, protected by ITABLE.
+#[derive(Clone, Copy)]
+struct InodeMeta {
+    dev: usize,
+    inum: u32,
+    refcnt: usize,
+}
+
+static ITABLE: SpinMutex<[InodeMeta; NINODE]> = SpinMutex::new(
+    [InodeMeta {
+        dev: 0,
+        inum: 0,
+        refcnt: 0,
+    }; NINODE],
+);
+
+static INODES: [SleepLock<Inode>; NINODE] = [const {
+    SleepLock::new(Inode {
+        dev: 0,
+        inum: 0,
+        valid: false,
+        typ: 0,
+        major: 0,
+        minor: 0,
+        nlink: 0,
+        size: 0,
+        addrs: [0; NDIRECT + 1],
+    })
+}; NINODE];
+
+// a counted reference to an inode table entry, what xv6 calls a
+// struct inode *. get one from iget() or idup(), give it back with
+// iput().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ip(usize);
+
+pub type InodeGuard = SleepLockGuard<'static, Inode>;
+
+fn dinode_ptr(bp: &mut Buf, inum: u32) -> *mut Dinode {
+    let off = (inum as usize % layout::IPB) * size_of::<Dinode>();
+    bp.data[off..].as_mut_ptr() as *mut Dinode
+}
+
+// allocate an inode on device dev.
+// mark it as allocated by giving it type typ.
+// returns an unlocked but allocated and referenced inode,
+// or None if there is no free inode.
+pub fn ialloc(dev: usize, typ: i16) -> Option<Ip> {
+    let sb = sb();
+    for inum in 1..sb.ninodes {
+        let mut bp = bread(dev, iblock(inum, &sb));
+        let dip = dinode_ptr(&mut bp, inum);
+        if unsafe { read_unaligned(dip) }.typ == 0 {
+            // a free inode
+            let dinode = Dinode {
+                typ,
+                ..Default::default()
+            };
+            unsafe { write_unaligned(dip, dinode) };
+            log_write(&bp); // mark it allocated on the disk
+            brelse(bp);
+            return Some(iget(dev, inum));
+        }
+        brelse(bp);
+    }
+    warn!("ialloc: no inodes");
+    None
+}
+
+// copy a modified in-memory inode to disk.
+// must be called after every change to an inode field
+// that lives on disk.
+pub fn iupdate(ip: &Inode) {
+    let sb = sb();
+    let mut bp = bread(ip.dev, iblock(ip.inum, &sb));
+    let dip = dinode_ptr(&mut bp, ip.inum);
+    let dinode = Dinode {
+        typ: ip.typ,
+        major: ip.major,
+        minor: ip.minor,
+        nlink: ip.nlink,
+        size: ip.size,
+        addrs: ip.addrs,
+    };
+    unsafe { write_unaligned(dip, dinode) };
+    log_write(&bp);
+    brelse(bp);
+}
+
+// find the inode with number inum on device dev
+// and return the in-memory copy. does not lock
+// the inode and does not read it from disk.
+pub fn iget(dev: usize, inum: u32) -> Ip {
+    let mut itable = ITABLE.lock();
+
+    // is the inode already in the table?
+    if let Some(i) = itable
+        .iter()
+        .position(|m| m.refcnt > 0 && m.dev == dev && m.inum == inum)
+    {
+        itable[i].refcnt += 1;
+        return Ip(i);
+    }
+
+    // recycle an inode entry.
+    let i = itable
+        .iter()
+        .position(|m| m.refcnt == 0)
+        .expect("iget: no inodes");
+    itable[i] = InodeMeta {
+        dev,
+        inum,
+        refcnt: 1,
+    };
+    // nobody holds the lock of an entry with refcnt 0.
+    let mut inode = INODES[i].lock();
+    inode.dev = dev;
+    inode.inum = inum;
+    inode.valid = false;
+    drop(inode);
+    Ip(i)
+}
+
+// the inode number of ip.
+fn inum(ip: Ip) -> u32 {
+    ITABLE.lock()[ip.0].inum
+}
+
+// increment reference count for ip.
+// returns ip to enable let ip1 = idup(ip);
+pub fn idup(ip: Ip) -> Ip {
+    ITABLE.lock()[ip.0].refcnt += 1;
+    ip
+}
+
+// lock the given inode.
+// reads the inode from disk if necessary.
+pub fn ilock(ip: Ip) -> InodeGuard {
+    if ITABLE.lock()[ip.0].refcnt < 1 {
+        panic!("ilock");
+    }
+    let mut inode = INODES[ip.0].lock();
+    if !inode.valid {
+        let sb = sb();
+        let mut bp = bread(inode.dev, iblock(inode.inum, &sb));
+        let dinode = unsafe { read_unaligned(dinode_ptr(&mut bp, inode.inum)) };
+        brelse(bp);
+        inode.typ = dinode.typ;
+        inode.major = dinode.major;
+        inode.minor = dinode.minor;
+        inode.nlink = dinode.nlink;
+        inode.size = dinode.size;
+        inode.addrs = dinode.addrs;
+        inode.valid = true;
+        if inode.typ == 0 {
+            panic!("ilock: no type");
+        }
+    }
+    inode
+}
+
+// drop a reference to an in-memory inode.
+// if that was the last reference, the inode table entry can
+// be recycled.
+// if that was the last reference and the inode has no links
+// to it, free the inode (and its content) on disk.
+// all calls to iput() must be inside a transaction in
+// case it has to free the inode.
+pub fn iput(ip: Ip) {
+    let mut itable = ITABLE.lock();
+
+    if itable[ip.0].refcnt == 1 {
+        // refcnt == 1 means no other process can have ip locked,
+        // so this lock won't sleep (or deadlock).
+        let mut inode = INODES[ip.0].lock();
+        if inode.valid && inode.nlink == 0 {
+            // inode has no links and no other references: truncate and free.
+            drop(itable);
+
+            itrunc(&mut inode);
+            inode.typ = 0;
+            iupdate(&inode);
+            inode.valid = false;
+            drop(inode);
+
+            itable = ITABLE.lock();
+        }
+    }
+
+    itable[ip.0].refcnt -= 1;
+}
+
+// common idiom: unlock, then put.
+pub fn iunlockput(ip: Ip, inode: InodeGuard) {
+    drop(inode);
+    iput(ip);
+}
+
+// inode content
+//
+// the content (data) associated with each inode is stored
+// in blocks on the disk. the first NDIRECT block numbers
+// are listed in ip.addrs[]. the next NINDIRECT blocks are
+// listed in block ip.addrs[NDIRECT].
+
+// return the disk block address of the nth block in inode ip.
+// if there is no such block, bmap allocates one.
+// returns 0 if out of disk space.
+fn bmap(ip: &mut Inode, bn: usize) -> u32 {
+    if bn < NDIRECT {
+        if ip.addrs[bn] == 0 {
+            ip.addrs[bn] = balloc(ip.dev);
+        }
+        return ip.addrs[bn];
+    }
+    let bn = bn - NDIRECT;
+
+    if bn < NINDIRECT {
+        // load indirect block, allocating if necessary.
+        if ip.addrs[NDIRECT] == 0 {
+            ip.addrs[NDIRECT] = balloc(ip.dev);
+            if ip.addrs[NDIRECT] == 0 {
+                return 0;
+            }
+        }
+        let mut bp = bread(ip.dev, ip.addrs[NDIRECT]);
+        let mut addr = block_u32(&bp, bn);
+        if addr == 0 {
+            addr = balloc(ip.dev);
+            if addr != 0 {
+                set_block_u32(&mut bp, bn, addr);
+                log_write(&bp);
+            }
+        }
+        brelse(bp);
+        return addr;
+    }
+
+    panic!("bmap: out of range");
+}
+
+// truncate inode (discard contents).
+// caller must hold the inode locked.
+pub fn itrunc(ip: &mut Inode) {
+    for i in 0..NDIRECT {
+        if ip.addrs[i] != 0 {
+            bfree(ip.dev, ip.addrs[i]);
+            ip.addrs[i] = 0;
+        }
+    }
+
+    if ip.addrs[NDIRECT] != 0 {
+        let bp = bread(ip.dev, ip.addrs[NDIRECT]);
+        for j in 0..NINDIRECT {
+            let addr = block_u32(&bp, j);
+            if addr != 0 {
+                bfree(ip.dev, addr);
+            }
+        }
+        brelse(bp);
+        bfree(ip.dev, ip.addrs[NDIRECT]);
+        ip.addrs[NDIRECT] = 0;
+    }
+
+    ip.size = 0;
+    iupdate(ip);
+}
+
+// copy stat information from inode.
+// caller must hold the inode locked.
+pub fn stati(ip: &Inode) -> Stat {
+    Stat {
+        dev: ip.dev as i32,
+        ino: ip.inum,
+        typ: ip.typ,
+        nlink: ip.nlink,
+        size: ip.size as u64,
+    }
+}
+
+// read data from inode.
+// caller must hold the inode locked.
+// if user_dst is true, then dst is a user virtual address;
+// otherwise, dst is a kernel address.
+// returns the number of bytes read, or -1 if copying out failed.
+pub fn readi(ip: &mut Inode, user_dst: bool, mut dst: usize, mut off: u32, n: u32) -> isize {
+    if off > ip.size || off.checked_add(n).is_none() {
+        return 0;
+    }
+    let n = n.min(ip.size - off);
+
+    let mut tot = 0;
+    while tot < n {
+        let addr = bmap(ip, off as usize / BSIZE);
+        if addr == 0 {
+            break;
+        }
+        let bp = bread(ip.dev, addr);
+        let start = off as usize % BSIZE;
+        let m = (n - tot).min((BSIZE - start) as u32);
+        if !either_copyout(user_dst, dst, &bp.data[start..start + m as usize]) {
+            return -1;
+        }
+        brelse(bp);
+        tot += m;
+        off += m;
+        dst += m as usize;
+    }
+    tot as isize
+}
+
+// write data to inode.
+// caller must hold the inode locked.
+// if user_src is true, then src is a user virtual address;
+// otherwise, src is a kernel address.
+// returns the number of bytes successfully written.
+// if the return value is less than the requested n,
+// there was an error of some kind.
+pub fn writei(ip: &mut Inode, user_src: bool, mut src: usize, mut off: u32, n: u32) -> isize {
+    if off > ip.size || off.checked_add(n).is_none() {
+        return -1;
+    }
+    if (off + n) as usize > MAXFILE * BSIZE {
+        return -1;
+    }
+
+    let mut tot = 0;
+    while tot < n {
+        let addr = bmap(ip, off as usize / BSIZE);
+        if addr == 0 {
+            break;
+        }
+        let mut bp = bread(ip.dev, addr);
+        let start = off as usize % BSIZE;
+        let m = (n - tot).min((BSIZE - start) as u32);
+        if !either_copyin(&mut bp.data[start..start + m as usize], user_src, src) {
+            break;
+        }
+        log_write(&bp);
+        brelse(bp);
+        tot += m;
+        off += m;
+        src += m as usize;
+    }
+
+    if off > ip.size {
+        ip.size = off;
+    }
+
+    // write the i-node back to disk even if the size didn't change
+    // because the loop above might have called bmap() and added a new
+    // block to ip.addrs[].
+    iupdate(ip);
+
+    tot as isize
+}
+
+// directories
+
+const DIRENT_SIZE: u32 = size_of::<Dirent>() as u32;
+
+fn read_dirent(dp: &mut Inode, off: u32) -> Dirent {
+    let mut de = Dirent::default();
+    if readi(dp, false, &mut de as *mut Dirent as usize, off, DIRENT_SIZE) != DIRENT_SIZE as isize {
+        panic!("dirent read");
+    }
+    de
+}
+
+// look for a directory entry in a directory.
+// if found, return its inode and the byte offset of the entry.
+pub fn dirlookup(dp: &mut Inode, name: &[u8]) -> Option<(Ip, u32)> {
+    if dp.typ != T_DIR {
+        panic!("dirlookup not DIR");
+    }
+
+    let name = &name[..name.len().min(DIRSIZ)];
+    for off in (0..dp.size).step_by(DIRENT_SIZE as usize) {
+        let de = read_dirent(dp, off);
+        if de.inum == 0 {
+            continue;
+        }
+        if de.name() == name {
+            // entry matches path element
+            return Some((iget(dp.dev, de.inum as u32), off));
+        }
+    }
+    None
+}
+
+// write a new directory entry (name, inum) into the directory dp.
+// returns false if the name is already there or the disk is full.
+pub fn dirlink(dp: &mut Inode, name: &[u8], inum: u32) -> bool {
+    // check that name is not present.
+    if let Some((ip, _)) = dirlookup(dp, name) {
+        iput(ip);
+        return false;
+    }
+
+    // look for an empty dirent.
+    let off = (0..dp.size)
+        .step_by(DIRENT_SIZE as usize)
+        .find(|&off| read_dirent(dp, off).inum == 0)
+        .unwrap_or(dp.size);
+
+    let mut de = Dirent {
+        inum: inum as u16,
+        ..Default::default()
+    };
+    let len = name.len().min(DIRSIZ);
+    de.name[..len].copy_from_slice(&name[..len]);
+    writei(dp, false, &de as *const Dirent as usize, off, DIRENT_SIZE) == DIRENT_SIZE as isize
+}
@@ -0,0 +1,85 @@
+// on-disk file system format.
+// both the kernel and the host mkfs tool use this header, so it may
+// only depend on core and on params.
+
+use core::mem::size_of;
+
+use crate::params::BSIZE;
+
+pub const ROOTINO: u32 = 1; // root i-number
+
+// disk layout:
+// [ boot block | super block | log | inode blocks |
+//                                          free bit map | data blocks]
+//
+// mkfs computes the super block and builds an initial file system. the
+// super block describes the disk layout:
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SuperBlock {
+    pub magic: u32,      // must be FSMAGIC
+    pub size: u32,       // size of file system image (blocks)
+    pub nblocks: u32,    // number of data blocks
+    pub ninodes: u32,    // number of inodes.
+    pub nlog: u32,       // number of log blocks
+    pub logstart: u32,   // block number of first log block
+    pub inodestart: u32, // block number of first inode block
+    pub bmapstart: u32,  // block number of first free map block
+}
+
+pub const FSMAGIC: u32 = 0x10203040;
+
+pub const NDIRECT: usize = 12;
+pub const NINDIRECT: usize = BSIZE / size_of::<u32>();
+pub const MAXFILE: usize = NDIRECT + NINDIRECT;
+
+// file types, in Dinode.typ.
+pub const T_DIR: i16 = 1; // directory
+pub const T_FILE: i16 = 2; // file
+pub const T_DEVICE: i16 = 3; // device
+
+// on-disk inode structure
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Dinode {
+    pub typ: i16,                  // file type, 0 if free
+    pub major: i16,                // major device number (T_DEVICE only)
+    pub minor: i16,                // minor device number (T_DEVICE only)
+    pub nlink: i16,                // number of links to inode in file system
+    pub size: u32,                 // size of file (bytes)
+    pub addrs: [u32; NDIRECT + 1], // data block addresses
+}
+
+// inodes per block.
+pub const IPB: usize = BSIZE / size_of::<Dinode>();
+
+// block containing inode i
+pub fn iblock(i: u32, sb: &SuperBlock) -> u32 {
+    i / IPB as u32 + sb.inodestart
+}
+
+// bitmap bits per block
+pub const BPB: usize = BSIZE * 8;
+
+// block of free map containing bit for block b
+pub fn bblock(b: u32, sb: &SuperBlock) -> u32 {
+    b / BPB as u32 + sb.bmapstart
+}
+
+// directory is a file containing a sequence of dirent structures.
+pub const DIRSIZ: usize = 14;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Dirent {
+    pub inum: u16,
+    pub name: [u8; DIRSIZ],
+}
+
+impl Dirent {
+    // the name, without the nul padding.
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+        &self.name[..len]
+    }
+}
@@ -0,0 +1,234 @@
+// the native file system, seen through the vfs.
+
+use alloc::sync::Arc;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::layout::{Dirent, ROOTINO, T_DEVICE, T_DIR};
+use super::{
+    dirlink, dirlookup, fsinit, ialloc, iget, ilock, inum, iput, itrunc, iunlockput, iupdate,
+    read_dirent, readi, stati, writei, Ip,
+};
+use crate::log::{begin_op, end_op};
+use crate::params::{BSIZE, MAXOPBLOCKS};
+use crate::stat::Stat;
+use crate::vfs::{alloc_fsid, DirEntry, FileOps, FsType, InodeOps, InodeRef, SuperOps};
+
+pub struct Xv6FsType;
+
+pub static XV6FS: Xv6FsType = Xv6FsType;
+
+// there is one superblock and one log, so one instance at most.
+static MOUNTED: AtomicBool = AtomicBool::new(false);
+
+impl FsType for Xv6FsType {
+    fn name(&self) -> &'static str {
+        "xv6fs"
+    }
+
+    fn mount(&self, dev: usize) -> Result<Arc<dyn SuperOps>, &'static str> {
+        if MOUNTED.swap(true, Ordering::AcqRel) {
+            return Err("xv6fs is already mounted");
+        }
+        fsinit(dev);
+        Ok(Arc::new(Xv6Fs {
+            dev,
+            fsid: alloc_fsid(),
+        }))
+    }
+}
+
+struct Xv6Fs {
+    dev: usize,
+    fsid: usize,
+}
+
+impl SuperOps for Xv6Fs {
+    fn root(&self) -> InodeRef {
+        xv6_inode(self.fsid, iget(self.dev, ROOTINO))
+    }
+}
+
+// holds a reference to an inode table entry, given back on drop.
+struct Xv6Inode {
+    fsid: usize,
+    inum: u32,
+    ip: Ip,
+}
+
+fn xv6_inode(fsid: usize, ip: Ip) -> InodeRef {
+    Arc::new(Xv6Inode {
+        fsid,
+        inum: inum(ip),
+        ip,
+    })
+}
+
+impl Drop for Xv6Inode {
+    // iput() may free the inode, so it needs a transaction.
+    fn drop(&mut self) {
+        begin_op();
+        iput(self.ip);
+        end_op();
+    }
+}
+
+impl FileOps for Xv6Inode {
+    fn read_at(&self, user_dst: bool, dst: usize, off: u64, n: usize) -> isize {
+        let off = match u32::try_from(off) {
+            Ok(off) => off,
+            Err(_) => return 0,
+        };
+        let mut inode = ilock(self.ip);
+        readi(&mut inode, user_dst, dst, off, n as u32)
+    }
+
+    fn write_at(&self, user_src: bool, src: usize, off: u64, n: usize) -> isize {
+        let off = match u32::try_from(off) {
+            Ok(off) => off,
+            Err(_) => return -1,
+        };
+
+        // write a few blocks at a time to avoid exceeding
+        // the maximum log transaction size, including
+        // i-node, indirect block, allocation blocks,
+        // and 2 blocks of slop for non-aligned writes.
+        let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+        let mut i = 0;
+        while i < n {
+            let n1 = (n - i).min(max);
+
+            begin_op();
+            let mut inode = ilock(self.ip);
+            let r = writei(&mut inode, user_src, src + i, off + i as u32, n1 as u32);
+            drop(inode);
+            end_op();
+
+            if r != n1 as isize {
+                // error from writei
+                break;
+            }
+            i += n1;
+        }
+        if i == n {
+            n as isize
+        } else {
+            -1
+        }
+    }
+
+    fn readdir(&self, index: usize) -> Option<DirEntry> {
+        let mut inode = ilock(self.ip);
+        if inode.typ != T_DIR {
+            return None;
+        }
+        let size = inode.size;
+        (0..size)
+            .step_by(size_of::<Dirent>())
+            .map(|off| read_dirent(&mut inode, off))
+            .filter(|de| de.inum != 0)
+            .nth(index)
+            .map(|de| DirEntry {
+                ino: de.inum as u64,
+                name: de.name().to_vec(),
+            })
+    }
+}
+
+impl InodeOps for Xv6Inode {
+    fn id(&self) -> (usize, u64) {
+        (self.fsid, self.inum as u64)
+    }
+
+    fn stat(&self) -> Stat {
+        stati(&ilock(self.ip))
+    }
+
+    fn device(&self) -> Option<(usize, usize)> {
+        let inode = ilock(self.ip);
+        if inode.typ != T_DEVICE {
+            return None;
+        }
+        Some((inode.major as usize, inode.minor as usize))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Option<InodeRef> {
+        let mut dir = ilock(self.ip);
+        if dir.typ != T_DIR {
+            return None;
+        }
+        let (ip, _) = dirlookup(&mut dir, name)?;
+        drop(dir);
+        Some(xv6_inode(self.fsid, ip))
+    }
+
+    fn create(
+        &self,
+        name: &[u8],
+        typ: i16,
+        major: i16,
+        minor: i16,
+    ) -> Result<InodeRef, &'static str> {
+        begin_op();
+        let r = create(self.ip, name, typ, major, minor);
+        end_op();
+        r.map(|ip| xv6_inode(self.fsid, ip))
+    }
+
+    fn truncate(&self) -> Result<(), &'static str> {
+        begin_op();
+        let mut inode = ilock(self.ip);
+        itrunc(&mut inode);
+        drop(inode);
+        end_op();
+        Ok(())
+    }
+}
+
+// make a new inode of type typ called name in directory dp and link
+// it in. must be called inside a transaction.
+fn create(dp: Ip, name: &[u8], typ: i16, major: i16, minor: i16) -> Result<Ip, &'static str> {
+    let mut dir = ilock(dp);
+    if dir.typ != T_DIR {
+        return Err("not a directory");
+    }
+
+    if let Some((ip, _)) = dirlookup(&mut dir, name) {
+        drop(dir);
+        iput(ip);
+        return Err("file exists");
+    }
+
+    let ip = ialloc(dir.dev, typ).ok_or("out of inodes")?;
+
+    let mut inode = ilock(ip);
+    inode.major = major;
+    inode.minor = minor;
+    inode.nlink = 1;
+    iupdate(&inode);
+
+    let inum = inode.inum;
+    let linked = if typ == T_DIR {
+        // create . and .. entries.
+        // no ip.nlink += 1 for ".": avoid cyclic ref count.
+        dirlink(&mut inode, b".", inum) && dirlink(&mut inode, b"..", dir.inum)
+    } else {
+        true
+    };
+
+    if linked && dirlink(&mut dir, name, inum) {
+        if typ == T_DIR {
+            // now that success is guaranteed:
+            dir.nlink += 1; // for ".."
+            iupdate(&dir);
+        }
+        drop(inode);
+        return Ok(ip);
+    }
+
+    // something went wrong. de-allocate ip.
+    inode.nlink = 0;
+    iupdate(&inode);
+    iunlockput(ip, inode);
+    Err("out of disk space")
+}
@@ -0,0 +1,178 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::fdt::{self, be32};
+use crate::{info, warn};
+use crate::proc::cpuid;
+use crate::riscv::{claim_stopei, r_sireg, w_sireg, w_siselect};
+use crate::spin_lock::SpinMutex;
+
+// the incoming MSI controller of the riscv advanced interrupt
+// architecture (qemu -machine virt,aia=aplic-imsic). every hart has an
+// interrupt file per privilege level; a device raises interrupt
+// identity n on a hart by writing n to the first word of that hart's
+// supervisor file. see chapter 3 of the AIA spec.
+
+// registers of this hart's file, reached through siselect/sireg.
+const IMSIC_EIDELIVERY: u64 = 0x70;
+const IMSIC_EITHRESHOLD: u64 = 0x72;
+const IMSIC_EIP0: u64 = 0x80;
+const IMSIC_EIE0: u64 = 0xc0;
+
+// interrupts-extended of the supervisor files names this cause.
+const IRQ_S_EXT: u32 = 9;
+
+// identities we keep track of. 0 is never used; qemu implements 1..=255.
+pub const NMSI: usize = 256;
+
+static IMSIC_PRESENT: AtomicBool = AtomicBool::new(false);
+static IMSIC_BASE: AtomicUsize = AtomicUsize::new(0); // hart 0's supervisor file
+static IMSIC_STRIDE: AtomicUsize = AtomicUsize::new(0); // between harts' files
+static IMSIC_NUM_IDS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+struct MsiHandler {
+    handler: fn(usize),
+    ctx: usize,
+}
+
+static MSI_HANDLERS: SpinMutex<[Option<MsiHandler>; NMSI]> = SpinMutex::new([None; NMSI]);
+
+// what a device has to write where to raise an identity.
+#[derive(Clone, Copy, Debug)]
+pub struct MsiMessage {
+    pub id: usize,
+    pub address: u64,
+    pub data: u32,
+}
+
+pub fn imsic_present() -> bool {
+    IMSIC_PRESENT.load(Ordering::Relaxed)
+}
+
+// where hart 0's supervisor file is, and how far apart the harts' are.
+pub fn imsic_layout() -> (usize, usize) {
+    (
+        IMSIC_BASE.load(Ordering::Relaxed),
+        IMSIC_STRIDE.load(Ordering::Relaxed),
+    )
+}
+
+// find the supervisor interrupt files in the device tree.
+// without them there are no msis and devices fall back to wired irqs.
+pub fn imsic_init() {
+    let fdt = match fdt::fdt() {
+        Some(fdt) => fdt,
+        None => return,
+    };
+    let address_cells = fdt.root().property_u32("#address-cells").unwrap_or(2) as usize;
+    let size_cells = fdt.root().property_u32("#size-cells").unwrap_or(2) as usize;
+    for node in fdt.find_all_compatible("riscv,imsics") {
+        // the machine-level files are a node of their own, wired to cause 11.
+        let supervisor = node
+            .property("interrupts-extended")
+            .is_some_and(|irqs| irqs.len() >= 8 && be32(&irqs[4..]) == IRQ_S_EXT);
+        if !supervisor {
+            continue;
+        }
+        let (base, _) = match node.reg(address_cells, size_cells) {
+            Some(reg) => reg,
+            None => continue,
+        };
+        let guest_bits = node.property_u32("riscv,guest-index-bits").unwrap_or(0);
+        let num_ids = node.property_u32("riscv,num-ids").unwrap_or(63) as usize;
+        IMSIC_BASE.store(base as usize, Ordering::Relaxed);
+        IMSIC_STRIDE.store(0x1000 << guest_bits, Ordering::Relaxed);
+        IMSIC_NUM_IDS.store(num_ids.min(NMSI - 1), Ordering::Relaxed);
+        IMSIC_PRESENT.store(true, Ordering::Relaxed);
+        info!("supervisor files at {:#x}, {} ids", base, num_ids);
+        return;
+    }
+}
+
+fn imsic_read(reg: u64) -> u64 {
+    w_siselect(reg);
+    r_sireg()
+}
+
+fn imsic_write(reg: u64, value: u64) {
+    w_siselect(reg);
+    w_sireg(value);
+}
+
+// on rv64 eip and eie come in 64-bit registers with even numbers only.
+fn id_reg(first: u64, id: usize) -> u64 {
+    first + (id / 64) as u64 * 2
+}
+
+// take interrupts from this hart's supervisor file.
+pub fn imsic_inithart() {
+    if !imsic_present() {
+        return;
+    }
+    let num_ids = IMSIC_NUM_IDS.load(Ordering::Relaxed);
+    for id in (0..=num_ids).step_by(64) {
+        imsic_write(id_reg(IMSIC_EIE0, id), 0);
+        imsic_write(id_reg(IMSIC_EIP0, id), 0);
+    }
+    imsic_write(IMSIC_EITHRESHOLD, 0); // no threshold
+    imsic_write(IMSIC_EIDELIVERY, 1);
+}
+
+fn set_enabled(id: usize, enable: bool) {
+    let reg = id_reg(IMSIC_EIE0, id);
+    let bits = imsic_read(reg);
+    let bit = 1 << (id % 64);
+    imsic_write(reg, if enable { bits | bit } else { bits & !bit });
+}
+
+// hand out a free identity on this hart that calls handler(ctx) when
+// it arrives. None if there is no imsic or no identity left.
+pub fn msi_alloc(handler: fn(usize), ctx: usize) -> Option<MsiMessage> {
+    if !imsic_present() {
+        return None;
+    }
+    let mut handlers = MSI_HANDLERS.lock();
+    let num_ids = IMSIC_NUM_IDS.load(Ordering::Relaxed);
+    let id = (1..=num_ids).find(|&id| handlers[id].is_none())?;
+    handlers[id] = Some(MsiHandler { handler, ctx });
+    // interrupts are off while the lock is held, so we stay on this hart.
+    set_enabled(id, true);
+    let file = IMSIC_BASE.load(Ordering::Relaxed) + cpuid() * IMSIC_STRIDE.load(Ordering::Relaxed);
+    Some(MsiMessage {
+        id,
+        address: file as u64,
+        data: id as u32,
+    })
+}
+
+// must run on the hart the identity was allocated on.
+pub fn msi_free(id: usize) {
+    let mut handlers = MSI_HANDLERS.lock();
+    if id == 0 || id >= NMSI || handlers[id].is_none() {
+        panic!("msi_free: identity {}", id);
+    }
+    set_enabled(id, false);
+    handlers[id] = None;
+}
+
+// claim and dispatch everything pending in this hart's file.
+// returns false if there was nothing, so devintr() asks the plic.
+pub fn imsic_intr() -> bool {
+    if !imsic_present() {
+        return false;
+    }
+    let mut handled = false;
+    loop {
+        let id = ((claim_stopei() >> 16) & 0x7ff) as usize;
+        if id == 0 {
+            break;
+        }
+        handled = true;
+        let entry = MSI_HANDLERS.lock().get(id).copied().flatten();
+        match entry {
+            Some(entry) => (entry.handler)(entry.ctx),
+            None => warn!("unexpected identity {}", id),
+        }
+    }
+    handled
+}
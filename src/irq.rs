@@ -0,0 +1,136 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::aplic::aplic_set_enable;
+use crate::params::NCPU;
+use crate::plic::{plic_set_enable, plic_set_priority};
+use crate::spin_lock::SpinMutex;
+
+// drivers ask for a plic irq with request_irq() and devintr() hands
+// every claimed irq to handle_irq(), which runs the handlers. with an
+// aplic instead, its msis reach handle_irq() through the imsic.
+
+// irqs 1..NIRQ can be requested; the plic reports 0 for none.
+pub const NIRQ: usize = 64;
+// handlers that can share one irq.
+const NSHARED: usize = 8;
+const DEFAULT_PRIORITY: u32 = 1;
+
+#[derive(Clone, Copy)]
+struct IrqAction {
+    handler: fn(usize),
+    ctx: usize,
+}
+
+#[derive(Clone, Copy)]
+struct IrqDesc {
+    actions: [Option<IrqAction>; NSHARED],
+    priority: u32,
+    harts: u64, // bit n set: hart n may take the irq
+}
+
+impl IrqDesc {
+    const fn new() -> Self {
+        IrqDesc {
+            actions: [None; NSHARED],
+            priority: DEFAULT_PRIORITY,
+            harts: !0,
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.actions.iter().any(|a| a.is_some())
+    }
+}
+
+static IRQS: SpinMutex<[IrqDesc; NIRQ]> = SpinMutex::new([IrqDesc::new(); NIRQ]);
+static IRQ_COUNTS: [AtomicUsize; NIRQ] = [const { AtomicUsize::new(0) }; NIRQ];
+
+fn valid_irq(irq: usize) -> bool {
+    irq != 0 && irq < NIRQ
+}
+
+// make the plic agree with desc: an irq without handlers has priority
+// 0, which disables it, and is enabled on no hart. on an aplic, which
+// has no priorities, it just goes on or off.
+fn irq_apply(irq: usize, desc: &IrqDesc) {
+    let active = desc.active();
+    plic_set_priority(irq, if active { desc.priority } else { 0 });
+    for hart in 0..NCPU {
+        plic_set_enable(hart, irq, active && desc.harts & (1 << hart) != 0);
+    }
+    aplic_set_enable(irq, active);
+}
+
+// have handler(ctx) called whenever irq fires. irqs can be shared, so
+// a handler must cope with being called when its device didn't interrupt.
+pub fn request_irq(irq: usize, handler: fn(usize), ctx: usize) -> Result<(), &'static str> {
+    if !valid_irq(irq) {
+        return Err("no such irq");
+    }
+    let mut irqs = IRQS.lock();
+    let desc = &mut irqs[irq];
+    let slot = desc
+        .actions
+        .iter_mut()
+        .find(|a| a.is_none())
+        .ok_or("too many handlers sharing the irq")?;
+    *slot = Some(IrqAction { handler, ctx });
+    irq_apply(irq, desc);
+    Ok(())
+}
+
+// the irq is disabled once its last handler is gone.
+pub fn free_irq(irq: usize, handler: fn(usize), ctx: usize) {
+    if !valid_irq(irq) {
+        return;
+    }
+    let mut irqs = IRQS.lock();
+    let desc = &mut irqs[irq];
+    for slot in desc.actions.iter_mut() {
+        if slot.is_some_and(|a| a.handler as usize == handler as usize && a.ctx == ctx) {
+            *slot = None;
+        }
+    }
+    irq_apply(irq, desc);
+}
+
+// higher priorities are claimed first; 1..=7 on qemu.
+pub fn irq_set_priority(irq: usize, priority: u32) {
+    if !valid_irq(irq) || priority == 0 {
+        return;
+    }
+    let mut irqs = IRQS.lock();
+    irqs[irq].priority = priority;
+    irq_apply(irq, &irqs[irq]);
+}
+
+// restrict irq to the harts whose bits are set in harts.
+pub fn irq_set_affinity(irq: usize, harts: u64) {
+    if !valid_irq(irq) {
+        return;
+    }
+    let mut irqs = IRQS.lock();
+    irqs[irq].harts = harts;
+    irq_apply(irq, &irqs[irq]);
+}
+
+// how many times irq has been claimed since boot.
+pub fn irq_count(irq: usize) -> usize {
+    IRQ_COUNTS.get(irq).map_or(0, |c| c.load(Ordering::Relaxed))
+}
+
+// run the handlers of a claimed irq. false if nobody asked for it.
+pub fn handle_irq(irq: usize) -> bool {
+    if !valid_irq(irq) {
+        return false;
+    }
+    IRQ_COUNTS[irq].fetch_add(1, Ordering::Relaxed);
+    // copy the handlers so they run without the lock held.
+    let actions = IRQS.lock()[irq].actions;
+    let mut handled = false;
+    for action in actions.iter().flatten() {
+        (action.handler)(action.ctx);
+        handled = true;
+    }
+    handled
+}
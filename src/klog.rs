@@ -0,0 +1,306 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::ns16550::Ns16550;
+use crate::print;
+use crate::proc::cpuid;
+use crate::riscv::r_time;
+use crate::spin_lock::SpinMutex;
+
+// the kernel log. error!, warn!, info!, debug! and trace! append a line
+// to an in-memory ring buffer, which user space reads with the dmesg
+// system call, and echo it on the console if it is important enough.
+//
+// a message is shown on the console if its level is at or below the
+// console level, and kept in the buffer if it is at or below the record
+// level. a per-module level (by module path, "pci" covers pci::bar too)
+// overrides both.
+//
+// if a second serial port is attached as the trace channel, messages
+// at or below the trace level also go there, one per line as
+// "<time> <hart> <level> <module>: <message>", for a host tool to
+// capture apart from the console.
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn from_u8(level: u8) -> Option<Level> {
+        match level {
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            5 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level: expr, $($arg:tt)*) => {
+        $crate::klog::log($level, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Trace, $($arg)*));
+}
+
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static RECORD_LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+pub fn set_console_level(level: Level) {
+    CONSOLE_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_record_level(level: Level) {
+    RECORD_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+static TRACE_LEVEL: AtomicU8 = AtomicU8::new(Level::Trace as u8);
+
+pub fn set_trace_level(level: Level) {
+    TRACE_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// the trace channel, if any. written synchronously, so a trace line is
+// out before whatever the message is about happens.
+static TRACE_PORT: SpinMutex<Option<Ns16550>> = SpinMutex::new(None);
+
+// make port the trace channel. false if there already is one.
+pub fn set_trace_port(port: Ns16550) -> bool {
+    let mut trace = TRACE_PORT.lock();
+    if trace.is_some() {
+        return false;
+    }
+    *trace = Some(port);
+    true
+}
+
+pub fn clear_trace_port() {
+    *TRACE_PORT.lock() = None;
+}
+
+struct TraceWriter<'a>(&'a Ns16550);
+
+impl Write for TraceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            self.0.putc_sync(c);
+        }
+        Ok(())
+    }
+}
+
+// per-module levels.
+const NFILTER: usize = 8;
+pub const MODNAME: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Filter {
+    module: [u8; MODNAME],
+    len: usize,
+    level: Level,
+}
+
+impl Filter {
+    fn module(&self) -> &[u8] {
+        &self.module[..self.len]
+    }
+
+    // "pci" matches pci and pci::bar, but not pcie.
+    fn matches(&self, module: &str) -> bool {
+        let module = module.as_bytes();
+        module.starts_with(self.module())
+            && (module.len() == self.len || module[self.len..].starts_with(b"::"))
+    }
+}
+
+static FILTERS: SpinMutex<[Option<Filter>; NFILTER]> = SpinMutex::new([None; NFILTER]);
+
+// set the level of a module and everything below it, or with None go
+// back to the global levels. false if the module name is too long or
+// there are too many filters.
+pub fn set_module_level(module: &[u8], level: Option<Level>) -> bool {
+    if module.is_empty() || module.len() > MODNAME {
+        return false;
+    }
+    let mut filters = FILTERS.lock();
+    let existing = filters
+        .iter()
+        .position(|f| f.is_some_and(|f| f.module() == module));
+    let level = match level {
+        Some(level) => level,
+        None => {
+            if let Some(i) = existing {
+                filters[i] = None;
+            }
+            return true;
+        }
+    };
+    let slot = match existing.or_else(|| filters.iter().position(|f| f.is_none())) {
+        Some(slot) => slot,
+        None => return false,
+    };
+    let mut name = [0; MODNAME];
+    name[..module.len()].copy_from_slice(module);
+    filters[slot] = Some(Filter {
+        module: name,
+        len: module.len(),
+        level,
+    });
+    true
+}
+
+// the most specific filter for a module, if any.
+fn module_level(module: &str) -> Option<Level> {
+    FILTERS
+        .lock()
+        .iter()
+        .flatten()
+        .filter(|f| f.matches(module))
+        .max_by_key(|f| f.len)
+        .map(|f| f.level)
+}
+
+// the log itself. bytes start..start+len (mod LOG_BUF_SIZE) hold whole
+// lines; old lines are dropped to make room.
+const LOG_BUF_SIZE: usize = 16 * 1024;
+
+struct LogBuf {
+    buf: [u8; LOG_BUF_SIZE],
+    start: usize,
+    len: usize,
+}
+
+static LOG: SpinMutex<LogBuf> = SpinMutex::new(LogBuf {
+    buf: [0; LOG_BUF_SIZE],
+    start: 0,
+    len: 0,
+});
+
+impl LogBuf {
+    fn drop_line(&mut self) {
+        while self.len > 0 {
+            let c = self.buf[self.start];
+            self.start = (self.start + 1) % LOG_BUF_SIZE;
+            self.len -= 1;
+            if c == b'\n' {
+                break;
+            }
+        }
+    }
+
+    fn push(&mut self, c: u8) {
+        if self.len == LOG_BUF_SIZE {
+            self.drop_line();
+        }
+        self.buf[(self.start + self.len) % LOG_BUF_SIZE] = c;
+        self.len += 1;
+    }
+
+    // copy out up to dst.len() bytes starting off bytes into the log.
+    fn read(&self, off: usize, dst: &mut [u8]) -> usize {
+        let n = dst.len().min(self.len.saturating_sub(off));
+        for (i, c) in dst[..n].iter_mut().enumerate() {
+            *c = self.buf[(self.start + off + i) % LOG_BUF_SIZE];
+        }
+        n
+    }
+}
+
+impl Write for LogBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            self.push(c);
+        }
+        Ok(())
+    }
+}
+
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    // module_path!() starts with the crate name.
+    let module = module.split_once("::").map_or("kernel", |(_, rest)| rest);
+    let (console, record) = match module_level(module) {
+        Some(level) => (level as u8, level as u8),
+        None => (
+            CONSOLE_LEVEL.load(Ordering::Relaxed),
+            RECORD_LEVEL.load(Ordering::Relaxed),
+        ),
+    };
+    if level as u8 <= record {
+        let mut log = LOG.lock();
+        let _ = writeln!(log, "[{:5}] {}: {}", level.name(), module, args);
+    }
+    if level as u8 <= console {
+        print!("{}: {}\n", module, args);
+    }
+    if level as u8 <= TRACE_LEVEL.load(Ordering::Relaxed) {
+        if let Some(port) = TRACE_PORT.lock().as_ref() {
+            let _ = writeln!(
+                TraceWriter(port),
+                "{} {} {} {}: {}",
+                r_time(),
+                cpuid(),
+                level.name(),
+                module,
+                args
+            );
+        }
+    }
+}
+
+// copy up to dst.len() bytes of the log, starting off bytes in, to dst.
+pub fn klog_read(off: usize, dst: &mut [u8]) -> usize {
+    LOG.lock().read(off, dst)
+}
+
+pub fn klog_size() -> usize {
+    LOG.lock().len
+}
+
+pub fn klog_clear() {
+    let mut log = LOG.lock();
+    log.start = 0;
+    log.len = 0;
+}
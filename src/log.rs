@@ -0,0 +1,226 @@
+use core::mem::size_of;
+use core::ptr::{addr_of, read_unaligned, write_unaligned};
+
+use crate::bio::{bpin, bread, bunpin, bwrite, Buf};
+use crate::params::{BSIZE, LOGSIZE, MAXOPBLOCKS};
+use crate::proc::{sleep, wakeup};
+use crate::spin_lock::SpinMutex;
+
+// simple logging that allows concurrent fs system calls.
+//
+// a log transaction contains the updates of multiple fs system
+// calls. the logging system only commits when there are
+// no fs system calls active. thus there is never
+// any reasoning required about whether a commit might
+// write an uncommitted system call's updates to disk.
+//
+// a system call should call begin_op()/end_op() to mark
+// its start and end. usually begin_op() just increments
+// the count of in-progress fs system calls and returns.
+// but if it thinks the log is close to running out, it
+// sleeps until the last outstanding end_op() commits.
+//
+// the log is a physical re-do log containing disk blocks.
+// the on-disk log format:
+//   header block, containing block #s for block A, B, C, ...
+//   block A
+//   block B
+//   block C
+//   ...
+// log appends are synchronous.
+
+// contents of the header block, used for both the on-disk header block
+// and to keep track in memory of logged block# before commit.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LogHeader {
+    pub n: u32,
+    pub block: [u32; LOGSIZE],
+}
+
+const _: () = assert!(size_of::<LogHeader>() <= BSIZE);
+
+struct Log {
+    start: u32,         // first block of the log: the header
+    size: u32,          // blocks in the log, header included
+    outstanding: usize, // how many fs sys calls are executing.
+    committing: bool,   // in commit(), please wait.
+    dev: usize,
+    lh: LogHeader,
+}
+
+static LOG: SpinMutex<Log> = SpinMutex::new(Log {
+    start: 0,
+    size: 0,
+    outstanding: 0,
+    committing: false,
+    dev: 0,
+    lh: LogHeader {
+        n: 0,
+        block: [0; LOGSIZE],
+    },
+});
+
+// begin_op() and end_op() wait on this.
+fn log_chan() -> usize {
+    addr_of!(LOG) as usize
+}
+
+// set up the log in blocks start..start+size of dev, and replay
+// whatever a crash left committed there. called when the file system
+// is mounted, before any transaction.
+pub fn initlog(dev: usize, start: u32, size: u32) {
+    {
+        let mut log = LOG.lock();
+        log.dev = dev;
+        log.start = start;
+        log.size = size;
+    }
+    recover_from_log();
+}
+
+// copy committed blocks from log to their home location.
+fn install_trans(dev: usize, start: u32, lh: &LogHeader, recovering: bool) {
+    for tail in 0..lh.n as usize {
+        let lbuf = bread(dev, start + tail as u32 + 1); // read log block
+        let mut dbuf = bread(dev, lh.block[tail]); // read dst
+        dbuf.data.copy_from_slice(&lbuf.data); // copy block to dst
+        bwrite(&mut dbuf); // write dst to disk
+        if !recovering {
+            bunpin(&dbuf);
+        }
+    }
+}
+
+// read the log header from disk into the in-memory log header.
+fn read_head(dev: usize, start: u32) -> LogHeader {
+    let buf = bread(dev, start);
+    let mut lh = unsafe { read_unaligned(buf.data.as_ptr() as *const LogHeader) };
+    lh.n = lh.n.min(LOGSIZE as u32); // don't trust a torn header
+    lh
+}
+
+// write in-memory log header to disk.
+// this is the true point at which the
+// current transaction commits.
+fn write_head(dev: usize, start: u32, lh: &LogHeader) {
+    let mut buf = bread(dev, start);
+    unsafe { write_unaligned(buf.data.as_mut_ptr() as *mut LogHeader, *lh) };
+    bwrite(&mut buf);
+}
+
+fn recover_from_log() {
+    let (dev, start) = {
+        let log = LOG.lock();
+        (log.dev, log.start)
+    };
+    let lh = read_head(dev, start);
+    install_trans(dev, start, &lh, true); // if committed, copy from log to disk
+    let empty = LogHeader {
+        n: 0,
+        block: [0; LOGSIZE],
+    };
+    LOG.lock().lh = empty;
+    write_head(dev, start, &empty); // clear the log
+}
+
+// called at the start of each fs system call.
+pub fn begin_op() {
+    let mut log = LOG.lock();
+    loop {
+        if log.committing {
+            log = sleep(log_chan(), log);
+        } else if log.lh.n as usize + (log.outstanding + 1) * MAXOPBLOCKS > LOGSIZE {
+            // this op might exhaust log space; wait for commit.
+            log = sleep(log_chan(), log);
+        } else {
+            log.outstanding += 1;
+            break;
+        }
+    }
+}
+
+// called at the end of each fs system call.
+// commits if this was the last outstanding operation.
+pub fn end_op() {
+    let do_commit = {
+        let mut log = LOG.lock();
+        log.outstanding -= 1;
+        if log.committing {
+            panic!("log.committing");
+        }
+        if log.outstanding == 0 {
+            log.committing = true;
+            true
+        } else {
+            // begin_op() may be waiting for log space,
+            // and decrementing log.outstanding has decreased
+            // the amount of reserved space.
+            wakeup(log_chan());
+            false
+        }
+    };
+
+    if do_commit {
+        // call commit w/o holding locks, since not allowed
+        // to sleep with locks.
+        commit();
+        let mut log = LOG.lock();
+        log.committing = false;
+        wakeup(log_chan());
+    }
+}
+
+// copy modified blocks from cache to log.
+fn write_log(dev: usize, start: u32, lh: &LogHeader) {
+    for tail in 0..lh.n as usize {
+        let mut to = bread(dev, start + tail as u32 + 1); // log block
+        let from = bread(dev, lh.block[tail]); // cache block
+        to.data.copy_from_slice(&from.data);
+        bwrite(&mut to); // write the log
+    }
+}
+
+fn commit() {
+    // nobody else touches the header while committing is set.
+    let (dev, start, mut lh) = {
+        let log = LOG.lock();
+        (log.dev, log.start, log.lh)
+    };
+    if lh.n > 0 {
+        write_log(dev, start, &lh); // write modified blocks from cache to log
+        write_head(dev, start, &lh); // write header to disk -- the real commit
+        install_trans(dev, start, &lh, false); // now install writes to home locations
+        lh.n = 0;
+        LOG.lock().lh.n = 0;
+        write_head(dev, start, &lh); // erase the transaction from the log
+    }
+}
+
+// caller has modified b.data and is done with the buffer.
+// record the block number and pin in the cache by increasing refcnt.
+// commit()/write_log() will do the disk write.
+//
+// log_write() replaces bwrite(); a typical use is:
+//   let mut bp = bread(...);
+//   modify bp.data[]
+//   log_write(&bp);
+//   brelse(bp);
+pub fn log_write(b: &Buf) {
+    let mut log = LOG.lock();
+    let n = log.lh.n as usize;
+    if n >= LOGSIZE || n as u32 + 1 >= log.size {
+        panic!("too big a transaction");
+    }
+    if log.outstanding < 1 {
+        panic!("log_write outside of trans");
+    }
+
+    // log absorption: a block written twice in a transaction takes
+    // one slot.
+    if !log.lh.block[..n].contains(&b.blockno) {
+        log.lh.block[n] = b.blockno;
+        bpin(b);
+        log.lh.n += 1;
+    }
+}
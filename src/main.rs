@@ -3,28 +3,49 @@
 #![feature(alloc_error_handler)]
 #![allow(dead_code, non_upper_case_globals)]
 
+mod aplic;
+mod bio;
+mod console;
+mod errno;
+mod ext2;
+mod fat;
+mod fcntl;
+mod fdt;
+mod file;
+mod fs;
+mod imsic;
+mod irq;
+mod klog;
+mod log;
 mod plic;
+mod sleep_lock;
+mod stat;
 mod spin_lock;
 mod trap;
 mod mem_utils;
 mod memolayout;
+mod ns16550;
 mod params;
 mod pci;
+mod pipe;
 mod proc;
 mod riscv;
 mod start;
 mod syscall;
+mod sysfile;
+mod sysproc;
+mod tmpfs;
 mod virtio;
 mod vm;
 mod uart;
 mod utils;
+mod vfs;
 mod virtio_gpu;
 
 use core::{arch::global_asm, panic::PanicInfo};
 use linked_list_allocator::LockedHeap;
 use plic::plicinithart;
 use riscv::intr_on;
-use virtio::virtio_blk::virtio_disk_rw;
 
 use crate::plic::plicinit;
 
@@ -47,38 +68,39 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 #[no_mangle]
 pub extern "C" fn main() -> ! {
     let heap_start = crate::memolayout::get_kernel_end();
-    let heap_end = crate::memolayout::PHYSTOP;
+    let mut heap_end = crate::memolayout::PHYSTOP;
+    // qemu puts the device tree near the top of ram; keep it out of the heap.
+    let dtb = fdt::dtb_addr();
+    if dtb > heap_start && dtb < heap_end {
+        heap_end = dtb & !(crate::riscv::PGSIZE - 1);
+    }
     let heap_size = heap_end - heap_start;
     unsafe {
         ALLOCATOR.lock().init(heap_start, heap_size);
     }
-    virtio::init_virtio_blk_device(memolayout::VIRTIO0 as *const u8);
-    uart::console_init();
     plicinit();
     plicinithart();
-    // pci::test_write_bar();
-    pci::test_bar();
-    
-    // pci::list_pci(memolayout::PCI_BASE);
-    // unsafe {
-    //     pci::write_vga(memolayout::PCI_BASE + 1 * 8 * (1 << 12));
-    // }
-    
-    intr_on();
+    imsic::imsic_init();
+    imsic::imsic_inithart();
+    aplic::aplic_init();
+    virtio::probe_mmio_devices();
+    console::console_init();
+    pci::pci_enumerate();
+    pci::pci_probe_drivers();
     vm::kvminit();
     vm::kvminithart();
     proc::procinit();
     trap::trapinithart();
+    // only now is there a kernel trap vector to take the uart's
+    // interrupts.
+    intr_on();
     proc::userinit();
-    virtio_disk_rw([0x75; 1024], true);
-    //pci::list_pci(memolayout::PCI_BASE+1*8*(1<<12));
-    loop {}
     proc::scheduler();
 }
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    println!("{}", _info);
+    uart::_print_panic(format_args!("{}\n", _info));
     loop {}
 }
 
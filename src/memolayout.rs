@@ -16,15 +16,25 @@ pub const PLIC: usize = 0x0c000000;
 
 pub const PCI_BASE: usize = 0x3000_0000;
 pub const PCI_BUS_WIDTH: usize = 8;
+// where qemu's host bridge forwards pci io and 32-bit memory accesses,
+// used when the device tree doesn't say.
+pub const PCI_IO_BASE: usize = 0x0300_0000;
+pub const PCI_IO_SIZE: usize = 0x1_0000;
+pub const PCI_MMIO_BASE: usize = 0x4000_0000;
+pub const PCI_MMIO_SIZE: usize = 0x4000_0000;
+// INTA..INTD of the slots on bus 0 are rotated onto these four plic irqs.
+pub const PCI_INTX_IRQ: usize = 32;
+pub const PCI_INTX_NUM: usize = 4;
 
-pub const VGA_FRAME_BUFFER: usize = 0x7000_0000;
-pub const VGA_FRAME_BUFFER_SIZE: usize = 16 * 1024 * 1024;
-pub const VGA_MMIO_BASE: usize = VGA_FRAME_BUFFER + VGA_FRAME_BUFFER_SIZE;
 pub const TRAMPOLINE: usize = MAXVA as usize - PGSIZE;
 pub const TRAPFRAME: usize = TRAMPOLINE - PGSIZE;
 // virtio mmio interface
+// qemu's virt machine has eight virtio-mmio transports, one page apart,
+// wired to consecutive irqs. empty slots report device id 0.
 pub const VIRTIO0: usize = 0x10001000;
 pub const VIRTIO0_IRQ: usize = 1;
+pub const VIRTIO_MMIO_NUM: usize = 8;
+pub const VIRTIO_MMIO_STRIDE: usize = 0x1000;
 pub const  UART_IRQ: usize = 10;
 extern "C" {
     static end: u8;
@@ -74,6 +84,16 @@ macro_rules! KSTACK {
     };
 }
 
+#[inline]
+pub fn virtio_mmio(slot: usize) -> usize {
+    VIRTIO0 + slot * VIRTIO_MMIO_STRIDE
+}
+
+#[inline]
+pub fn virtio_mmio_irq(slot: usize) -> usize {
+    VIRTIO0_IRQ + slot
+}
+
 #[inline]
 pub fn plic_priority() -> usize {
     PLIC + 0x0
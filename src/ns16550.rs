@@ -0,0 +1,401 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::fdt;
+
+// a driver for the national semiconductor 16550a and the many uarts
+// that copy it, like the one qemu's virt machine has.
+// http://byterunner.com/16550.html
+
+// registers, numbered as in the datasheet. on some hardware they are
+// spaced out, and only word accesses work: the device tree's
+// "reg-shift" and "reg-io-width".
+const RHR: usize = 0; // receive holding register (for input bytes)
+const THR: usize = 0; // transmit holding register (for output bytes)
+const DLL: usize = 0; // divisor latch, low byte (with LCR_BAUD_LATCH)
+const IER: usize = 1; // interrupt enable register
+const DLM: usize = 1; // divisor latch, high byte (with LCR_BAUD_LATCH)
+const FCR: usize = 2; // FIFO control register
+const ISR: usize = 2; // interrupt status register
+const LCR: usize = 3; // line control register
+const MCR: usize = 4; // modem control register
+const LSR: usize = 5; // line status register
+const MSR: usize = 6; // modem status register
+const SCR: usize = 7; // scratch register
+
+pub const IER_RX_ENABLE: u8 = 1 << 0;
+pub const IER_TX_ENABLE: u8 = 1 << 1;
+pub const IER_LINE_STATUS: u8 = 1 << 2;
+pub const IER_MODEM_STATUS: u8 = 1 << 3;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_FIFO_CLEAR: u8 = 3 << 1; // clear the content of the two FIFOs
+const FCR_TRIGGER_8: u8 = 2 << 6; // receive interrupt after 8 bytes
+
+const ISR_NO_INTERRUPT: u8 = 1 << 0;
+const ISR_ID_MASK: u8 = 0x0e;
+const ISR_LINE_STATUS: u8 = 0x06;
+const ISR_RX_READY: u8 = 0x04;
+const ISR_RX_TIMEOUT: u8 = 0x0c;
+const ISR_TX_EMPTY: u8 = 0x02;
+const ISR_MODEM_STATUS: u8 = 0x00;
+
+const LCR_STOP_BITS_2: u8 = 1 << 2;
+const LCR_PARITY_ENABLE: u8 = 1 << 3;
+const LCR_PARITY_EVEN: u8 = 1 << 4;
+const LCR_BAUD_LATCH: u8 = 1 << 7; // special mode to set baud rate
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT2: u8 = 1 << 3; // gates the interrupt line on pc-style boards
+
+pub const LSR_RX_READY: u8 = 1 << 0; // input is waiting to be read from RHR
+pub const LSR_OVERRUN: u8 = 1 << 1;
+pub const LSR_PARITY: u8 = 1 << 2;
+pub const LSR_FRAMING: u8 = 1 << 3;
+pub const LSR_BREAK: u8 = 1 << 4;
+pub const LSR_TX_IDLE: u8 = 1 << 5; // THR can accept another character to send
+
+pub const MSR_DELTA_CTS: u8 = 1 << 0;
+pub const MSR_DELTA_DSR: u8 = 1 << 1;
+pub const MSR_TRAILING_RI: u8 = 1 << 2;
+pub const MSR_DELTA_DCD: u8 = 1 << 3;
+pub const MSR_CTS: u8 = 1 << 4;
+pub const MSR_DSR: u8 = 1 << 5;
+pub const MSR_RI: u8 = 1 << 6;
+pub const MSR_DCD: u8 = 1 << 7;
+
+// the input clock of the classic pc uart, if the device tree doesn't say.
+pub const DEFAULT_CLOCK: u32 = 1_843_200;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: u8, // 5..=8
+    pub parity: Parity,
+    pub stop_bits: u8, // 1 or 2
+}
+
+impl SerialConfig {
+    // 115200 8n1
+    pub const fn default() -> Self {
+        SerialConfig {
+            baud: 115200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+
+    fn lcr(&self) -> u8 {
+        let mut lcr = self.data_bits.clamp(5, 8) - 5;
+        if self.stop_bits == 2 {
+            lcr |= LCR_STOP_BITS_2;
+        }
+        match self.parity {
+            Parity::None => {}
+            Parity::Odd => lcr |= LCR_PARITY_ENABLE,
+            Parity::Even => lcr |= LCR_PARITY_ENABLE | LCR_PARITY_EVEN,
+        }
+        lcr
+    }
+}
+
+// what next_event() found, for the caller to act on.
+pub enum UartEvent {
+    // input is waiting; read it with getc().
+    RxReady,
+    // THR is empty; more output can go.
+    TxEmpty,
+    // LSR error bits of a received character.
+    LineStatus(u8),
+    // MSR, with the delta bits saying what changed.
+    ModemStatus(u8),
+}
+
+// receive errors seen since boot.
+pub struct UartErrors {
+    pub overrun: AtomicUsize,
+    pub parity: AtomicUsize,
+    pub framing: AtomicUsize,
+    pub breaks: AtomicUsize,
+}
+
+impl UartErrors {
+    pub const fn new() -> Self {
+        UartErrors {
+            overrun: AtomicUsize::new(0),
+            parity: AtomicUsize::new(0),
+            framing: AtomicUsize::new(0),
+            breaks: AtomicUsize::new(0),
+        }
+    }
+
+    fn count(&self, lsr: u8) {
+        let counters = [
+            (LSR_OVERRUN, &self.overrun),
+            (LSR_PARITY, &self.parity),
+            (LSR_FRAMING, &self.framing),
+            (LSR_BREAK, &self.breaks),
+        ];
+        for (bit, counter) in counters {
+            if lsr & bit != 0 {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// one uart. pci io ports are memory mapped on riscv, so they are
+// reached the same way with a reg_shift of 0.
+pub struct Ns16550 {
+    base: usize,
+    reg_shift: AtomicU32,
+    reg_io_width: AtomicU32, // bytes per access, 1 or 4
+    pub errors: UartErrors,
+}
+
+impl Ns16550 {
+    pub const fn new(base: usize, reg_shift: u32) -> Self {
+        Ns16550 {
+            base,
+            reg_shift: AtomicU32::new(reg_shift),
+            reg_io_width: AtomicU32::new(1),
+            errors: UartErrors::new(),
+        }
+    }
+
+    // how the registers are laid out, as dtb_uart_regs() finds it.
+    // call before init().
+    pub fn set_layout(&self, reg_shift: u32, reg_io_width: u32) {
+        self.reg_shift.store(reg_shift, Ordering::Relaxed);
+        self.reg_io_width.store(reg_io_width, Ordering::Relaxed);
+    }
+
+    fn reg_addr(&self, reg: usize) -> usize {
+        self.base + (reg << self.reg_shift.load(Ordering::Relaxed))
+    }
+
+    fn wide(&self) -> bool {
+        self.reg_io_width.load(Ordering::Relaxed) == 4
+    }
+
+    pub fn read(&self, reg: usize) -> u8 {
+        let addr = self.reg_addr(reg);
+        if self.wide() {
+            unsafe { read_volatile(addr as *const u32) as u8 }
+        } else {
+            unsafe { read_volatile(addr as *const u8) }
+        }
+    }
+
+    pub fn write(&self, reg: usize, val: u8) {
+        let addr = self.reg_addr(reg);
+        if self.wide() {
+            unsafe { write_volatile(addr as *mut u32, val as u32) }
+        } else {
+            unsafe { write_volatile(addr as *mut u8, val) }
+        }
+    }
+
+    // is there a 16550 here? the scratch register has to hold a value.
+    pub fn probe(&self) -> bool {
+        let old = self.read(SCR);
+        self.write(SCR, 0x5a);
+        let present = self.read(SCR) == 0x5a;
+        self.write(SCR, old);
+        present
+    }
+
+    // program baud rate, framing and FIFOs for an input clock of clock
+    // Hz. interrupts stay off.
+    pub fn init(&self, clock: u32, config: &SerialConfig) {
+        // disable interrupts.
+        self.write(IER, 0x00);
+
+        // special mode to set baud rate.
+        let divisor = (clock / (16 * config.baud.max(1))).clamp(1, 0xffff);
+        self.write(LCR, LCR_BAUD_LATCH);
+        self.write(DLL, divisor as u8);
+        self.write(DLM, (divisor >> 8) as u8);
+
+        // leave set-baud mode, and set word length, parity and stop bits.
+        self.write(LCR, config.lcr());
+
+        // reset and enable FIFOs.
+        self.write(FCR, FCR_FIFO_ENABLE | FCR_FIFO_CLEAR | FCR_TRIGGER_8);
+
+        // raise DTR and RTS, and let the interrupt through.
+        self.write(MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+
+        // clear stale status.
+        self.read(LSR);
+        self.read(MSR);
+        self.read(RHR);
+    }
+
+    pub fn enable_interrupts(&self, ier: u8) {
+        self.write(IER, ier);
+    }
+
+    pub fn tx_idle(&self) -> bool {
+        self.read(LSR) & LSR_TX_IDLE != 0
+    }
+
+    // write a character once THR has room. spins.
+    pub fn putc_sync(&self, c: u8) {
+        while !self.tx_idle() {}
+        self.write(THR, c);
+    }
+
+    // write a character; THR must have room.
+    pub fn putc(&self, c: u8) {
+        self.write(THR, c);
+    }
+
+    // read one input character, if any. characters that arrived with
+    // an error are counted and dropped.
+    pub fn getc(&self) -> Option<u8> {
+        loop {
+            let lsr = self.read(LSR);
+            if lsr & LSR_RX_READY == 0 {
+                return None;
+            }
+            let c = self.read(RHR);
+            if lsr & (LSR_PARITY | LSR_FRAMING | LSR_BREAK | LSR_OVERRUN) == 0 {
+                return Some(c);
+            }
+            self.errors.count(lsr);
+            if lsr & (LSR_PARITY | LSR_FRAMING | LSR_BREAK) == 0 {
+                return Some(c); // overrun lost earlier bytes, not this one
+            }
+        }
+    }
+
+    pub fn modem_status(&self) -> u8 {
+        self.read(MSR)
+    }
+
+    // reading ISR acknowledges a THR-empty interrupt.
+    pub fn ack_tx(&self) {
+        self.read(ISR);
+    }
+
+    // the next reason the uart is interrupting, None once it has stopped.
+    // line and modem status are acknowledged by reading them here.
+    pub fn next_event(&self) -> Option<UartEvent> {
+        let isr = self.read(ISR);
+        if isr & ISR_NO_INTERRUPT != 0 {
+            return None;
+        }
+        match isr & ISR_ID_MASK {
+            ISR_LINE_STATUS => {
+                let lsr = self.read(LSR);
+                self.errors.count(lsr);
+                if lsr & LSR_RX_READY != 0 {
+                    // drop the character the error came with.
+                    self.read(RHR);
+                }
+                Some(UartEvent::LineStatus(lsr))
+            }
+            ISR_RX_READY | ISR_RX_TIMEOUT => Some(UartEvent::RxReady),
+            ISR_TX_EMPTY => Some(UartEvent::TxEmpty),
+            ISR_MODEM_STATUS => Some(UartEvent::ModemStatus(self.read(MSR))),
+            _ => None,
+        }
+    }
+}
+
+// the device tree node of the ns16550a at base.
+fn dtb_uart_node(fdt: &fdt::Fdt, base: usize) -> Option<fdt::FdtNode<'_>> {
+    let address_cells = fdt.root().property_u32("#address-cells").unwrap_or(2) as usize;
+    let size_cells = fdt.root().property_u32("#size-cells").unwrap_or(2) as usize;
+    fdt.find_all_compatible("ns16550a")
+        .find(|n| n.reg(address_cells, size_cells).map(|r| r.0 as usize) == Some(base))
+}
+
+// the register layout of the ns16550a at base, from the device tree:
+// "reg-shift" and "reg-io-width". widths other than 1 and 4 aren't
+// supported, and fall back to 1.
+pub fn dtb_uart_regs(base: usize) -> (u32, u32) {
+    let fdt = match fdt::fdt() {
+        Some(fdt) => fdt,
+        None => return (0, 1),
+    };
+    let node = match dtb_uart_node(&fdt, base) {
+        Some(node) => node,
+        None => return (0, 1),
+    };
+    let reg_shift = node.property_u32("reg-shift").unwrap_or(0);
+    let reg_io_width = match node.property_u32("reg-io-width") {
+        Some(4) => 4,
+        _ => 1,
+    };
+    (reg_shift, reg_io_width)
+}
+
+// the input clock and line settings of the ns16550a at base, from the
+// device tree: "clock-frequency", "current-speed", and options such as
+// "115200n8" after the path in /chosen's "stdout-path" if this is the
+// console.
+pub fn dtb_uart_config(base: usize) -> (u32, SerialConfig) {
+    let mut config = SerialConfig::default();
+    let fdt = match fdt::fdt() {
+        Some(fdt) => fdt,
+        None => return (DEFAULT_CLOCK, config),
+    };
+    let node = match dtb_uart_node(&fdt, base) {
+        Some(node) => node,
+        None => return (DEFAULT_CLOCK, config),
+    };
+    if let Some(speed) = node.property_u32("current-speed") {
+        config.baud = speed;
+    }
+    if let Some((path, options)) = stdout_path(&fdt) {
+        let stdout = fdt.find_node(path);
+        if stdout == Some(node) {
+            parse_options(options, &mut config);
+        }
+    }
+    let clock = node
+        .property_u32("clock-frequency")
+        .unwrap_or(DEFAULT_CLOCK);
+    (clock, config)
+}
+
+// /chosen's stdout-path split into the node and its options, with an
+// alias resolved.
+fn stdout_path(fdt: &fdt::Fdt) -> Option<(&str, &str)> {
+    let value = fdt.find_node("/chosen")?.property_str("stdout-path")?;
+    let (path, options) = value.split_once(':').unwrap_or((value, ""));
+    if path.starts_with('/') {
+        return Some((path, options));
+    }
+    let path = fdt.find_node("/aliases")?.property_str(path)?;
+    Some((path, options))
+}
+
+// <baud>{<parity>{<bits>}}, as in linux's console= option.
+fn parse_options(options: &str, config: &mut SerialConfig) {
+    let digits = options.bytes().take_while(u8::is_ascii_digit).count();
+    if let Ok(baud) = options[..digits].parse() {
+        config.baud = baud;
+    }
+    let mut rest = options[digits..].bytes();
+    match rest.next() {
+        Some(b'o') => config.parity = Parity::Odd,
+        Some(b'e') => config.parity = Parity::Even,
+        Some(b'n') => config.parity = Parity::None,
+        _ => return,
+    }
+    if let Some(bits @ b'5'..=b'8') = rest.next() {
+        config.data_bits = bits - b'0';
+    }
+}
@@ -1,15 +1,28 @@
-use core::{cell::UnsafeCell, hint::black_box};
+use core::hint::black_box;
 
-use msix::{set_all_msix_interrupt_handler, MSIXCapability};
-use virtio::{virtio_pci_device_reset, VirtioPciCommonCfg};
+use msix::{mask_all_msix_entries, MSIXCapability};
+use virtio::{virtio_pci_transport, VirtioPciDevice};
+
+pub use bar::{map_bar, pci_mapped_windows, probe_bar};
+pub use device::{pci_devices, pci_enumerate};
+pub use driver::pci_probe_drivers;
+pub use intx::{free_intx, request_intx};
+pub use msix::{free_msix, request_msix};
 
 use crate::{
-    memolayout::{PCI_BASE, VGA_FRAME_BUFFER, VGA_FRAME_BUFFER_SIZE, VGA_MMIO_BASE},
-    println,
+    debug, println, warn,
+    spin_lock::SpinMutex,
+    virtio::{Transport, VIRTIO_F_VERSION_1},
 };
 
+mod bar;
+mod device;
+mod driver;
+mod intx;
 mod msix;
+mod serial;
 mod virtio;
+mod virtio_sound;
 
 pub const VENDOR_SPECIFIC: u8 = 0x09;
 pub const MIS_X: u8 = 0x11;
@@ -45,6 +58,34 @@ pub struct PCIConfigurationSpcaeHeaderType0 {
     pub max_lat: u8,
 }
 
+// the pci-to-pci bridge header, after the common part.
+#[repr(C)]
+pub struct PCIConfigurationSpcaeHeaderType1 {
+    pub __padding: [u8; 16],
+    pub base_address_registers: [u8; 8],
+    pub primary_bus_number: u8,
+    pub secondary_bus_number: u8,
+    pub subordinate_bus_number: u8,
+    pub secondary_latency_timer: u8,
+    pub io_base: u8,
+    pub io_limit: u8,
+    pub secondary_status: u16,
+    pub memory_base: u16,
+    pub memory_limit: u16,
+    pub prefetchable_memory_base: u16,
+    pub prefetchable_memory_limit: u16,
+    pub prefetchable_base_upper: u32,
+    pub prefetchable_limit_upper: u32,
+    pub io_base_upper: u16,
+    pub io_limit_upper: u16,
+    pub capabilities_pointer: u8,
+    pub reserved: [u8; 3],
+    pub expansion_rom_base_address: u32,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bridge_control: u16,
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct VirtioPciCap {
@@ -66,42 +107,23 @@ pub struct CapHeader {
     pub cap_next: u8,
 }
 
-pub fn list_pci(pci_base_addr: usize) {
-    for dev_num in 0..(1 << 5) {
-        for func_num in 0..(1 << 3) {
-            //we list device in bus 0 only
-            let config_space_addr = pci_base_addr + (dev_num << (12 + 3)) + (func_num << 12);
-            let config_space_header =
-                unsafe { &*(config_space_addr as *const PCIConfigurationSpcaeHeader) };
-            if config_space_header.vendor_id == 0xffff {
-                //ignore device with vendor id 0xffff
-                continue;
-            }
-            println!(
-                "bus:dev:func {bus_num}:{dev_num}:{func_num} vendor_id:device_id {:04x}:{:04x}",
-                config_space_header.vendor_id,
-                config_space_header.device_id,
-                bus_num = 0,
-            );
-
-            println!(
-                "header_type {}, status: {}",
-                config_space_header.header_type, config_space_header.status
-            );
-            if config_space_header.header_type == 0 {
-                let type0_header =
-                    unsafe { &*(config_space_addr as *const PCIConfigurationSpcaeHeaderType0) };
-                println!(
-                    "capabilities pointer {:x}",
-                    type0_header.capabilities_pointer
-                );
-                disp_cap_list(
-                    config_space_addr,
-                    type0_header.capabilities_pointer as usize,
-                );
+pub fn list_pci() {
+    for dev in pci_devices() {
+        println!(
+            "bus:dev:func {} vendor_id:device_id {:04x}:{:04x} class {:06x}",
+            dev.bdf, dev.vendor, dev.device, dev.class
+        );
+        println!("header_type {}", dev.header_type);
+        for (i, bar) in dev.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                println!("bar {}: {:?}", i, bar);
             }
-            println!("----------------");
         }
+        if let Some(first_cap) = dev.caps.first() {
+            println!("capabilities pointer {:x}", first_cap.offset);
+            disp_cap_list(dev.config_addr(), first_cap.offset as usize);
+        }
+        println!("----------------");
     }
 }
 
@@ -120,120 +142,24 @@ pub fn disp_cap_list(config_addr: usize, cap_pointer: usize) {
     }
 }
 
-pub fn find_device(pci_base_addr: usize, vendor_id: u16, device_id: u16) -> Option<usize> {
-    // assume all pci device is in bus 0
-    for dev_num in 0..(1 << 5) {
-        for func_num in 0..(1 << 3) {
-            let config_space_addr = pci_base_addr + (dev_num << (12 + 3)) + (func_num << 12);
-            let config_space_header =
-                unsafe { &*(config_space_addr as *const PCIConfigurationSpcaeHeader) };
-            if config_space_header.vendor_id == vendor_id
-                && config_space_header.device_id == device_id
-            {
-                return Some(config_space_addr);
-            }
-        }
-    }
-    None
-}
-
-pub unsafe fn write_vga(addr: usize) {
-    let csh: &mut PCIConfigurationSpcaeHeader = &mut *(addr as *mut PCIConfigurationSpcaeHeader);
-    csh.command = csh.command | 0b10;
-    println!("Command: {}", csh.command);
+pub unsafe fn write_vga(config_addr: usize) {
+    let csh: &mut PCIConfigurationSpcaeHeader =
+        &mut *(config_addr as *mut PCIConfigurationSpcaeHeader);
     if csh.header_type != 0 {
         return;
     }
-    let type0_header: &PCIConfigurationSpcaeHeaderType0 =
-        &*((&csh.remain_part as *const u8) as u64 as *const PCIConfigurationSpcaeHeaderType0);
-    let bar_base_addr: UnsafeCell<u32> = (type0_header.base_address_registers[0] as u32).into();
-    let bar_0: *mut u32 = bar_base_addr.get();
-    let bar_2: *mut u32 = bar_base_addr.get().wrapping_add(2);
-    // let bar_6: *mut u32 = bar_base_addr.get().wrapping_add(5);//why 5? not 6?
-    let bar_0_val = VGA_FRAME_BUFFER;
-    *bar_0 = bar_0_val as u32;
-    *bar_2 = VGA_MMIO_BASE as u32;
+    let framebuffer_addr = map_bar(config_addr, 0);
+    let framebuffer_size = get_bar_region_size(config_addr, 0);
+    let vga_mmio_addr = map_bar(config_addr, 2);
+    println!("Command: {}", csh.command);
 
-    println!("{:x}", *bar_0);
-    println!("{:x}", bar_0_val);
-    let framebuffer: &mut [u8; VGA_FRAME_BUFFER_SIZE] =
-        &mut *(bar_0_val as *mut [u8; VGA_FRAME_BUFFER_SIZE]);
-    let vga_mmio: &mut [u8; 4096] = &mut *(VGA_MMIO_BASE as *mut [u8; 4096]);
+    println!("{:x}", framebuffer_addr);
+    let framebuffer = core::slice::from_raw_parts_mut(framebuffer_addr as *mut u8, framebuffer_size);
+    let vga_mmio: &mut [u8; 4096] = &mut *(vga_mmio_addr as *mut [u8; 4096]);
     framebuffer.fill(0xff);
     vga_mmio[0] = 0x0c;
 }
 
-pub fn test_write_bar() {
-    let config_addr = find_device(PCI_BASE, 0x1af4, 0x1050).expect("can't find pci device");
-    let header = unsafe { &*(config_addr as *mut PCIConfigurationSpcaeHeaderType0) };
-    let header_t = unsafe { &mut *(config_addr as *mut PCIConfigurationSpcaeHeader) };
-    let capability_struct_addr: usize = header.capabilities_pointer as usize + config_addr;
-
-    println!("cap_id: {:?}", unsafe {
-        *(capability_struct_addr as *const u8)
-    });
-    println!("next_cap: {:?}", unsafe {
-        *((capability_struct_addr + 1) as *const u8)
-    });
-    println!("msi_mc: {:?}", unsafe {
-        *((capability_struct_addr + 2) as *const u8)
-    });
-    println!("msi_mc: {:?}", unsafe {
-        *((capability_struct_addr + 3) as *const u8)
-    });
-    unsafe {
-        let mut msix_enable = *((capability_struct_addr + 2) as *const u16);
-        msix_enable |= 1 << 15;
-        *((capability_struct_addr + 2) as *mut u16) = msix_enable;
-    };
-    header_t.command = 6;
-    println!("cap_id: {:?}", unsafe {
-        *(capability_struct_addr as *const u8)
-    });
-    println!("next_cap: {:?}", unsafe {
-        *((capability_struct_addr + 1) as *const u8)
-    });
-    println!("msi_mc: {:?}", unsafe {
-        *((capability_struct_addr + 2) as *const u8)
-    });
-    println!("msi_mc: {:?}", unsafe {
-        *((capability_struct_addr + 3) as *const u8)
-    });
-
-    // header.base_address_registers
-}
-
-pub fn test_bar() {
-    // let config_addr = find_device(PCI_BASE, 0x1af4, 0x1050).expect("can't find pci device");
-    // println!("found Virtio GPU: {:#x}", config_addr);
-    let config_addr_sound =
-        find_device(PCI_BASE, 0x1af4, 0x1040 + 25).expect("can't find pci device");
-    println!("found Virtio SOUND: {:#x}", config_addr_sound);
-    // let config_addr2 = find_device(PCI_BASE, 0x1234, 0x11e8).expect("can't find edu pci device");
-    // println!("found PCI EDU device: {:#x}", config_addr2);
-    let header = unsafe { &mut *(config_addr_sound as *mut PCIConfigurationSpcaeHeaderType0) };
-    let header_sound =
-        unsafe { &mut *(config_addr_sound as *mut PCIConfigurationSpcaeHeaderType0) };
-    // traverse_cap_list(config_addr, header.capabilities_pointer as usize);
-
-    // traverse_cap_list(config_addr_sound, header_sound.capabilities_pointer as usize);
-
-    let cap_pointer_sound = header_sound.capabilities_pointer as usize;
-    enable_device(config_addr_sound as usize);
-    enable_msix(config_addr_sound, cap_pointer_sound);
-
-    start_virtio_sound_config(config_addr_sound);
-
-    // println!("command register: {:x}", header_t.command);
-    // header_t.command = header_t.command | 0b100;//enable mastering enable bit
-    // println!("command register: {:x}", header_t.command);
-
-    let bar_base_addr = black_box(&(header.base_address_registers[0]) as *const u8 as usize);
-    traverse_cap_list(config_addr_sound, cap_pointer_sound);
-    let bar0 = bar_base_addr as *mut u32;
-    let bar2 = bar0.wrapping_add(2);
-}
-
 struct StatusRegister(u16);
 
 impl StatusRegister {
@@ -297,49 +223,19 @@ pub fn enable_msix(config_addr: usize, cap_pointer: usize) {
     }
 }
 
-fn enable_msix_inner(config_addr: usize, msix_cap_pointer: usize) {
+pub fn disable_msix(config_addr: usize, msix_cap_pointer: usize) {
     let msix_cap = unsafe { &mut *((config_addr + msix_cap_pointer) as *mut MSIXCapability) };
-    let table_bar = msix_cap.get_table_bir();
-    let pba_bar = msix_cap.get_pba_bir();
-    println!("msix table bar: {:?}", table_bar);
-    println!("msix pba bar: {:?}", pba_bar);
-
-    let table_offset = msix_cap.get_table_offset();
-    let pba_offset = msix_cap.get_pba_offset();
-    println!("table offset: {:?}", table_offset);
-    println!("pba offset: {:?}", pba_offset);
-
-    println!(
-        "table bar content{}: ",
-        get_bar_value(config_addr, table_bar as usize)
-    );
-    println!(
-        "pba bar content{}: ",
-        get_bar_value(config_addr, pba_bar as usize)
-    );
-
-    let bar_content = 0x4001_0000;
-    let table_addr = bar_content + table_offset;
-    let pba_addr = bar_content + pba_offset;
-
-    let table_size = msix_cap.table_size();
-
-    if table_bar == pba_bar {
-        set_bar_value(config_addr, table_bar as usize, bar_content);
-    } else {
-        panic!("not implemented");
-    }
-    println!(
-        "table bar content: {:#x}: ",
-        get_bar_value(config_addr, table_bar as usize)
-    );
-    println!(
-        "pba bar content: {:#x}: ",
-        get_bar_value(config_addr, pba_bar as usize)
-    );
-    set_all_msix_interrupt_handler(table_addr as usize, table_size as usize);
+    msix_cap.set_enable(false);
+}
 
+fn enable_msix_inner(config_addr: usize, msix_cap_pointer: usize) {
+    let msix_cap = unsafe { &mut *((config_addr + msix_cap_pointer) as *mut MSIXCapability) };
+    // mask everything before turning msi-x on; drivers unmask the
+    // entries they route with request_msix().
+    msix_cap.set_function_mask(true);
     msix_cap.set_enable(true);
+    mask_all_msix_entries(config_addr, msix_cap);
+    msix_cap.set_function_mask(false);
 }
 fn get_bar_value(config_addr: usize, bar: usize) -> u32 {
     let header = unsafe { &mut *(config_addr as *mut PCIConfigurationSpcaeHeaderType0) };
@@ -355,63 +251,49 @@ fn set_bar_value(config_addr: usize, bar: usize, value: u32) {
     unsafe { *bar0.wrapping_add(bar) = value };
 }
 
-pub fn start_virtio_sound_config(config_addr: usize) {
-    let header = unsafe { &mut *(config_addr as *mut PCIConfigurationSpcaeHeaderType0) };
-    let header_t = unsafe { &mut *(config_addr as *mut PCIConfigurationSpcaeHeader) };
-    let bar_base_addr = black_box(&(header.base_address_registers[0]) as *const u8 as usize);
-    let mut next_cap_pointer = header.capabilities_pointer as usize;
-    let mut bar: Option<usize> = None;
-    let mut offset: Option<usize> = None;
-    loop {
-        let cap = unsafe { &*((config_addr + next_cap_pointer) as *const VirtioPciCap) };
-
-        if cap.cap_vndr == VENDOR_SPECIFIC && cap.cfg_type == 0x01 {
-            bar = Some(cap.bar as usize);
-            offset = Some(cap.offset as usize);
-            break;
-        }
+pub static VIRTIO_SOUND: SpinMutex<Option<VirtioPciDevice>> = SpinMutex::new(None);
+const VIRTIO_SOUND_QUEUE_SIZE: u16 = 64;
 
-        if cap.cap_next == 0 {
-            println!("did not find common cfg struct");
+// vector is the msi-x table entry for configuration changes and every
+// queue, or VIRTIO_MSI_NO_VECTOR.
+pub fn start_virtio_sound_config(config_addr: usize, vector: u16) {
+    let header_t = unsafe { &mut *(config_addr as *mut PCIConfigurationSpcaeHeader) };
+    debug!("status: {}", header_t.status);
+    let mut transport = match virtio_pci_transport(config_addr) {
+        Some(transport) => transport,
+        None => {
+            warn!("did not find virtio pci capabilities");
             return;
         }
-        next_cap_pointer = cap.cap_next as usize;
-    }
+    };
+    let features = transport.begin_init(|features| features & VIRTIO_F_VERSION_1);
+    debug!("sound features: {:#x}", features);
 
-    println!("sound card cap pointer:");
-    let bar_content = 0x4100_0000;
-    println!("status: {}", header_t.status);
-    println!("bar: {}", bar.unwrap());
-    println!("offset: {}", offset.unwrap());
-    set_bar_value(config_addr, bar.unwrap(), bar_content);
-    println!(
-        "bar content: {:#x}: ",
-        get_bar_value(config_addr, bar.unwrap())
-    );
-    println!("status: {}", header_t.status);
+    if !transport.set_config_msix_vector(vector) {
+        warn!("sound: device refused msi-x vector {}", vector);
+    }
 
-    let common_cfg_addr = bar_content as usize + offset.unwrap() as usize;
-    println!("common cfg addr: {:#x}", common_cfg_addr);
-    let common_cfg = unsafe { &mut *(common_cfg_addr as *mut VirtioPciCommonCfg) };
-    println!("common cfg: {:?}", common_cfg);
-    virtio_pci_device_reset(common_cfg);
-    common_cfg.device_status |= virtio::DEVICE_STATUS_ACKNOWLEDGE;
-    println!("common cfg: {:?}", common_cfg);
-    common_cfg.device_status |= virtio::DEVICE_STATUS_DRIVER;
-    println!("common cfg: {:?}", common_cfg);
-    common_cfg.device_status |= virtio::DEVICE_STATUS_FEATURES_OK;
-    println!("common cfg: {:?}", common_cfg);
+    // controlq, eventq, txq, rxq
+    let queues = (0..transport.num_queues())
+        .map(|i| {
+            transport.set_queue_msix_vector(i, vector);
+            transport.create_queue(i, VIRTIO_SOUND_QUEUE_SIZE)
+        })
+        .collect();
+    transport.finish_init();
+    debug!("status: {}", header_t.status);
+    *VIRTIO_SOUND.lock() = Some(VirtioPciDevice { transport, queues });
 }
 
 pub fn get_bar_region_size(config_addr: usize, bar: usize) -> usize {
-    0
+    probe_bar(config_addr, bar).map_or(0, |info| info.size)
 }
 
 pub fn enable_device(config_addr: usize) {
     let header_t = unsafe { &mut *(config_addr as *mut PCIConfigurationSpcaeHeader) };
     header_t.command = header_t.command | 0b100; //enable mastering enable bit
     header_t.command = header_t.command | 0b10; //enable mmory space enable bit
-    println!("command register: {:x}", header_t.command);
+    debug!("command register: {:x}", header_t.command);
 }
 
 #[repr(C)]
@@ -0,0 +1,283 @@
+use super::{get_bar_value, set_bar_value, PCIConfigurationSpcaeHeader};
+use crate::fdt::{self, read_cells};
+use crate::memolayout::{PCI_IO_BASE, PCI_IO_SIZE, PCI_MMIO_BASE, PCI_MMIO_SIZE};
+use crate::debug;
+use crate::riscv::PGSIZE;
+use crate::spin_lock::SpinMutex;
+
+const BAR_IO_SPACE: u32 = 1 << 0;
+const BAR_MEM_TYPE_MASK: u32 = 0b110;
+const BAR_MEM_TYPE_64: u32 = 0b100;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+
+// the space code in bits 24-25 of the first cell of a pci "ranges" entry.
+const RANGES_SPACE_IO: u32 = 0b01;
+const RANGES_SPACE_MEM32: u32 = 0b10;
+const RANGES_SPACE_MEM64: u32 = 0b11;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BarKind {
+    Io,
+    Mem32,
+    Mem64, // takes this bar and the next one
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BarInfo {
+    pub kind: BarKind,
+    pub prefetchable: bool,
+    pub size: usize,
+}
+
+// size a bar the standard way: write all ones and see which address
+// bits stick. decoding is off meanwhile so the device doesn't claim
+// addresses it was never given. returns None for unimplemented bars.
+pub fn probe_bar(config_addr: usize, bar: usize) -> Option<BarInfo> {
+    let header = unsafe { &mut *(config_addr as *mut PCIConfigurationSpcaeHeader) };
+    let command = header.command;
+    header.command = command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE);
+
+    let orig = get_bar_value(config_addr, bar);
+    set_bar_value(config_addr, bar, 0xffff_ffff);
+    let mask = get_bar_value(config_addr, bar);
+    set_bar_value(config_addr, bar, orig);
+
+    let info = if mask == 0 {
+        None
+    } else if mask & BAR_IO_SPACE != 0 {
+        let mut mask = mask & !0x3;
+        if mask & 0xffff_0000 == 0 {
+            mask |= 0xffff_0000; // only 16 bits of io address decoded
+        }
+        Some(BarInfo {
+            kind: BarKind::Io,
+            prefetchable: false,
+            size: (!mask).wrapping_add(1) as usize,
+        })
+    } else {
+        let is_64 = mask & BAR_MEM_TYPE_MASK == BAR_MEM_TYPE_64;
+        let mut mask64 = (mask & !0xf) as u64;
+        if is_64 {
+            let orig_high = get_bar_value(config_addr, bar + 1);
+            set_bar_value(config_addr, bar + 1, 0xffff_ffff);
+            mask64 |= (get_bar_value(config_addr, bar + 1) as u64) << 32;
+            set_bar_value(config_addr, bar + 1, orig_high);
+        } else {
+            mask64 |= 0xffff_ffff_0000_0000;
+        }
+        Some(BarInfo {
+            kind: if is_64 {
+                BarKind::Mem64
+            } else {
+                BarKind::Mem32
+            },
+            prefetchable: mask & BAR_PREFETCHABLE != 0,
+            size: (!mask64).wrapping_add(1) as usize,
+        })
+    };
+
+    header.command = command;
+    info
+}
+
+// a piece of cpu physical address space the host bridge forwards to
+// pci, at bus_base on the pci side.
+#[derive(Clone, Copy)]
+struct PciWindow {
+    cpu_base: usize,
+    bus_base: usize,
+    size: usize,
+    next: usize, // offset of the first unallocated byte
+}
+
+impl PciWindow {
+    fn new(cpu_base: usize, bus_base: usize, size: usize) -> Self {
+        PciWindow {
+            cpu_base,
+            bus_base,
+            size,
+            next: 0,
+        }
+    }
+
+    // returns the (bus, cpu) addresses of a fresh, aligned region.
+    fn alloc(&mut self, size: usize, align: usize) -> Option<(usize, usize)> {
+        let bus = (self.bus_base + self.next + align - 1) & !(align - 1);
+        let off = bus - self.bus_base;
+        if off + size > self.size {
+            return None;
+        }
+        self.next = off + size;
+        Some((bus, self.cpu_base + off))
+    }
+
+    // round the next allocation up to align, return its bus address.
+    fn align_next(&mut self, align: usize) -> usize {
+        let bus = (self.bus_base + self.next + align - 1) & !(align - 1);
+        self.next = (bus - self.bus_base).min(self.size);
+        self.bus_base + self.next
+    }
+
+    fn bus_to_cpu(&self, bus: usize) -> Option<usize> {
+        if bus >= self.bus_base && bus < self.bus_base + self.size {
+            Some(self.cpu_base + (bus - self.bus_base))
+        } else {
+            None
+        }
+    }
+}
+
+struct PciWindows {
+    io: Option<PciWindow>,
+    mem32: Option<PciWindow>,
+    mem64: Option<PciWindow>, // prefetchable, above 4G
+}
+
+static WINDOWS: SpinMutex<Option<PciWindows>> = SpinMutex::new(None);
+
+// read the windows from the "ranges" of the host bridge in the device tree,
+// falling back to qemu virt's layout.
+fn windows_from_dtb() -> PciWindows {
+    let mut windows = PciWindows {
+        io: Some(PciWindow::new(PCI_IO_BASE, 0, PCI_IO_SIZE)),
+        mem32: Some(PciWindow::new(PCI_MMIO_BASE, PCI_MMIO_BASE, PCI_MMIO_SIZE)),
+        mem64: None,
+    };
+    let fdt = match fdt::fdt() {
+        Some(fdt) => fdt,
+        None => return windows,
+    };
+    let parent_cells = fdt.root().property_u32("#address-cells").unwrap_or(2) as usize;
+    let node = match fdt.find_compatible("pci-host-ecam-generic") {
+        Some(node) => node,
+        None => return windows,
+    };
+    let size_cells = node.property_u32("#size-cells").unwrap_or(2) as usize;
+    let ranges = match node.property("ranges") {
+        Some(ranges) => ranges,
+        None => return windows,
+    };
+
+    // each entry: pci address (3 cells), cpu address, size.
+    let entry_len = (3 + parent_cells + size_cells) * 4;
+    windows.io = None;
+    windows.mem32 = None;
+    for entry in ranges.chunks_exact(entry_len) {
+        let space = (fdt::be32(entry) >> 24) & 0b11;
+        let bus = read_cells(&entry[4..], 2) as usize;
+        let cpu = read_cells(&entry[12..], parent_cells) as usize;
+        let size = read_cells(&entry[(3 + parent_cells) * 4..], size_cells) as usize;
+        let window = Some(PciWindow::new(cpu, bus, size));
+        match space {
+            RANGES_SPACE_IO => windows.io = window,
+            RANGES_SPACE_MEM32 => windows.mem32 = window,
+            RANGES_SPACE_MEM64 => windows.mem64 = window,
+            _ => {}
+        }
+    }
+    if let Some(io) = windows.io.as_mut() {
+        // keep bus io address 0 free, a bar holding 0 means unassigned.
+        io.next = 0x1000;
+    }
+    windows
+}
+
+fn with_windows<R>(f: impl FnOnce(&mut PciWindows) -> R) -> R {
+    let mut guard = WINDOWS.lock();
+    let windows = guard.get_or_insert_with(windows_from_dtb);
+    f(windows)
+}
+
+// the cpu physical ranges the kernel page table has to map for pci bars:
+// io and 32-bit memory windows entirely, the 64-bit one as far as used.
+pub fn pci_mapped_windows() -> [Option<(usize, usize)>; 3] {
+    with_windows(|w| {
+        [
+            w.io.map(|io| (io.cpu_base, io.size)),
+            w.mem32.map(|mem| (mem.cpu_base, mem.size)),
+            w.mem64
+                .filter(|mem| mem.next != 0)
+                .map(|mem| (mem.cpu_base, (mem.next + PGSIZE - 1) & !(PGSIZE - 1))),
+        ]
+    })
+}
+
+// a pci-to-pci bridge forwards io in 4K and memory in 1M granules.
+const BRIDGE_IO_ALIGN: usize = 0x1000;
+const BRIDGE_MEM_ALIGN: usize = 0x10_0000;
+
+// round every window up to bridge granularity and return the bus
+// addresses the next io, memory and prefetchable allocations start at.
+// whatever gets allocated between two calls is what a bridge has to forward.
+pub fn bridge_window_marks() -> [Option<usize>; 3] {
+    with_windows(|w| {
+        [
+            w.io.as_mut().map(|io| io.align_next(BRIDGE_IO_ALIGN)),
+            w.mem32.as_mut().map(|mem| mem.align_next(BRIDGE_MEM_ALIGN)),
+            w.mem64.as_mut().map(|mem| mem.align_next(BRIDGE_MEM_ALIGN)),
+        ]
+    })
+}
+
+// carve out a non-overlapping, naturally aligned region for a bar.
+fn alloc_bar_region(info: &BarInfo) -> Option<(usize, usize)> {
+    with_windows(|w| match info.kind {
+        BarKind::Io => w.io.as_mut()?.alloc(info.size, info.size.max(4)),
+        // keep memory bars on separate pages, so they can be mapped apart.
+        BarKind::Mem64 if info.prefetchable && w.mem64.is_some() => {
+            w.mem64.as_mut()?.alloc(info.size, info.size.max(PGSIZE))
+        }
+        BarKind::Mem32 | BarKind::Mem64 => {
+            w.mem32.as_mut()?.alloc(info.size, info.size.max(PGSIZE))
+        }
+    })
+}
+
+fn bus_to_cpu(kind: BarKind, bus: usize) -> usize {
+    with_windows(|w| {
+        let window = match kind {
+            BarKind::Io => w.io,
+            _ => w.mem32.filter(|m| m.bus_to_cpu(bus).is_some()).or(w.mem64),
+        };
+        window.and_then(|w| w.bus_to_cpu(bus)).unwrap_or(bus)
+    })
+}
+
+// return the cpu address a bar decodes at, assigning it an address
+// first if nobody (there is no firmware) did, and turn on decoding.
+pub fn map_bar(config_addr: usize, bar: usize) -> usize {
+    let info = probe_bar(config_addr, bar).expect("map_bar: bar not implemented");
+    let value = get_bar_value(config_addr, bar);
+    let mut bus = match info.kind {
+        BarKind::Io => (value & !0x3) as usize,
+        _ => (value & !0xf) as usize,
+    };
+    if info.kind == BarKind::Mem64 {
+        bus |= (get_bar_value(config_addr, bar + 1) as usize) << 32;
+    }
+
+    let cpu = if bus != 0 {
+        bus_to_cpu(info.kind, bus)
+    } else {
+        let (bus, cpu) = alloc_bar_region(&info).expect("map_bar: out of pci address space");
+        set_bar_value(config_addr, bar, bus as u32);
+        if info.kind == BarKind::Mem64 {
+            set_bar_value(config_addr, bar + 1, (bus >> 32) as u32);
+        }
+        debug!(
+            "bar {} {:?} size {:#x} mapped at {:#x}",
+            bar, info.kind, info.size, cpu
+        );
+        cpu
+    };
+
+    let header = unsafe { &mut *(config_addr as *mut PCIConfigurationSpcaeHeader) };
+    header.command |= match info.kind {
+        BarKind::Io => COMMAND_IO_SPACE,
+        _ => COMMAND_MEMORY_SPACE,
+    };
+    cpu
+}
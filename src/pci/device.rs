@@ -0,0 +1,295 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::bar::{bridge_window_marks, map_bar, probe_bar, BarKind};
+use super::{
+    PCIConfigurationSpcaeHeader, PCIConfigurationSpcaeHeaderType0, PCIConfigurationSpcaeHeaderType1,
+};
+use crate::memolayout::{PCI_BASE, PCI_INTX_IRQ};
+use crate::{error, info, warn};
+use crate::spin_lock::SpinMutex;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_TYPE_DEVICE: u8 = 0;
+const HEADER_TYPE_BRIDGE: u8 = 1;
+
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+// bus/device/function, which also gives the place of the config space in ecam.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Bdf {
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+}
+
+impl Bdf {
+    pub fn config_addr(&self) -> usize {
+        PCI_BASE
+            + ((self.bus as usize) << 20)
+            + ((self.dev as usize) << 15)
+            + ((self.func as usize) << 12)
+    }
+}
+
+impl fmt::Display for Bdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.dev, self.func)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PciBar {
+    pub kind: BarKind,
+    pub prefetchable: bool,
+    pub size: usize,
+    pub addr: usize, // cpu physical address
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PciCap {
+    pub id: u8,
+    pub offset: u8, // in config space
+}
+
+#[derive(Clone)]
+pub struct PciDevice {
+    pub bdf: Bdf,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u32, // base class << 16 | sub class << 8 | programming interface
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_pin: u8,       // 1..4 for INTA..INTD, 0 for none
+    pub intx_irq: Option<usize>, // plic irq the pin ends up on
+    pub bars: [Option<PciBar>; 6],
+    pub caps: Vec<PciCap>,
+}
+
+impl PciDevice {
+    pub fn config_addr(&self) -> usize {
+        self.bdf.config_addr()
+    }
+
+    pub fn base_class(&self) -> u8 {
+        (self.class >> 16) as u8
+    }
+
+    pub fn sub_class(&self) -> u8 {
+        (self.class >> 8) as u8
+    }
+
+    // config space offset of the first capability with this id.
+    pub fn find_cap(&self, id: u8) -> Option<usize> {
+        self.caps
+            .iter()
+            .find(|cap| cap.id == id)
+            .map(|cap| cap.offset as usize)
+    }
+
+    pub fn bar_addr(&self, bar: usize) -> Option<usize> {
+        self.bars.get(bar)?.map(|bar| bar.addr)
+    }
+}
+
+// every function found by pci_enumerate(), bridges included.
+static DEVICES: SpinMutex<Vec<PciDevice>> = SpinMutex::new(Vec::new());
+
+// walk the whole hierarchy from bus 0 down through pci-to-pci bridges,
+// numbering buses and placing bars, and fill the registry.
+pub fn pci_enumerate() {
+    let mut devices = Vec::new();
+    let mut next_bus = 1;
+    scan_bus(0, &[], &mut next_bus, &mut devices);
+    *DEVICES.lock() = devices;
+}
+
+// path holds the device numbers of the bridges above bus, root side first.
+fn scan_bus(bus: u8, path: &[u8], next_bus: &mut u16, devices: &mut Vec<PciDevice>) {
+    for dev in 0..32 {
+        for func in 0..8 {
+            let bdf = Bdf { bus, dev, func };
+            let header = unsafe { &*(bdf.config_addr() as *const PCIConfigurationSpcaeHeader) };
+            if header.vendor_id == 0xffff {
+                if func == 0 {
+                    break; // no device here
+                }
+                continue;
+            }
+            let header_type = header.header_type;
+            match header_type & HEADER_TYPE_MASK {
+                HEADER_TYPE_DEVICE => devices.push(probe_function(bdf, path, 6)),
+                HEADER_TYPE_BRIDGE => scan_bridge(bdf, path, next_bus, devices),
+                _ => warn!("pci {}: unknown header type {:#x}", bdf, header_type),
+            }
+            if func == 0 && header_type & HEADER_TYPE_MULTI_FUNCTION == 0 {
+                break;
+            }
+        }
+    }
+}
+
+// give a bridge the next bus number, enumerate behind it, then open its
+// forwarding windows over whatever its subtree was assigned.
+fn scan_bridge(bdf: Bdf, path: &[u8], next_bus: &mut u16, devices: &mut Vec<PciDevice>) {
+    if *next_bus > 0xff {
+        error!("pci {}: out of bus numbers", bdf);
+        return;
+    }
+    let bridge = probe_function(bdf, path, 2);
+    let header = unsafe { &mut *(bdf.config_addr() as *mut PCIConfigurationSpcaeHeaderType1) };
+    let secondary = *next_bus as u8;
+    *next_bus += 1;
+    header.primary_bus_number = bdf.bus;
+    header.secondary_bus_number = secondary;
+    header.subordinate_bus_number = 0xff; // until we know
+    devices.push(bridge);
+
+    let mut below = path.to_vec();
+    below.push(bdf.dev);
+    let start = bridge_window_marks();
+    scan_bus(secondary, &below, next_bus, devices);
+    let end = bridge_window_marks();
+    header.subordinate_bus_number = (*next_bus - 1) as u8;
+
+    // a base above its limit closes a window.
+    match (start[0], end[0]) {
+        (Some(base), Some(end)) if end > base => {
+            let limit = end - 1;
+            header.io_base = ((base >> 8) & 0xf0) as u8;
+            header.io_limit = ((limit >> 8) & 0xf0) as u8;
+            header.io_base_upper = (base >> 16) as u16;
+            header.io_limit_upper = (limit >> 16) as u16;
+        }
+        _ => {
+            header.io_base = 0xf0;
+            header.io_limit = 0;
+        }
+    }
+    match (start[1], end[1]) {
+        (Some(base), Some(end)) if end > base => {
+            header.memory_base = ((base >> 16) & 0xfff0) as u16;
+            header.memory_limit = (((end - 1) >> 16) & 0xfff0) as u16;
+        }
+        _ => {
+            header.memory_base = 0xfff0;
+            header.memory_limit = 0;
+        }
+    }
+    match (start[2], end[2]) {
+        (Some(base), Some(end)) if end > base => {
+            let limit = end - 1;
+            header.prefetchable_memory_base = ((base >> 16) & 0xfff0) as u16 | 1;
+            header.prefetchable_memory_limit = ((limit >> 16) & 0xfff0) as u16 | 1;
+            header.prefetchable_base_upper = (base >> 32) as u32;
+            header.prefetchable_limit_upper = (limit >> 32) as u32;
+        }
+        _ => {
+            header.prefetchable_memory_base = 0xfff0;
+            header.prefetchable_memory_limit = 0;
+            header.prefetchable_base_upper = 0;
+            header.prefetchable_limit_upper = 0;
+        }
+    }
+    let common = unsafe { &mut *(bdf.config_addr() as *mut PCIConfigurationSpcaeHeader) };
+    common.command |= COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER;
+    info!(
+        "pci {}: bridge to buses {}..={}",
+        bdf, secondary, header.subordinate_bus_number
+    );
+}
+
+// the plic irq behind an interrupt pin. every bridge on the way up
+// rotates the pin by the device number below it, and the host bridge
+// does the same onto PCI_INTX_IRQ..PCI_INTX_IRQ+3.
+fn intx_irq(bdf: Bdf, path: &[u8], pin: u8) -> Option<usize> {
+    if pin == 0 || pin > 4 {
+        return None;
+    }
+    let mut pin = (pin - 1) as usize + bdf.dev as usize;
+    for dev in path.iter().rev() {
+        pin += *dev as usize;
+    }
+    Some(PCI_INTX_IRQ + pin % 4)
+}
+
+// read the identity of a function, place its first nbars bars and
+// collect its capabilities.
+fn probe_function(bdf: Bdf, path: &[u8], nbars: usize) -> PciDevice {
+    let config_addr = bdf.config_addr();
+    let header = unsafe { &*(config_addr as *const PCIConfigurationSpcaeHeader) };
+    let type0 = unsafe { &*(config_addr as *const PCIConfigurationSpcaeHeaderType0) };
+
+    let mut bars = [None; 6];
+    let mut bar = 0;
+    while bar < nbars {
+        let info = match probe_bar(config_addr, bar) {
+            Some(info) => info,
+            None => {
+                bar += 1;
+                continue;
+            }
+        };
+        bars[bar] = Some(PciBar {
+            kind: info.kind,
+            prefetchable: info.prefetchable,
+            size: info.size,
+            addr: map_bar(config_addr, bar),
+        });
+        bar += if info.kind == BarKind::Mem64 { 2 } else { 1 };
+    }
+
+    let mut caps = Vec::new();
+    if header.status & STATUS_CAPABILITIES_LIST != 0 {
+        // the capabilities pointer sits at the same offset in both header types.
+        let mut cap_ptr = type0.capabilities_pointer & !0x3;
+        while cap_ptr != 0 {
+            let addr = config_addr + cap_ptr as usize;
+            caps.push(PciCap {
+                id: unsafe { *(addr as *const u8) },
+                offset: cap_ptr,
+            });
+            cap_ptr = unsafe { *((addr + 1) as *const u8) } & !0x3;
+        }
+    }
+
+    let class_code = header.class_code;
+    PciDevice {
+        bdf,
+        vendor: header.vendor_id,
+        device: header.device_id,
+        class: (class_code[2] as u32) << 16 | (class_code[1] as u32) << 8 | class_code[0] as u32,
+        revision: header.revision_id,
+        header_type: header.header_type & HEADER_TYPE_MASK,
+        interrupt_pin: type0.interrupt_pin,
+        intx_irq: intx_irq(bdf, path, type0.interrupt_pin),
+        bars,
+        caps,
+    }
+}
+
+pub fn pci_devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+pub fn find_device(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|d| d.vendor == vendor_id && d.device == device_id)
+        .cloned()
+}
+
+// all functions of a base class and sub class, e.g. 0x06, 0x04 for bridges.
+pub fn find_devices_by_class(base_class: u8, sub_class: u8) -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|d| d.base_class() == base_class && d.sub_class() == sub_class)
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,116 @@
+use alloc::vec::Vec;
+
+use super::device::{pci_devices, Bdf, PciDevice};
+use super::serial::PCI_SERIAL_DRIVER;
+use super::virtio_sound::VIRTIO_SOUND_DRIVER;
+use crate::{info, warn};
+use crate::spin_lock::SpinMutex;
+
+pub const PCI_ANY_ID: u16 = 0xffff;
+
+// one entry of a driver's match table. a device matches when vendor
+// and device agree (PCI_ANY_ID matches anything) and its class code
+// agrees with class on the bits set in class_mask.
+#[derive(Clone, Copy)]
+pub struct PciDeviceId {
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u32,
+    pub class_mask: u32,
+}
+
+impl PciDeviceId {
+    pub const fn new(vendor: u16, device: u16) -> Self {
+        PciDeviceId {
+            vendor,
+            device,
+            class: 0,
+            class_mask: 0,
+        }
+    }
+
+    pub const fn class(class: u32, class_mask: u32) -> Self {
+        PciDeviceId {
+            vendor: PCI_ANY_ID,
+            device: PCI_ANY_ID,
+            class,
+            class_mask,
+        }
+    }
+
+    pub fn matches(&self, dev: &PciDevice) -> bool {
+        (self.vendor == PCI_ANY_ID || self.vendor == dev.vendor)
+            && (self.device == PCI_ANY_ID || self.device == dev.device)
+            && (dev.class & self.class_mask) == (self.class & self.class_mask)
+    }
+}
+
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+    fn id_table(&self) -> &'static [PciDeviceId];
+    // take over a matching device. on error the device stays unclaimed.
+    fn probe(&self, dev: &PciDevice) -> Result<(), &'static str>;
+    // stop using the device.
+    fn remove(&self, _dev: &PciDevice) {}
+}
+
+// every pci driver in the kernel. a new driver only needs an entry here.
+static PCI_DRIVERS: &[&dyn PciDriver] = &[&VIRTIO_SOUND_DRIVER, &PCI_SERIAL_DRIVER];
+
+// which driver took which device.
+static BOUND: SpinMutex<Vec<(Bdf, &'static dyn PciDriver)>> = SpinMutex::new(Vec::new());
+
+fn driver_for(dev: &PciDevice) -> Option<&'static dyn PciDriver> {
+    PCI_DRIVERS
+        .iter()
+        .copied()
+        .find(|driver| driver.id_table().iter().any(|id| id.matches(dev)))
+}
+
+// offer every enumerated device to the drivers, then report the
+// devices nobody took. bridges are handled by enumeration itself.
+pub fn pci_probe_drivers() {
+    let mut unclaimed = Vec::new();
+    for dev in pci_devices() {
+        if dev.header_type != 0 {
+            continue;
+        }
+        let driver = match driver_for(&dev) {
+            Some(driver) => driver,
+            None => {
+                unclaimed.push(dev);
+                continue;
+            }
+        };
+        match driver.probe(&dev) {
+            Ok(()) => {
+                info!("pci {}: bound to {}", dev.bdf, driver.name());
+                BOUND.lock().push((dev.bdf, driver));
+            }
+            Err(err) => {
+                warn!("pci {}: {} probe failed: {}", dev.bdf, driver.name(), err);
+                unclaimed.push(dev);
+            }
+        }
+    }
+    for dev in unclaimed {
+        info!(
+            "pci {}: {:04x}:{:04x} class {:06x} has no driver",
+            dev.bdf, dev.vendor, dev.device, dev.class
+        );
+    }
+}
+
+// detach the driver bound to a device, if any.
+pub fn pci_remove_device(bdf: Bdf) {
+    let driver = {
+        let mut bound = BOUND.lock();
+        match bound.iter().position(|(b, _)| *b == bdf) {
+            Some(i) => bound.remove(i).1,
+            None => return,
+        }
+    };
+    if let Some(dev) = pci_devices().into_iter().find(|d| d.bdf == bdf) {
+        driver.remove(&dev);
+    }
+}
@@ -0,0 +1,31 @@
+use super::device::PciDevice;
+use super::PCIConfigurationSpcaeHeader;
+use crate::irq::{free_irq, request_irq};
+
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+// hang handler(ctx) on the irq the device's pin is routed to, and let
+// the device assert it. the four intx irqs are shared by every device
+// on them, so the handler must check whether its device raised it.
+// returns the irq.
+pub fn request_intx(
+    dev: &PciDevice,
+    handler: fn(usize),
+    ctx: usize,
+) -> Result<usize, &'static str> {
+    let irq = dev.intx_irq.ok_or("no interrupt pin")?;
+    request_irq(irq, handler, ctx)?;
+    let header = unsafe { &mut *(dev.config_addr() as *mut PCIConfigurationSpcaeHeader) };
+    header.command &= !COMMAND_INTX_DISABLE;
+    Ok(irq)
+}
+
+pub fn free_intx(dev: &PciDevice, handler: fn(usize), ctx: usize) {
+    let irq = match dev.intx_irq {
+        Some(irq) => irq,
+        None => return,
+    };
+    let header = unsafe { &mut *(dev.config_addr() as *mut PCIConfigurationSpcaeHeader) };
+    header.command |= COMMAND_INTX_DISABLE;
+    free_irq(irq, handler, ctx);
+}
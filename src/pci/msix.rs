@@ -1,4 +1,9 @@
-use crate::println;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+
+use super::device::PciDevice;
+use super::{map_bar, MIS_X};
+use crate::debug;
+use crate::imsic::{msi_alloc, msi_free, MsiMessage};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -12,10 +17,10 @@ pub struct MSIXCapability {
 
 impl MSIXCapability {
     pub fn get_table_offset(&self) -> u32 {
-        self.table_offset_and_bir & !(0b11)
+        self.table_offset_and_bir & !(0b111)
     }
     pub fn get_pba_offset(&self) -> u32 {
-        self.pba_offset_and_bir & !(0b11)
+        self.pba_offset_and_bir & !(0b111)
     }
     pub fn table_size(&self) -> u16 {
         let n_minus_1 = self.message_control & 0b111_1111_1111;
@@ -29,17 +34,32 @@ impl MSIXCapability {
         }
     }
     pub fn get_table_bir(&self) -> u32 {
-        self.table_offset_and_bir & 0b11
+        self.table_offset_and_bir & 0b111
     }
 
     pub fn get_pba_bir(&self) -> u32 {
-        self.pba_offset_and_bir & 0b11
+        self.pba_offset_and_bir & 0b111
     }
-    pub fn get_table_entry(&self, index: u32) -> u32 {
-        panic!("not implemented");
+    pub fn set_function_mask(&mut self, mask: bool) {
+        if mask {
+            self.message_control |= 1 << 14;
+        } else {
+            self.message_control &= !(1 << 14);
+        }
+    }
+    // the table lives in one of the device's memory bars.
+    pub fn get_table_entry(&self, config_addr: usize, index: u16) -> &'static mut MSIXTableEntry {
+        if index >= self.table_size() {
+            panic!("msix: table entry {} out of range", index);
+        }
+        let table_addr = map_bar(config_addr, self.get_table_bir() as usize)
+            + self.get_table_offset() as usize;
+        let entry_addr = (table_addr as *mut MSIXTableEntry).wrapping_add(index as usize);
+        unsafe { &mut *entry_addr }
     }
 }
 
+#[repr(C)]
 pub struct MSIXTableEntry {
     pub message_address: u32,
     pub message_address_high: u32,
@@ -47,26 +67,63 @@ pub struct MSIXTableEntry {
     pub vector_control: u32,
 }
 
+impl MSIXTableEntry {
+    pub fn set_message(&mut self, message: &MsiMessage) {
+        unsafe {
+            write_volatile(addr_of_mut!(self.message_address), message.address as u32);
+            write_volatile(
+                addr_of_mut!(self.message_address_high),
+                (message.address >> 32) as u32,
+            );
+            write_volatile(addr_of_mut!(self.message_data), message.data);
+        }
+    }
+    pub fn set_masked(&mut self, mask: bool) {
+        let control = unsafe { read_volatile(addr_of!(self.vector_control)) };
+        let control = if mask { control | 1 } else { control & !1 };
+        unsafe { write_volatile(addr_of_mut!(self.vector_control), control) };
+    }
+}
+
 pub struct MSIXPBATableEntry(u64);
 
-pub fn dummy_msix_interrupt_handler() {
-    println!("dummy interrupt handler");
+// with every entry masked, nothing fires until a driver asks for it.
+pub fn mask_all_msix_entries(config_addr: usize, msix_cap: &MSIXCapability) {
+    for i in 0..msix_cap.table_size() {
+        msix_cap.get_table_entry(config_addr, i).set_masked(true);
+    }
 }
 
-pub fn set_dummy_msix_interrupt_handler(table_addr : usize, entry_index: usize) {
-    let table_addr = table_addr as *mut MSIXTableEntry;
-    let entry_addr = table_addr.wrapping_add(entry_index);
-    unsafe {
-        let table_entry = &mut *entry_addr;
-        table_entry.message_address = dummy_msix_interrupt_handler as u32;
-        table_entry.message_address_high = 0;
-        table_entry.message_data = 0;
-        table_entry.vector_control = 0;
+// point table entry `entry` of a device at a fresh imsic identity that
+// calls handler(ctx), and unmask it. msi-x must already be enabled.
+pub fn request_msix(
+    dev: &PciDevice,
+    entry: u16,
+    handler: fn(usize),
+    ctx: usize,
+) -> Result<MsiMessage, &'static str> {
+    let cap_pointer = dev.find_cap(MIS_X).ok_or("no msi-x capability")?;
+    let config_addr = dev.config_addr();
+    let msix_cap = unsafe { &*((config_addr + cap_pointer) as *const MSIXCapability) };
+    if entry >= msix_cap.table_size() {
+        return Err("no such msi-x table entry");
     }
+    let message = msi_alloc(handler, ctx).ok_or("no msi identity available")?;
+    let table_entry = msix_cap.get_table_entry(config_addr, entry);
+    table_entry.set_message(&message);
+    table_entry.set_masked(false);
+    debug!("pci {}: msi-x entry {} -> imsic identity {}", dev.bdf, entry, message.id);
+    Ok(message)
 }
 
-pub fn set_all_msix_interrupt_handler(table_addr : usize, table_size: usize) {
-    for i in 0..table_size {
-        set_dummy_msix_interrupt_handler(table_addr, i);
+// undo request_msix: mask table entry `entry` and give back identity id.
+pub fn free_msix(dev: &PciDevice, entry: u16, id: usize) {
+    if let Some(cap_pointer) = dev.find_cap(MIS_X) {
+        let config_addr = dev.config_addr();
+        let msix_cap = unsafe { &*((config_addr + cap_pointer) as *const MSIXCapability) };
+        if entry < msix_cap.table_size() {
+            msix_cap.get_table_entry(config_addr, entry).set_masked(true);
+        }
     }
-}
\ No newline at end of file
+    msi_free(id);
+}
@@ -0,0 +1,47 @@
+use super::bar::BarKind;
+use super::device::PciDevice;
+use super::driver::{PciDeviceId, PciDriver};
+use crate::info;
+use crate::klog::{clear_trace_port, set_trace_port};
+use crate::ns16550::{Ns16550, SerialConfig, DEFAULT_CLOCK};
+
+// qemu's pci-serial: one 16550 behind an 8-byte io bar, clocked like a
+// pc uart. the kernel doesn't take input from it; it is the trace
+// channel, where klog sends everything up to the trace level.
+pub struct PciSerialDriver;
+
+pub static PCI_SERIAL_DRIVER: PciSerialDriver = PciSerialDriver;
+
+static ID_TABLE: [PciDeviceId; 1] = [PciDeviceId::new(0x1b36, 0x0002)];
+
+impl PciDriver for PciSerialDriver {
+    fn name(&self) -> &'static str {
+        "pci-serial"
+    }
+
+    fn id_table(&self) -> &'static [PciDeviceId] {
+        &ID_TABLE
+    }
+
+    fn probe(&self, dev: &PciDevice) -> Result<(), &'static str> {
+        let bar = dev.bars[0].ok_or("no bar 0")?;
+        if bar.kind != BarKind::Io {
+            return Err("bar 0 is not io");
+        }
+        let port = Ns16550::new(bar.addr, 0);
+        if !port.probe() {
+            return Err("no 16550 behind bar 0");
+        }
+        port.init(DEFAULT_CLOCK, &SerialConfig::default());
+        // the first one found stays the trace channel.
+        if !set_trace_port(port) {
+            return Err("already have a trace channel");
+        }
+        info!("trace channel at {:#x}", bar.addr);
+        Ok(())
+    }
+
+    fn remove(&self, _dev: &PciDevice) {
+        clear_trace_port();
+    }
+}
@@ -1,3 +1,13 @@
+use alloc::vec::Vec;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+
+use super::{
+    map_bar, PCIConfigurationSpcaeHeader, PCIConfigurationSpcaeHeaderType0, VirtioPciCap,
+    VENDOR_SPECIFIC,
+};
+use crate::virtio::virtqueue::Virtqueue;
+use crate::virtio::Transport;
+
 pub const DEVICE_STATUS_ACKNOWLEDGE: u8 = 0x01;
 pub const DEVICE_STATUS_DRIVER: u8 = 0x02;
 pub const DEVICE_STATUS_FAILED: u8 = 0x80;// 128
@@ -5,8 +15,19 @@ pub const DEVICE_STATUS_FEATURES_OK: u8 = 0x08;
 pub const DEVICE_STATUS_DRIVER_OK: u8 = 0x04;
 pub const DEVICE_STATUS_NEEDS_RESET: u8 = 0x40; // 64
 
+// cfg_type of the vendor specific capabilities
+pub const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+pub const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+pub const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+pub const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+pub const VIRTIO_PCI_CAP_PCI_CFG: u8 = 5;
+
+// an msi-x vector register holding this raises no interrupt.
+pub const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
 
 #[derive(Debug)]
+#[repr(C)]
 pub struct VirtioPciCommonCfg {
     pub device_feature_select: u32,
     pub device_feature: u32,
@@ -32,3 +53,203 @@ pub fn virtio_pci_device_reset(device: &mut VirtioPciCommonCfg) {
     device.device_status = 0;
 }
 
+// a modern virtio-pci device, described by the structures its
+// vendor specific capabilities point at (section 4.1.4 of the virtio spec).
+pub struct VirtioPciTransport {
+    device_id: u32, // the virtio device type
+    common_cfg: *mut VirtioPciCommonCfg,
+    notify_base: usize,
+    notify_off_multiplier: u32,
+    isr: *mut u8,
+    device_cfg: *mut u8,
+}
+
+unsafe impl Send for VirtioPciTransport {}
+
+macro_rules! cfg_read {
+    ($self: expr, $field: ident) => {
+        unsafe { read_volatile(addr_of!((*$self.common_cfg).$field)) }
+    };
+}
+
+macro_rules! cfg_write {
+    ($self: expr, $field: ident, $val: expr) => {
+        unsafe { write_volatile(addr_of_mut!((*$self.common_cfg).$field), $val) }
+    };
+}
+
+impl VirtioPciTransport {
+    pub fn new(
+        device_id: u32,
+        common_cfg: usize,
+        notify_base: usize,
+        notify_off_multiplier: u32,
+        isr: usize,
+        device_cfg: usize,
+    ) -> Self {
+        VirtioPciTransport {
+            device_id,
+            common_cfg: common_cfg as *mut VirtioPciCommonCfg,
+            notify_base,
+            notify_off_multiplier,
+            isr: isr as *mut u8,
+            device_cfg: device_cfg as *mut u8,
+        }
+    }
+
+    pub fn num_queues(&self) -> u16 {
+        cfg_read!(self, num_queues)
+    }
+
+    // the device reads back VIRTIO_MSI_NO_VECTOR if it can't use the vector.
+    pub fn set_config_msix_vector(&mut self, vector: u16) -> bool {
+        cfg_write!(self, config_msix_vector, vector);
+        cfg_read!(self, config_msix_vector) == vector
+    }
+
+    // before the queue is enabled.
+    pub fn set_queue_msix_vector(&mut self, queue: u16, vector: u16) -> bool {
+        cfg_write!(self, queue_select, queue);
+        cfg_write!(self, queue_msix_vector, vector);
+        cfg_read!(self, queue_msix_vector) == vector
+    }
+}
+
+impl Transport for VirtioPciTransport {
+    fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        cfg_write!(self, device_feature_select, 0);
+        let low = cfg_read!(self, device_feature);
+        cfg_write!(self, device_feature_select, 1);
+        let high = cfg_read!(self, device_feature);
+        ((high as u64) << 32) | low as u64
+    }
+
+    fn write_driver_features(&mut self, features: u64) {
+        cfg_write!(self, driver_feature_select, 0);
+        cfg_write!(self, driver_feature, features as u32);
+        cfg_write!(self, driver_feature_select, 1);
+        cfg_write!(self, driver_feature, (features >> 32) as u32);
+    }
+
+    fn get_status(&self) -> u32 {
+        cfg_read!(self, device_status) as u32
+    }
+
+    fn set_status(&mut self, status: u32) {
+        cfg_write!(self, device_status, status as u8);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        if queue >= self.num_queues() {
+            return 0;
+        }
+        cfg_write!(self, queue_select, queue);
+        cfg_read!(self, queue_size)
+    }
+
+    fn queue_ready(&mut self, queue: u16) -> bool {
+        cfg_write!(self, queue_select, queue);
+        cfg_read!(self, queue_enable) != 0
+    }
+
+    fn setup_queue(&mut self, vq: &Virtqueue) {
+        cfg_write!(self, queue_select, vq.index());
+        cfg_write!(self, queue_size, vq.size());
+        cfg_write!(self, queue_desc, vq.desc_addr());
+        cfg_write!(self, queue_driver, vq.avail_addr());
+        cfg_write!(self, queue_device, vq.used_addr());
+        cfg_write!(self, queue_enable, 1);
+    }
+
+    fn notify(&mut self, queue: u16) {
+        cfg_write!(self, queue_select, queue);
+        let off = cfg_read!(self, queue_notify_off) as usize;
+        let addr = self.notify_base + off * self.notify_off_multiplier as usize;
+        unsafe { write_volatile(addr as *mut u16, queue) };
+    }
+
+    fn ack_interrupt(&mut self) -> u32 {
+        // reading the isr status clears it.
+        unsafe { read_volatile(self.isr) as u32 }
+    }
+
+    fn config_space(&self) -> *mut u8 {
+        self.device_cfg
+    }
+
+    fn config_generation(&self) -> u32 {
+        cfg_read!(self, config_generation) as u32
+    }
+}
+
+// a started virtio-pci device with its queues, indexed by queue number.
+pub struct VirtioPciDevice {
+    pub transport: VirtioPciTransport,
+    pub queues: Vec<Virtqueue>,
+}
+
+// the virtio device type of a virtio-pci device: modern devices are
+// 0x1040 + type, transitional ones 0x1000-0x103f keep the type in the
+// subsystem id.
+fn virtio_device_id(config_addr: usize) -> u32 {
+    let common = unsafe { &*(config_addr as *const PCIConfigurationSpcaeHeader) };
+    let header = unsafe { &*(config_addr as *const PCIConfigurationSpcaeHeaderType0) };
+    match common.device_id {
+        id @ 0x1040..=0x107f => (id - 0x1040) as u32,
+        0x1000..=0x103f => header.subsystem_id as u32,
+        _ => 0,
+    }
+}
+
+// walk the capability list of a virtio-pci device, map the bars the
+// virtio structures live in, and build a transport out of them.
+// returns None for devices without the modern interface.
+pub fn virtio_pci_transport(config_addr: usize) -> Option<VirtioPciTransport> {
+    let header = unsafe { &*(config_addr as *const PCIConfigurationSpcaeHeaderType0) };
+    let mut common_cfg = None;
+    let mut notify = None;
+    let mut isr = None;
+    let mut device_cfg = 0;
+
+    let mut next_cap_pointer = header.capabilities_pointer as usize;
+    while next_cap_pointer != 0 {
+        let cap_addr = config_addr + next_cap_pointer;
+        let cap = unsafe { &*(cap_addr as *const VirtioPciCap) };
+        next_cap_pointer = cap.cap_next as usize;
+        if cap.cap_vndr != VENDOR_SPECIFIC {
+            continue;
+        }
+        // the pci cfg access window is not in a bar.
+        if cap.cfg_type == VIRTIO_PCI_CAP_PCI_CFG {
+            continue;
+        }
+        let addr = map_bar(config_addr, cap.bar as usize) + cap.offset as usize;
+        match cap.cfg_type {
+            VIRTIO_PCI_CAP_COMMON_CFG => common_cfg = common_cfg.or(Some(addr)),
+            VIRTIO_PCI_CAP_NOTIFY_CFG => {
+                // struct virtio_pci_notify_cap appends the multiplier to the cap.
+                let multiplier = unsafe {
+                    read_volatile((cap_addr + core::mem::size_of::<VirtioPciCap>()) as *const u32)
+                };
+                notify = notify.or(Some((addr, multiplier)));
+            }
+            VIRTIO_PCI_CAP_ISR_CFG => isr = isr.or(Some(addr)),
+            VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg == 0 => device_cfg = addr,
+            _ => {}
+        }
+    }
+
+    let (notify_base, notify_off_multiplier) = notify?;
+    Some(VirtioPciTransport::new(
+        virtio_device_id(config_addr),
+        common_cfg?,
+        notify_base,
+        notify_off_multiplier,
+        isr?,
+        device_cfg,
+    ))
+}
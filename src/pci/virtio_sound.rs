@@ -0,0 +1,106 @@
+use super::device::PciDevice;
+use super::driver::{PciDeviceId, PciDriver};
+use super::virtio::VIRTIO_MSI_NO_VECTOR;
+use super::{
+    disable_msix, enable_device, enable_msix, free_intx, free_msix, request_intx, request_msix,
+    start_virtio_sound_config, MIS_X, VIRTIO_SOUND,
+};
+use crate::imsic::imsic_present;
+use crate::spin_lock::SpinMutex;
+use crate::{info, warn};
+use crate::virtio::Transport;
+
+pub struct VirtioSoundDriver;
+
+pub static VIRTIO_SOUND_DRIVER: VirtioSoundDriver = VirtioSoundDriver;
+
+// modern virtio-pci devices are 0x1040 + virtio device id; sound is 25.
+static ID_TABLE: [PciDeviceId; 1] = [PciDeviceId::new(0x1af4, 0x1040 + 25)];
+
+// msi-x table entry shared by configuration changes and all queues.
+const SOUND_MSIX_ENTRY: u16 = 0;
+
+// the interrupt probe took, for remove to give back.
+#[derive(Clone, Copy)]
+enum SoundIrq {
+    Msix(usize), // imsic identity
+    Intx,
+}
+
+static SOUND_IRQ: SpinMutex<Option<SoundIrq>> = SpinMutex::new(None);
+
+fn virtio_sound_intr(_ctx: usize) {
+    let mut sound = VIRTIO_SOUND.lock();
+    if let Some(sound) = sound.as_mut() {
+        // reading the isr lowers intx; a shared line may not be ours,
+        // but there is no harm in looking at the queues anyway.
+        sound.transport.ack_interrupt();
+        for vq in sound.queues.iter_mut() {
+            while vq.poll_used().is_some() {}
+        }
+    }
+}
+
+impl PciDriver for VirtioSoundDriver {
+    fn name(&self) -> &'static str {
+        "virtio-sound"
+    }
+
+    fn id_table(&self) -> &'static [PciDeviceId] {
+        &ID_TABLE
+    }
+
+    fn probe(&self, dev: &PciDevice) -> Result<(), &'static str> {
+        let config_addr = dev.config_addr();
+        enable_device(config_addr);
+        let mut vector = VIRTIO_MSI_NO_VECTOR;
+        if let Some(cap_pointer) = dev.find_cap(MIS_X).filter(|_| imsic_present()) {
+            enable_msix(config_addr, cap_pointer);
+            match request_msix(dev, SOUND_MSIX_ENTRY, virtio_sound_intr, 0) {
+                Ok(message) => {
+                    info!("virtio-sound: msi-x identity {}", message.id);
+                    *SOUND_IRQ.lock() = Some(SoundIrq::Msix(message.id));
+                    vector = SOUND_MSIX_ENTRY;
+                }
+                Err(err) => {
+                    warn!("virtio-sound: no msi-x: {}", err);
+                    disable_msix(config_addr, cap_pointer);
+                }
+            }
+        }
+        if vector == VIRTIO_MSI_NO_VECTOR {
+            // with msi-x off the device raises its interrupt pin.
+            match request_intx(dev, virtio_sound_intr, 0) {
+                Ok(irq) => {
+                    info!("virtio-sound: intx irq {}", irq);
+                    *SOUND_IRQ.lock() = Some(SoundIrq::Intx);
+                }
+                Err(err) => warn!("virtio-sound: no interrupt: {}", err),
+            }
+        }
+        start_virtio_sound_config(config_addr, vector);
+        if VIRTIO_SOUND.lock().is_none() {
+            self.remove(dev); // give back the interrupt
+            return Err("no modern virtio interface");
+        }
+        Ok(())
+    }
+
+    fn remove(&self, dev: &PciDevice) {
+        if let Some(mut sound) = VIRTIO_SOUND.lock().take() {
+            sound.transport.set_status(0); // reset
+        }
+        // with the device quiet, nothing can race us for the handler.
+        let irq = SOUND_IRQ.lock().take();
+        match irq {
+            Some(SoundIrq::Msix(id)) => {
+                free_msix(dev, SOUND_MSIX_ENTRY, id);
+                if let Some(cap_pointer) = dev.find_cap(MIS_X) {
+                    disable_msix(dev.config_addr(), cap_pointer);
+                }
+            }
+            Some(SoundIrq::Intx) => free_intx(dev, virtio_sound_intr, 0),
+            None => {}
+        }
+    }
+}
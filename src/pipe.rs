@@ -0,0 +1,160 @@
+use core::ptr::addr_of;
+
+use crate::errno::EPIPE;
+use crate::file::{filealloc, fileclose, FileKind, Fp};
+use crate::params::NFILE;
+use crate::proc::{either_copyin, either_copyout, killed, myproc, sleep, wakeup};
+use crate::spin_lock::SpinMutex;
+
+const PIPESIZE: usize = 512;
+
+// every pipe holds two files, so this many are enough.
+const NPIPE: usize = NFILE / 2;
+
+struct Pipe {
+    data: [u8; PIPESIZE],
+    nread: usize,    // number of bytes read
+    nwrite: usize,   // number of bytes written
+    readopen: bool,  // read fd is still open
+    writeopen: bool, // write fd is still open
+}
+
+// a free slot is None.
+static PIPES: [SpinMutex<Option<Pipe>>; NPIPE] = [const { SpinMutex::new(None) }; NPIPE];
+
+// readers sleep on this, waiting for data or for the writer to close.
+fn read_chan(pi: usize) -> usize {
+    addr_of!(PIPES[pi]) as usize
+}
+
+// writers sleep on this, waiting for room.
+fn write_chan(pi: usize) -> usize {
+    read_chan(pi) + 1
+}
+
+// claim a free pipe.
+fn alloc_pipe() -> Option<usize> {
+    for (pi, slot) in PIPES.iter().enumerate() {
+        let mut slot = slot.lock();
+        if slot.is_none() {
+            *slot = Some(Pipe {
+                data: [0; PIPESIZE],
+                nread: 0,
+                nwrite: 0,
+                readopen: true,
+                writeopen: true,
+            });
+            return Some(pi);
+        }
+    }
+    None
+}
+
+// make a pipe and return its read and write ends.
+pub fn pipealloc() -> Option<(Fp, Fp)> {
+    let pi = alloc_pipe()?;
+
+    let f0 = match filealloc(true, false, FileKind::Pipe { pi }) {
+        Some(f0) => f0,
+        None => {
+            *PIPES[pi].lock() = None;
+            return None;
+        }
+    };
+    match filealloc(false, true, FileKind::Pipe { pi }) {
+        Some(f1) => Some((f0, f1)),
+        None => {
+            // no file holds the write end, so close it here;
+            // fileclose() does the read end.
+            pipeclose(pi, true);
+            fileclose(f0);
+            None
+        }
+    }
+}
+
+// close one end of pipe pi; the pipe goes once both are closed.
+pub fn pipeclose(pi: usize, writable: bool) {
+    let mut slot = PIPES[pi].lock();
+    let pipe = slot.as_mut().expect("pipeclose");
+    if writable {
+        pipe.writeopen = false;
+        wakeup(read_chan(pi));
+    } else {
+        pipe.readopen = false;
+        wakeup(write_chan(pi));
+    }
+    if !pipe.readopen && !pipe.writeopen {
+        *slot = None;
+    }
+}
+
+// write n bytes from user address addr, sleeping while the pipe is
+// full. once the readers are gone the writer gets -EPIPE, so it can
+// tell a broken pipe from being killed; if some bytes already went in,
+// it gets their count instead.
+pub fn pipewrite(pi: usize, addr: usize, n: usize) -> isize {
+    let proc_index = myproc().expect("pipewrite: no proc");
+    let mut slot = PIPES[pi].lock();
+    let mut i = 0;
+    while i < n {
+        let pipe = slot.as_mut().expect("pipewrite");
+        if !pipe.readopen {
+            if i > 0 {
+                break;
+            }
+            return -EPIPE;
+        }
+        if killed(proc_index) {
+            return -1;
+        }
+        if pipe.nwrite == pipe.nread + PIPESIZE {
+            // pipe is full: let the readers at it.
+            wakeup(read_chan(pi));
+            slot = sleep(write_chan(pi), slot);
+            continue;
+        }
+        // copy as much as fits before the buffer wraps.
+        let start = pipe.nwrite % PIPESIZE;
+        let room = PIPESIZE - (pipe.nwrite - pipe.nread);
+        let m = (n - i).min(room).min(PIPESIZE - start);
+        if !either_copyin(&mut pipe.data[start..start + m], true, addr + i) {
+            break;
+        }
+        pipe.nwrite += m;
+        i += m;
+    }
+    wakeup(read_chan(pi));
+    i as isize
+}
+
+// read up to n bytes to user address addr. sleeps while the pipe is
+// empty and a writer could still fill it; 0 means end of file.
+pub fn piperead(pi: usize, addr: usize, n: usize) -> isize {
+    let proc_index = myproc().expect("piperead: no proc");
+    let mut slot = PIPES[pi].lock();
+    loop {
+        let pipe = slot.as_ref().expect("piperead");
+        if pipe.nread != pipe.nwrite || !pipe.writeopen {
+            break;
+        }
+        if killed(proc_index) {
+            return -1;
+        }
+        slot = sleep(read_chan(pi), slot);
+    }
+
+    let pipe = slot.as_mut().unwrap();
+    let mut i = 0;
+    while i < n && pipe.nread != pipe.nwrite {
+        let start = pipe.nread % PIPESIZE;
+        let m = (n - i).min(pipe.nwrite - pipe.nread).min(PIPESIZE - start);
+        if !either_copyout(true, addr + i, &pipe.data[start..start + m]) {
+            break;
+        }
+        pipe.nread += m;
+        i += m;
+    }
+    wakeup(write_chan(pi));
+    i as isize
+}
@@ -1,28 +1,67 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::{
-    memolayout::{plic_sclaim, plic_senable, plic_spriority, PLIC, UART_IRQ, VIRTIO0_IRQ},
+    fdt,
+    memolayout::{plic_priority, plic_sclaim, plic_senable, plic_spriority},
     proc::cpuid,
 };
 
+// the platform-level interrupt controller. which irqs are enabled
+// where is decided by irq.rs; this file only knows the registers.
+
+// with aia=aplic-imsic qemu has an APLIC (aplic.rs) and no PLIC at all.
+static PLIC_PRESENT: AtomicBool = AtomicBool::new(true);
+
+fn plic_present() -> bool {
+    PLIC_PRESENT.load(Ordering::Relaxed)
+}
+
 pub fn plicinit() {
-    let virtio_irq_ptr = (PLIC + VIRTIO0_IRQ * 4) as *mut u32;
-    let uart_irq = (PLIC + UART_IRQ * 4) as *mut u32;
-    unsafe {
-        (*uart_irq) = 1;
-        (*virtio_irq_ptr) = 1;
+    if let Some(fdt) = fdt::fdt() {
+        let present = fdt.find_compatible("riscv,plic0").is_some()
+            || fdt.find_compatible("sifive,plic-1.0.0").is_some();
+        PLIC_PRESENT.store(present, Ordering::Relaxed);
     }
 }
 
 pub fn plicinithart() {
-    let hart = cpuid();
-    let senable_addr = plic_senable(hart) as *mut u32;
-    let spriority = plic_spriority(hart) as *mut u32;
+    if !plic_present() {
+        return;
+    }
+    // set this hart's S-mode priority threshold to 0.
+    let spriority = plic_spriority(cpuid()) as *mut u32;
+    unsafe { *spriority = 0 };
+}
+
+// priority 0 disables an irq.
+pub fn plic_set_priority(irq: usize, priority: u32) {
+    if !plic_present() {
+        return;
+    }
+    let addr = (plic_priority() + irq * 4) as *mut u32;
+    unsafe { *addr = priority };
+}
+
+// let the irq interrupt hart's supervisor mode, or not.
+pub fn plic_set_enable(hart: usize, irq: usize, enable: bool) {
+    if !plic_present() {
+        return;
+    }
+    let addr = (plic_senable(hart) + (irq / 32) * 4) as *mut u32;
+    let bit = 1 << (irq % 32);
     unsafe {
-        *senable_addr = (1 << VIRTIO0_IRQ) | (1 << UART_IRQ);
-        *spriority = 0;
+        if enable {
+            *addr |= bit;
+        } else {
+            *addr &= !bit;
+        }
     }
 }
 
 pub fn plic_claim() -> u32 {
+    if !plic_present() {
+        return 0;
+    }
     let hart = cpuid();
     let irq_addr = plic_sclaim(hart) as *const u32;
     unsafe { *irq_addr }
@@ -1,13 +1,18 @@
 use core::mem::MaybeUninit;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
+use crate::file::{filealloc, fileclose, filedup, FileKind, Fp, CONSOLE};
 use crate::mem_utils::slice_cpy;
 use crate::memolayout::{get_trampoline, TRAMPOLINE, TRAPFRAME};
-use crate::params::{NCPU, NPROC};
-use crate::riscv::{r_tp, PGSIZE, PTE_R, PTE_W, PTE_X};
+use crate::params::{NCPU, NOFILE, NPROC, ROOTDEV, ROOTFS};
+use crate::riscv::{intr_get, intr_on, r_tp, PGSIZE, PTE_R, PTE_W, PTE_X};
+use crate::spin_lock::{pop_off, push_off, SpinMutexGuard};
 use crate::trap::usertrapret;
 use crate::utils::get_ref_addr;
-use crate::vm::{kalloc, mappages, uvmcreate, uvminit, PageTable};
+use crate::vfs::{mount_root, mount_tmp, namei, InodeRef};
+use crate::vm::{copyin, copyout, kalloc, mappages, uvmcopy, uvmcreate, uvminit, PageTable};
 
 // Saved registers for kernel context switches.
 
@@ -37,11 +42,11 @@ pub static mut cpus: [Cpu; NCPU] = unsafe { MaybeUninit::zeroed().assume_init()
 //0xef, 0xf0, 0x9f, 0xff, 0x2f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
 //0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
 //];
-pub static initcode: [u8; 60] = [
+pub static initcode: [u8; 52] = [
     0x17, 0x05, 0x00, 0x00, 0x03, 0x35, 0x05, 0x00, 0x97, 0x05, 0x00, 0x00, 0x83, 0xb5, 0x05, 0x00,
-    0x93, 0x08, 0x20, 0x07, 0x09, 0xa0, 0x73, 0x00, 0x00, 0x00, 0xf5, 0xbf, 0x93, 0x08, 0xa0, 0x02,
-    0x73, 0x00, 0x00, 0x00, 0xef, 0xf0, 0x9f, 0xff, 0x2f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, 0x01,
-    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xad, 0x48, 0x73, 0x00, 0x00, 0x00, 0xf5, 0xbf, 0x93, 0x08, 0xa0, 0x02, 0x73, 0x00, 0x00, 0x00,
+    0xef, 0xf0, 0x9f, 0xff, 0x2f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
 ];
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
@@ -136,13 +141,12 @@ pub enum ProcessState {
 }
 
 // Per-process state
-#[derive(Clone, Copy)]
 pub struct Proc {
     // struct spinlock lock;
 
     // p->lock must be held when using these:
     pub state: ProcessState, // Process state
-    // void *chan;                  // If non-zero, sleeping on chan
+    pub chan: usize,          // If non-zero, sleeping on chan
     pub killed: bool, // If non-zero, have been killed
     pub xstate: i32,  // Exit status to be returned to parent's wait
     pub pid: i32,     // Process ID
@@ -156,8 +160,8 @@ pub struct Proc {
     pub pagetable: *mut PageTable, // User page table
     pub trapframe: *mut Trapframe, // data page for trampoline.S
     pub context: Context,          // swtch() here to run process
-    // struct file *ofile[NOFILE];  // Open files
-    // struct inode *cwd;           // Current directory
+    pub ofile: [Option<Fp>; NOFILE], // Open files
+    pub cwd: Option<InodeRef>, // Current directory
     pub name: [u8; 16], // Process name (debugging)
 }
 
@@ -166,6 +170,8 @@ pub fn procinit() {
         unsafe {
             proc[i] = MaybeUninit::zeroed().assume_init();
             proc[i].kstack = crate::KSTACK!(i) as u64;
+            proc[i].ofile = [None; NOFILE];
+            proc[i].cwd = None;
         }
     }
 }
@@ -185,9 +191,7 @@ pub fn allocproc() -> Option<usize> {
                     p.context.sp = p.kstack + PGSIZE as u64;
                     return Some(i);
                 }
-                _ => {
-                    break;
-                }
+                _ => {}
             }
         }
     }
@@ -236,11 +240,22 @@ fn get_next_pid() -> i32 {
     pid
 }
 
+static ROOT_MOUNTED: AtomicBool = AtomicBool::new(false);
+
 pub fn forkret() {
     //we need release clock on curreent proc
     let proc_index = myproc().expect("forkret should have proc_index");
     proc_locks[proc_index].unlock();
-    //file system operation not implement
+    pop_off();
+
+    // file system initialization must be run in the context of a
+    // regular process (e.g., because it calls sleep), and thus cannot
+    // be run from main(). the first process mounts the root.
+    if !ROOT_MOUNTED.swap(true, Ordering::AcqRel) {
+        mount_root(ROOTFS, ROOTDEV);
+        mount_tmp();
+        unsafe { proc[proc_index].cwd = namei(b"/") };
+    }
 
     usertrapret();
 }
@@ -260,6 +275,59 @@ pub fn userinit() {
         (*p.trapframe).sp = PGSIZE as u64;
         p.state = ProcessState::RUNNABLE;
         slice_cpy(&mut p.name, "initcode".as_bytes());
+        // no cwd until forkret() has mounted the root.
+        p.cwd = None;
+
+        // there is no /dev/console to open yet, so init starts with
+        // the console as fds 0, 1 and 2.
+        let console = FileKind::Device {
+            ip: None,
+            major: CONSOLE,
+            minor: 0,
+        };
+        let f = filealloc(true, true, console).expect("userinit: no files");
+        p.ofile[0] = Some(f);
+        p.ofile[1] = Some(filedup(f));
+        p.ofile[2] = Some(filedup(f));
+    }
+}
+
+// create a new process, copying the parent.
+// sets up child kernel stack to return as if from fork() system call.
+// returns the child's pid, or None if there is no free process.
+pub fn fork() -> Option<i32> {
+    let me = myproc().expect("fork: no proc");
+    let np = allocproc()?;
+    unsafe {
+        let p = &*addr_of!(proc[me]);
+        let child = &mut *addr_of_mut!(proc[np]);
+
+        // copy user memory from parent to child.
+        uvmcopy(&mut *p.pagetable, &mut *child.pagetable, p.sz as usize);
+        child.sz = p.sz;
+
+        // copy saved user registers.
+        core::ptr::copy_nonoverlapping(p.trapframe, child.trapframe, 1);
+
+        // cause fork to return 0 in the child.
+        (*child.trapframe).a0 = 0;
+
+        // increment reference counts on open file descriptors.
+        for fd in 0..NOFILE {
+            child.ofile[fd] = p.ofile[fd].map(filedup);
+        }
+        child.cwd = p.cwd.clone();
+
+        child.name = p.name;
+        child.parent = addr_of_mut!(proc[me]);
+
+        push_off();
+        proc_locks[np].lock();
+        child.state = ProcessState::RUNNABLE;
+        proc_locks[np].unlock();
+        pop_off();
+
+        Some(child.pid)
     }
 }
 
@@ -283,7 +351,11 @@ pub fn scheduler() -> ! {
         let cpu = &mut cpus[cpuid];
         cpu.proc_index = None;
         loop {
+            // avoid deadlock by ensuring that devices can interrupt.
+            intr_on();
             for i in 0..NPROC {
+                // proc locks are taken with interrupts off, like xv6's acquire().
+                push_off();
                 proc_locks[i].lock();
                 let p = &mut proc[i];
                 match p.state {
@@ -299,11 +371,181 @@ pub fn scheduler() -> ! {
                     _ => {}
                 }
                 proc_locks[i].unlock();
+                pop_off();
+            }
+        }
+    }
+}
+
+// switch to scheduler. must hold only the proc lock (taken after a
+// push_off()) and have changed p.state. saves and restores intena
+// because intena is a property of this kernel thread, not this cpu.
+pub fn sched() {
+    let proc_index = myproc().expect("sched: no proc");
+    unsafe {
+        let cpu = &mut cpus[cpuid()];
+        if cpu.noff != 1 {
+            panic!("sched locks");
+        }
+        if matches!(proc[proc_index].state, ProcessState::RUNNING) {
+            panic!("sched running");
+        }
+        if intr_get() {
+            panic!("sched interruptible");
+        }
+        let intena = cpu.intena;
+        swtch(&mut proc[proc_index].context, &mut cpu.context);
+        cpus[cpuid()].intena = intena;
+    }
+}
+
+// atomically release the lock behind guard and sleep on chan.
+// reacquires the lock when awakened.
+// without a process (still booting) there is nobody to put to sleep,
+// so let interrupts in with the lock released and take it again.
+pub fn sleep<'a, T>(chan: usize, guard: SpinMutexGuard<'a, T>) -> SpinMutexGuard<'a, T> {
+    let lock = guard.mutex();
+    let proc_index = match myproc() {
+        Some(i) => i,
+        None => {
+            drop(guard);
+            core::hint::spin_loop();
+            return lock.lock();
+        }
+    };
+
+    // must acquire p.lock in order to change p.state and then call
+    // sched. once we hold p.lock, we can be guaranteed that we won't
+    // miss any wakeup (wakeup locks p.lock), so it's okay to release
+    // the lock behind guard.
+    push_off();
+    proc_locks[proc_index].lock();
+    drop(guard);
+
+    unsafe {
+        // go to sleep.
+        proc[proc_index].chan = chan;
+        proc[proc_index].state = ProcessState::SLEEPING;
+
+        sched();
+
+        // tidy up.
+        proc[proc_index].chan = 0;
+    }
+
+    // reacquire original lock.
+    proc_locks[proc_index].unlock();
+    pop_off();
+    lock.lock()
+}
+
+// wake up all processes sleeping on chan.
+// must be called without any p.lock.
+pub fn wakeup(chan: usize) {
+    let me = myproc();
+    for i in 0..NPROC {
+        if me == Some(i) {
+            continue;
+        }
+        push_off();
+        proc_locks[i].lock();
+        unsafe {
+            let p = &mut proc[i];
+            if matches!(p.state, ProcessState::SLEEPING) && p.chan == chan {
+                p.state = ProcessState::RUNNABLE;
             }
         }
+        proc_locks[i].unlock();
+        pop_off();
     }
 }
 
 extern "C" {
     fn swtch(curr: *mut Context, next: *mut Context);
 }
+
+// exit the current process. does not return.
+// an exited process remains in the zombie state
+// until its parent calls wait().
+pub fn exit(status: i32) -> ! {
+    let proc_index = myproc().expect("exit: no proc");
+
+    // close all open files.
+    for fd in 0..NOFILE {
+        if let Some(f) = unsafe { proc[proc_index].ofile[fd].take() } {
+            fileclose(f);
+        }
+    }
+
+    // dropping the cwd gives back its inode.
+    unsafe { proc[proc_index].cwd = None };
+
+    push_off();
+    proc_locks[proc_index].lock();
+    unsafe {
+        proc[proc_index].xstate = status;
+        proc[proc_index].state = ProcessState::ZOMBIE;
+    }
+    // jump into the scheduler, never to return.
+    sched();
+    panic!("zombie exit");
+}
+
+// kill the process with the given pid.
+// the victim won't exit until it tries to return
+// to user space (see usertrap() in trap.rs).
+pub fn kill(pid: i32) -> bool {
+    for i in 0..NPROC {
+        push_off();
+        proc_locks[i].lock();
+        let found = unsafe {
+            let p = &mut proc[i];
+            if p.pid == pid && !matches!(p.state, ProcessState::UNUSED) {
+                p.killed = true;
+                if matches!(p.state, ProcessState::SLEEPING) {
+                    // wake process from sleep().
+                    p.state = ProcessState::RUNNABLE;
+                }
+                true
+            } else {
+                false
+            }
+        };
+        proc_locks[i].unlock();
+        pop_off();
+        if found {
+            return true;
+        }
+    }
+    false
+}
+
+pub fn killed(proc_index: usize) -> bool {
+    unsafe { proc[proc_index].killed }
+}
+
+// copy to either a user address, or kernel address,
+// depending on user_dst.
+// returns false on error.
+pub fn either_copyout(user_dst: bool, dst: usize, src: &[u8]) -> bool {
+    if user_dst {
+        let proc_index = myproc().expect("either_copyout: no proc");
+        unsafe { copyout(&mut *proc[proc_index].pagetable, dst, src) }
+    } else {
+        unsafe { core::ptr::copy(src.as_ptr(), dst as *mut u8, src.len()) };
+        true
+    }
+}
+
+// copy from either a user address, or kernel address,
+// depending on user_src.
+// returns false on error.
+pub fn either_copyin(dst: &mut [u8], user_src: bool, src: usize) -> bool {
+    if user_src {
+        let proc_index = myproc().expect("either_copyin: no proc");
+        unsafe { copyin(&mut *proc[proc_index].pagetable, dst, src) }
+    } else {
+        unsafe { core::ptr::copy(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+        true
+    }
+}
@@ -394,6 +394,55 @@ pub fn r_time() -> u64{
     x
 }
 
+// Supervisor Indirect Register Select and Alias (AIA),
+// the window onto this hart's IMSIC interrupt file.
+// numbers rather than names, older assemblers don't know them.
+#[inline]
+pub fn w_siselect(x: u64){
+    unsafe {
+        asm! {
+            "csrw 0x150, {x}",
+            x = in(reg) x
+        } //volatile by default
+    }
+}
+
+#[inline]
+pub fn r_sireg() -> u64{
+    let mut x;
+    unsafe {
+        asm! {
+            "csrr {x}, 0x151",
+            x = out(reg) x
+        } //volatile by default
+    }
+    x
+}
+
+#[inline]
+pub fn w_sireg(x: u64){
+    unsafe {
+        asm! {
+            "csrw 0x151, {x}",
+            x = in(reg) x
+        } //volatile by default
+    }
+}
+
+// Supervisor Top External Interrupt (AIA).
+// swapping in zero claims the interrupt it reports.
+#[inline]
+pub fn claim_stopei() -> u64{
+    let mut x;
+    unsafe {
+        asm! {
+            "csrrw {x}, 0x15c, zero",
+            x = out(reg) x
+        } //volatile by default
+    }
+    x
+}
+
 // enable device interrupts
 #[inline]
 pub fn intr_on(){
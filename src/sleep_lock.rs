@@ -0,0 +1,85 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use crate::proc::{myproc, proc, sleep, wakeup};
+use crate::spin_lock::SpinMutex;
+
+// long-term locks for processes, like xv6's sleeplock: a process that
+// finds it held sleeps instead of spinning, so it may be kept across
+// disk i/o. interrupts stay on while it is held.
+
+struct SleepState {
+    locked: bool,
+    pid: i32, // process holding the lock, 0 for none
+}
+
+pub struct SleepLock<T> {
+    lk: SpinMutex<SleepState>, // protects locked and pid
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SleepLock<T> {}
+unsafe impl<T: Send> Send for SleepLock<T> {}
+
+pub struct SleepLockGuard<'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+fn mypid() -> i32 {
+    myproc().map_or(0, |i| unsafe { proc[i].pid })
+}
+
+impl<T> SleepLock<T> {
+    pub const fn new(value: T) -> Self {
+        SleepLock {
+            lk: SpinMutex::new(SleepState {
+                locked: false,
+                pid: 0,
+            }),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    // waiters sleep on the lock itself.
+    fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        let mut state = self.lk.lock();
+        while state.locked {
+            state = sleep(self.chan(), state);
+        }
+        state.locked = true;
+        state.pid = mypid();
+        SleepLockGuard { lock: self }
+    }
+
+    // does this process hold the lock?
+    pub fn holding(&self) -> bool {
+        let state = self.lk.lock();
+        state.locked && state.pid == mypid()
+    }
+}
+
+impl<T> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SleepLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.lk.lock();
+        state.locked = false;
+        state.pid = 0;
+        wakeup(self.lock.chan());
+    }
+}
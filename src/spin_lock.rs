@@ -1,6 +1,12 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::ptr::addr_of_mut;
 use core::sync::atomic::AtomicBool;
 use core::sync::atomic::Ordering;
 
+use crate::proc::{cpuid, cpus, Cpu};
+use crate::riscv::{intr_get, intr_off, intr_on};
+
 pub struct SpinLock {
     locked: AtomicBool,
 }
@@ -18,3 +24,108 @@ impl SpinLock {
         self.locked.store(false, Ordering::Release);
     }
 }
+
+// a spin::Mutex that keeps interrupts off on this hart while it is held,
+// like xv6's acquire()/release(). data shared with an interrupt handler
+// must be protected by one of these, otherwise the handler can spin
+// forever on a lock its own hart already holds.
+pub struct SpinMutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinMutexGuard<'a, T> {
+    lock: &'a SpinMutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+}
+
+unsafe impl<T: Send> Sync for SpinMutex<T> {}
+unsafe impl<T: Send> Send for SpinMutex<T> {}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        push_off(); // disable interrupts to avoid deadlock.
+        SpinMutexGuard {
+            lock: self,
+            guard: ManuallyDrop::new(self.inner.lock()),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        push_off();
+        match self.inner.try_lock() {
+            Some(guard) => Some(SpinMutexGuard {
+                lock: self,
+                guard: ManuallyDrop::new(guard),
+            }),
+            None => {
+                pop_off();
+                None
+            }
+        }
+    }
+}
+
+impl<'a, T> SpinMutexGuard<'a, T> {
+    // the lock this guard was taken from, so that sleep() can
+    // release it and take it again.
+    pub fn mutex(&self) -> &'a SpinMutex<T> {
+        self.lock
+    }
+}
+
+impl<T> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for SpinMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        pop_off();
+    }
+}
+
+fn mycpu() -> &'static mut Cpu {
+    unsafe { &mut (*addr_of_mut!(cpus))[cpuid()] }
+}
+
+// push_off/pop_off are like intr_off()/intr_on() except that they are matched:
+// it takes two pop_off()s to undo two push_off()s.  Also, if interrupts
+// are initially off, then push_off, pop_off leaves them off.
+pub fn push_off() {
+    let old = intr_get();
+    intr_off();
+    let cpu = mycpu();
+    if cpu.noff == 0 {
+        cpu.intena = old;
+    }
+    cpu.noff += 1;
+}
+
+pub fn pop_off() {
+    if intr_get() {
+        panic!("pop_off - interruptible");
+    }
+    let cpu = mycpu();
+    if cpu.noff < 1 {
+        panic!("pop_off");
+    }
+    cpu.noff -= 1;
+    if cpu.noff == 0 && cpu.intena {
+        intr_on();
+    }
+}
@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-use crate::{main, println};
+use crate::{fdt, main, println};
 use crate::riscv::*;
 use crate::memolayout::{clint_mtimecmp, CLINT_MTIME};
 
@@ -12,9 +12,14 @@ extern "C" {
 }
 
 #[no_mangle]
-extern "C" fn start() {
+extern "C" fn start(_hartid: u64, dtb: usize) {
+    // keep each CPU's hartid in its tp register, for cpuid().
+    // println! needs it already.
+    let id = r_mhartid();
+    w_tp(id);
     // set M Previous Privilege mode to Supervisor, for mret.
     println!("starting");// uart didn't get init, but it works.
+    fdt::set_dtb_addr(dtb);
     let mut x: u64 = r_mstatus();
     x &= !MSTATUS_MPP_MASK;
     x |= MSTATUS_MPP_S;
@@ -31,9 +36,9 @@ extern "C" fn start() {
     // access to all of physical memory.
     w_pmpaddr0(0x3fffffffffffff);
     w_pmpcfg0(0xf);
+    // let supervisor mode read the time CSR, for log timestamps.
+    w_mcounteren(r_mcounteren() | 2);
     //timerinit();
-    let id = r_mhartid();
-    w_tp(id);
     unsafe{asm!("mret");}
 }
 
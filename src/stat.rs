@@ -0,0 +1,10 @@
+// file metadata returned by fstat(), laid out as user space expects.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stat {
+    pub dev: i32,   // file system's disk device, < 0 for tmpfs
+    pub ino: u32,   // inode number
+    pub typ: i16,   // type of file
+    pub nlink: i16, // number of links to file
+    pub size: u64,  // size of file in bytes
+}
@@ -1,16 +1,92 @@
-use crate::print;
-use crate::{proc::{procid, proc}};
+use crate::println;
+use crate::proc::{proc, procid, Trapframe};
+use crate::sysfile::{
+    sys_close, sys_dup, sys_fstat, sys_lseek, sys_mknod, sys_mount, sys_open, sys_pipe, sys_read,
+    sys_umount, sys_write,
+};
+use crate::vm::copyinstr;
+use crate::sysproc::{sys_dmesg, sys_exit, sys_fork, sys_getpid, sys_kill};
+
+// system call numbers, as in xv6.
+pub const SYS_fork: u64 = 1;
+pub const SYS_exit: u64 = 2;
+pub const SYS_pipe: u64 = 4;
+pub const SYS_read: u64 = 5;
+pub const SYS_kill: u64 = 6;
+pub const SYS_fstat: u64 = 8;
+pub const SYS_dup: u64 = 10;
+pub const SYS_getpid: u64 = 11;
+pub const SYS_open: u64 = 15;
+pub const SYS_write: u64 = 16;
+pub const SYS_mknod: u64 = 17;
+pub const SYS_close: u64 = 21;
+pub const SYS_dmesg: u64 = 22;
+pub const SYS_lseek: u64 = 23;
+pub const SYS_mount: u64 = 24;
+pub const SYS_umount: u64 = 25;
+
+fn trapframe() -> &'static mut Trapframe {
+    let proc_index = procid().unwrap();
+    unsafe { &mut *proc[proc_index].trapframe }
+}
+
+fn argraw(n: usize) -> u64 {
+    let tf = trapframe();
+    match n {
+        0 => tf.a0,
+        1 => tf.a1,
+        2 => tf.a2,
+        3 => tf.a3,
+        4 => tf.a4,
+        5 => tf.a5,
+        _ => panic!("argraw"),
+    }
+}
+
+// fetch the nth 32-bit system call argument.
+pub fn argint(n: usize) -> i32 {
+    argraw(n) as i32
+}
+
+// retrieve an argument as a pointer.
+// doesn't check for legality, since
+// copyin/copyout will do that.
+pub fn argaddr(n: usize) -> usize {
+    argraw(n) as usize
+}
+
+// fetch the nul-terminated string at addr from the current process.
+// returns the length of the string, not including nul, or None.
+pub fn fetchstr(addr: usize, buf: &mut [u8]) -> Option<usize> {
+    let proc_index = procid().unwrap();
+    unsafe { copyinstr(&mut *proc[proc_index].pagetable, buf, addr) }
+}
+
 pub fn syscall(){
     let proc_index = procid().unwrap();
-    unsafe{
-        let proc_guard = &proc[proc_index];
-        let trapfram = &mut (*proc_guard.trapframe);
-        let num = trapfram.a7;
-        if num == 114{
-            print!("a");
-            loop {
-                
-            }
+    let num = trapframe().a7;
+    let ret = match num {
+        SYS_fork => sys_fork(),
+        SYS_exit => sys_exit(),
+        SYS_pipe => sys_pipe(),
+        SYS_read => sys_read(),
+        SYS_kill => sys_kill(),
+        SYS_fstat => sys_fstat(),
+        SYS_dup => sys_dup(),
+        SYS_getpid => sys_getpid(),
+        SYS_open => sys_open(),
+        SYS_write => sys_write(),
+        SYS_mknod => sys_mknod(),
+        SYS_close => sys_close(),
+        SYS_dmesg => sys_dmesg(),
+        SYS_lseek => sys_lseek(),
+        SYS_mount => sys_mount(),
+        SYS_umount => sys_umount(),
+        _ => {
+            let pid = unsafe { proc[proc_index].pid };
+            println!("{}: unknown sys call {}", pid, num);
+            -1
         }
-    }
+    };
+    trapframe().a0 = ret as u64;
 }
@@ -0,0 +1,271 @@
+use crate::fcntl::{O_CREATE, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+use crate::file::{
+    filealloc, fileclose, filedup, fileread, fileseek, filestat, filewrite, FileKind, Fp,
+};
+use crate::fs::layout::{T_DEVICE, T_FILE};
+use crate::params::{MAXPATH, NDEV, NOFILE};
+use crate::pipe::pipealloc;
+use crate::proc::{either_copyout, myproc, proc};
+use crate::syscall::{argaddr, argint, fetchstr};
+use crate::vfs::{is_dir, lookup, mount, namei, nameiparent, umount};
+use crate::warn;
+
+// file-system system calls.
+// mostly argument checking, since we don't trust
+// user code, and calls into file.rs and vfs.rs.
+
+// fetch the nth word-sized system call argument as a file descriptor
+// and return both the descriptor and the corresponding file.
+fn argfd(n: usize) -> Option<(usize, Fp)> {
+    let fd = argint(n);
+    let proc_index = myproc()?;
+    if fd < 0 || fd as usize >= NOFILE {
+        return None;
+    }
+    let f = unsafe { proc[proc_index].ofile[fd as usize] }?;
+    Some((fd as usize, f))
+}
+
+// allocate a file descriptor for the given file.
+// takes over file reference from caller on success.
+fn fdalloc(f: Fp) -> Option<usize> {
+    let proc_index = myproc()?;
+    let ofile = unsafe { &mut proc[proc_index].ofile };
+    let fd = ofile.iter().position(|f| f.is_none())?;
+    ofile[fd] = Some(f);
+    Some(fd)
+}
+
+// fetch the nth system call argument as a nul-terminated string.
+fn argstr(n: usize, buf: &mut [u8]) -> Option<&[u8]> {
+    let len = fetchstr(argaddr(n), buf)?;
+    Some(&buf[..len])
+}
+
+// fetch the nth system call argument as a path.
+fn argpath(n: usize, buf: &mut [u8; MAXPATH]) -> Option<&[u8]> {
+    argstr(n, buf)
+}
+
+pub fn sys_dup() -> i64 {
+    let (_, f) = match argfd(0) {
+        Some(f) => f,
+        None => return -1,
+    };
+    match fdalloc(f) {
+        Some(fd) => {
+            filedup(f);
+            fd as i64
+        }
+        None => -1,
+    }
+}
+
+pub fn sys_read() -> i64 {
+    match argfd(0) {
+        Some((_, f)) => fileread(f, argaddr(1), argint(2).max(0) as usize) as i64,
+        None => -1,
+    }
+}
+
+pub fn sys_write() -> i64 {
+    match argfd(0) {
+        Some((_, f)) => filewrite(f, argaddr(1), argint(2).max(0) as usize) as i64,
+        None => -1,
+    }
+}
+
+pub fn sys_close() -> i64 {
+    let (fd, f) = match argfd(0) {
+        Some(f) => f,
+        None => return -1,
+    };
+    let proc_index = myproc().expect("sys_close: no proc");
+    unsafe { proc[proc_index].ofile[fd] = None };
+    fileclose(f);
+    0
+}
+
+pub fn sys_fstat() -> i64 {
+    match argfd(0) {
+        Some((_, f)) => filestat(f, argaddr(1)) as i64,
+        None => -1,
+    }
+}
+
+pub fn sys_lseek() -> i64 {
+    match argfd(0) {
+        Some((_, f)) => fileseek(f, argaddr(1) as i64, argint(2)),
+        None => -1,
+    }
+}
+
+pub fn sys_open() -> i64 {
+    let mut buf = [0u8; MAXPATH];
+    let path = match argpath(0, &mut buf) {
+        Some(path) => path,
+        None => return -1,
+    };
+    let omode = argint(1);
+
+    let ip = if omode & O_CREATE != 0 {
+        let (dp, name) = match nameiparent(path) {
+            Some(parent) => parent,
+            None => return -1,
+        };
+        match lookup(&dp, name) {
+            Some(ip) => {
+                let typ = ip.stat().typ;
+                if typ != T_FILE && typ != T_DEVICE {
+                    return -1;
+                }
+                ip
+            }
+            None => match dp.create(name, T_FILE, 0, 0) {
+                Ok(ip) => ip,
+                Err(_) => return -1,
+            },
+        }
+    } else {
+        let ip = match namei(path) {
+            Some(ip) => ip,
+            None => return -1,
+        };
+        if is_dir(&ip) && omode != O_RDONLY {
+            return -1;
+        }
+        ip
+    };
+
+    // truncate before there is a descriptor to give back: a read-only
+    // file system refuses.
+    if omode & O_TRUNC != 0 && ip.stat().typ == T_FILE && ip.truncate().is_err() {
+        return -1;
+    }
+    let kind = match ip.device() {
+        Some((major, _)) if major >= NDEV => return -1,
+        Some((major, minor)) => FileKind::Device {
+            ip: Some(ip.clone()),
+            major,
+            minor,
+        },
+        None => FileKind::Inode {
+            ip: ip.clone(),
+            off: 0,
+        },
+    };
+    let readable = omode & O_WRONLY == 0;
+    let writable = omode & (O_WRONLY | O_RDWR) != 0;
+
+    let f = match filealloc(readable, writable, kind) {
+        Some(f) => f,
+        None => return -1,
+    };
+    let fd = match fdalloc(f) {
+        Some(fd) => fd,
+        None => {
+            fileclose(f);
+            return -1;
+        }
+    };
+
+    fd as i64
+}
+
+// make a device file at path for driver major, unit minor. the
+// virtio console's ports, for one, are major VIRTIO_CONSOLE with the
+// port number as minor.
+pub fn sys_mknod() -> i64 {
+    let mut buf = [0u8; MAXPATH];
+    let path = match argpath(0, &mut buf) {
+        Some(path) => path,
+        None => return -1,
+    };
+    let major = argint(1);
+    let minor = argint(2);
+    if major < 0 || major as usize >= NDEV || !(0..=i16::MAX as i32).contains(&minor) {
+        return -1;
+    }
+    let (dp, name) = match nameiparent(path) {
+        Some(parent) => parent,
+        None => return -1,
+    };
+    if lookup(&dp, name).is_some() {
+        return -1;
+    }
+    match dp.create(name, T_DEVICE, major as i16, minor as i16) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+pub fn sys_pipe() -> i64 {
+    let fdarray = argaddr(0); // user pointer to array of two integers
+    let proc_index = myproc().expect("sys_pipe: no proc");
+
+    let (rf, wf) = match pipealloc() {
+        Some(files) => files,
+        None => return -1,
+    };
+    let fd0 = fdalloc(rf);
+    let fd1 = fd0.and_then(|_| fdalloc(wf));
+    let (fd0, fd1) = match (fd0, fd1) {
+        (Some(fd0), Some(fd1)) => (fd0, fd1),
+        _ => {
+            if let Some(fd0) = fd0 {
+                unsafe { proc[proc_index].ofile[fd0] = None };
+            }
+            fileclose(rf);
+            fileclose(wf);
+            return -1;
+        }
+    };
+
+    let mut fds = [0u8; 8];
+    fds[..4].copy_from_slice(&(fd0 as i32).to_ne_bytes());
+    fds[4..].copy_from_slice(&(fd1 as i32).to_ne_bytes());
+    if !either_copyout(true, fdarray, &fds) {
+        unsafe {
+            proc[proc_index].ofile[fd0] = None;
+            proc[proc_index].ofile[fd1] = None;
+        }
+        fileclose(rf);
+        fileclose(wf);
+        return -1;
+    }
+    0
+}
+
+// mount(dev, path, fstype): mount a file system of type fstype from
+// virtio disk dev on the directory path.
+pub fn sys_mount() -> i64 {
+    let dev = argint(0);
+    let mut pathbuf = [0u8; MAXPATH];
+    let mut typebuf = [0u8; 16];
+    let (path, fstype) = match (argpath(1, &mut pathbuf), argstr(2, &mut typebuf)) {
+        (Some(path), Some(fstype)) if dev >= 0 => (path, fstype),
+        _ => return -1,
+    };
+    match mount(dev as usize, path, fstype) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!("mount: {}", e);
+            -1
+        }
+    }
+}
+
+pub fn sys_umount() -> i64 {
+    let mut buf = [0u8; MAXPATH];
+    let path = match argpath(0, &mut buf) {
+        Some(path) => path,
+        None => return -1,
+    };
+    match umount(path) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!("umount: {}", e);
+            -1
+        }
+    }
+}
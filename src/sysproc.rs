@@ -0,0 +1,108 @@
+use crate::errno::EINVAL;
+use crate::klog::{
+    klog_clear, klog_read, klog_size, set_console_level, set_module_level, set_trace_level, Level,
+    MODNAME,
+};
+use crate::proc::{either_copyout, exit, fork, kill, myproc, proc};
+use crate::syscall::{argaddr, argint, fetchstr};
+
+pub fn sys_exit() -> i64 {
+    exit(argint(0));
+}
+
+pub fn sys_fork() -> i64 {
+    match fork() {
+        Some(pid) => pid as i64,
+        None => -1,
+    }
+}
+
+pub fn sys_getpid() -> i64 {
+    let proc_index = myproc().expect("sys_getpid: no proc");
+    unsafe { proc[proc_index].pid as i64 }
+}
+
+pub fn sys_kill() -> i64 {
+    if kill(argint(0)) {
+        0
+    } else {
+        -1
+    }
+}
+
+// what sys_dmesg(action, addr, n) does.
+pub const DMESG_READ: i32 = 0; // copy up to n bytes of the log to addr
+pub const DMESG_CLEAR: i32 = 1;
+pub const DMESG_CONSOLE_LEVEL: i32 = 2; // console level n
+pub const DMESG_MODULE_LEVEL: i32 = 3; // level n for module addr, 0 to reset
+pub const DMESG_SIZE: i32 = 4; // bytes in the log
+pub const DMESG_TRACE_LEVEL: i32 = 5; // trace channel level n
+
+// a level from user space, checked before it is narrowed.
+fn arglevel(n: i32) -> Option<Level> {
+    u8::try_from(n).ok().and_then(Level::from_u8)
+}
+
+pub fn sys_dmesg() -> i64 {
+    let addr = argaddr(1);
+    let n = argint(2);
+    match argint(0) {
+        DMESG_READ => {
+            let n = n.max(0) as usize;
+            let mut chunk = [0u8; 128];
+            let mut off = 0;
+            while off < n {
+                let len = (n - off).min(chunk.len());
+                let got = klog_read(off, &mut chunk[..len]);
+                if got == 0 {
+                    break;
+                }
+                if !either_copyout(true, addr + off, &chunk[..got]) {
+                    return -1;
+                }
+                off += got;
+            }
+            off as i64
+        }
+        DMESG_CLEAR => {
+            klog_clear();
+            0
+        }
+        DMESG_CONSOLE_LEVEL => match arglevel(n) {
+            Some(level) => {
+                set_console_level(level);
+                0
+            }
+            None => -EINVAL as i64,
+        },
+        DMESG_MODULE_LEVEL => {
+            // room for a name of MODNAME bytes and its nul.
+            let mut module = [0u8; MODNAME + 1];
+            let len = match fetchstr(addr, &mut module) {
+                Some(len) => len,
+                None => return -1,
+            };
+            let level = match n {
+                0 => None,
+                n => match arglevel(n) {
+                    Some(level) => Some(level),
+                    None => return -EINVAL as i64,
+                },
+            };
+            if set_module_level(&module[..len], level) {
+                0
+            } else {
+                -1
+            }
+        }
+        DMESG_SIZE => klog_size() as i64,
+        DMESG_TRACE_LEVEL => match arglevel(n) {
+            Some(level) => {
+                set_trace_level(level);
+                0
+            }
+            None => -EINVAL as i64,
+        },
+        _ => -1,
+    }
+}
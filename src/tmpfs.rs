@@ -0,0 +1,305 @@
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::fs::layout::{T_DEVICE, T_DIR, T_FILE};
+use crate::proc::{either_copyin, either_copyout};
+use crate::riscv::PGSIZE;
+use crate::sleep_lock::SleepLock;
+use crate::stat::Stat;
+use crate::vfs::{alloc_fsid, DirEntry, FileOps, FsType, InodeOps, InodeRef, SuperOps};
+use crate::vm::{kfree, try_kalloc};
+
+// tmpfs: files and directories that live in memory, in pages from the
+// page allocator, and are gone once it is unmounted. it never touches a
+// disk, which makes it the place for scratch files.
+//
+// every node is owned by its directory, and points back at it with a
+// weak reference, so unmounting frees the whole tree.
+
+// pages of file data one tmpfs may hold.
+const MAXPAGES: usize = 2048;
+
+pub struct TmpfsType;
+
+pub static TMPFS: TmpfsType = TmpfsType;
+
+impl FsType for TmpfsType {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn nodev(&self) -> bool {
+        true
+    }
+
+    fn mount(&self, _dev: usize) -> Result<Arc<dyn SuperOps>, &'static str> {
+        let fs = Arc::new(Shared {
+            fsid: alloc_fsid(),
+            npages: AtomicUsize::new(0),
+        });
+        Ok(Arc::new(Tmpfs {
+            root: TmpNode::new(&fs, T_DIR, 0, 0, None),
+        }))
+    }
+}
+
+// inode numbers, unique across every tmpfs.
+static NEXT_INO: AtomicU32 = AtomicU32::new(1);
+
+// what the nodes of one tmpfs share.
+struct Shared {
+    fsid: usize,
+    npages: AtomicUsize, // pages in use, at most MAXPAGES
+}
+
+struct Tmpfs {
+    root: Arc<TmpNode>,
+}
+
+impl SuperOps for Tmpfs {
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+// one page of file data.
+struct Page(*mut u8);
+
+unsafe impl Send for Page {}
+unsafe impl Sync for Page {}
+
+impl Page {
+    fn alloc(fs: &Shared) -> Option<Page> {
+        if fs.npages.fetch_add(1, Ordering::Relaxed) >= MAXPAGES {
+            fs.npages.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        match try_kalloc() {
+            Some(pa) => {
+                unsafe { core::ptr::write_bytes(pa, 0, PGSIZE) };
+                Some(Page(pa))
+            }
+            None => {
+                fs.npages.fetch_sub(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.0, PGSIZE) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.0, PGSIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        kfree(self.0);
+    }
+}
+
+struct TmpNode {
+    fs: Arc<Shared>,
+    me: Weak<TmpNode>, // for the ".." of new subdirectories
+    ino: u32,
+    typ: i16,
+    major: i16, // T_DEVICE only
+    minor: i16,
+    data: SleepLock<NodeData>,
+}
+
+struct NodeData {
+    size: u64,
+    pages: Vec<Page>,                      // T_FILE only
+    entries: Vec<(Vec<u8>, Arc<TmpNode>)>, // T_DIR only, without "." and ".."
+    parent: Weak<TmpNode>,                 // the root is its own parent
+}
+
+impl TmpNode {
+    // a new node in directory parent, or a root if parent is None.
+    fn new(
+        fs: &Arc<Shared>,
+        typ: i16,
+        major: i16,
+        minor: i16,
+        parent: Option<&TmpNode>,
+    ) -> Arc<TmpNode> {
+        Arc::new_cyclic(|me| TmpNode {
+            fs: fs.clone(),
+            me: me.clone(),
+            ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+            typ,
+            major,
+            minor,
+            data: SleepLock::new(NodeData {
+                size: 0,
+                pages: Vec::new(),
+                entries: Vec::new(),
+                parent: parent.map_or(me.clone(), |p| p.me.clone()),
+            }),
+        })
+    }
+
+    fn parent(data: &NodeData) -> Arc<TmpNode> {
+        data.parent.upgrade().expect("tmpfs: no parent")
+    }
+
+    // give back the pages of a file.
+    fn free_pages(&self, data: &mut NodeData) {
+        self.fs
+            .npages
+            .fetch_sub(data.pages.len(), Ordering::Relaxed);
+        data.pages.clear();
+    }
+}
+
+impl Drop for TmpNode {
+    fn drop(&mut self) {
+        let mut data = self.data.lock();
+        self.free_pages(&mut data);
+    }
+}
+
+impl FileOps for TmpNode {
+    fn read_at(&self, user_dst: bool, dst: usize, off: u64, n: usize) -> isize {
+        if self.typ != T_FILE {
+            return -1;
+        }
+        let data = self.data.lock();
+        if off >= data.size {
+            return 0;
+        }
+        let n = (n as u64).min(data.size - off) as usize;
+        let mut done = 0;
+        while done < n {
+            let pos = off as usize + done;
+            let start = pos % PGSIZE;
+            let m = (n - done).min(PGSIZE - start);
+            let page = &data.pages[pos / PGSIZE];
+            if !either_copyout(user_dst, dst + done, &page.data()[start..start + m]) {
+                return -1;
+            }
+            done += m;
+        }
+        n as isize
+    }
+
+    fn write_at(&self, user_src: bool, src: usize, off: u64, n: usize) -> isize {
+        if self.typ != T_FILE {
+            return -1;
+        }
+        let mut data = self.data.lock();
+        if off > data.size {
+            return -1;
+        }
+        let mut done = 0;
+        while done < n {
+            let pos = off as usize + done;
+            let start = pos % PGSIZE;
+            let m = (n - done).min(PGSIZE - start);
+            if pos / PGSIZE == data.pages.len() {
+                match Page::alloc(&self.fs) {
+                    Some(page) => data.pages.push(page),
+                    None => break, // out of memory
+                }
+            }
+            let page = &mut data.pages[pos / PGSIZE];
+            if !either_copyin(&mut page.data_mut()[start..start + m], user_src, src + done) {
+                break;
+            }
+            done += m;
+        }
+        data.size = data.size.max(off + done as u64);
+        if done == 0 && n > 0 {
+            return -1;
+        }
+        done as isize
+    }
+
+    fn readdir(&self, index: usize) -> Option<DirEntry> {
+        if self.typ != T_DIR {
+            return None;
+        }
+        let data = self.data.lock();
+        let (ino, name) = match index {
+            0 => (self.ino, &b"."[..]),
+            1 => (TmpNode::parent(&data).ino, &b".."[..]),
+            _ => {
+                let (name, node) = data.entries.get(index - 2)?;
+                (node.ino, &name[..])
+            }
+        };
+        Some(DirEntry {
+            ino: ino as u64,
+            name: name.to_vec(),
+        })
+    }
+}
+
+impl InodeOps for TmpNode {
+    fn id(&self) -> (usize, u64) {
+        (self.fs.fsid, self.ino as u64)
+    }
+
+    fn stat(&self) -> Stat {
+        let data = self.data.lock();
+        Stat {
+            // no disk behind it, so a negative number that can't be one
+            // and differs for every mount.
+            dev: -(self.fs.fsid as i32),
+            ino: self.ino,
+            typ: self.typ,
+            nlink: 1,
+            size: data.size,
+        }
+    }
+
+    fn device(&self) -> Option<(usize, usize)> {
+        if self.typ != T_DEVICE {
+            return None;
+        }
+        Some((self.major as usize, self.minor as usize))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Option<InodeRef> {
+        if self.typ != T_DIR {
+            return None;
+        }
+        let data = self.data.lock();
+        if name == b".." {
+            return Some(TmpNode::parent(&data));
+        }
+        let (_, node) = data.entries.iter().find(|(n, _)| n == name)?;
+        Some(node.clone())
+    }
+
+    fn create(
+        &self,
+        name: &[u8],
+        typ: i16,
+        major: i16,
+        minor: i16,
+    ) -> Result<InodeRef, &'static str> {
+        if self.typ != T_DIR {
+            return Err("not a directory");
+        }
+        let mut data = self.data.lock();
+        if data.entries.iter().any(|(n, _)| n == name) {
+            return Err("file exists");
+        }
+        let node = TmpNode::new(&self.fs, typ, major, minor, Some(self));
+        data.entries.push((name.to_vec(), node.clone()));
+        Ok(node)
+    }
+
+    fn truncate(&self) -> Result<(), &'static str> {
+        let mut data = self.data.lock();
+        self.free_pages(&mut data);
+        data.size = 0;
+        Ok(())
+    }
+}
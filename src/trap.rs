@@ -3,18 +3,17 @@ use core::panic;
 use spin::Mutex;
 
 use crate::memolayout::{
-    get_kernelvec, get_trampoline, get_userret, get_uservec, TRAMPOLINE, TRAPFRAME, UART_IRQ,
-    VIRTIO0_IRQ,
+    get_kernelvec, get_trampoline, get_userret, get_uservec, TRAMPOLINE, TRAPFRAME,
 };
+use crate::imsic::imsic_intr;
+use crate::irq::handle_irq;
 use crate::plic::{plic_claim, plic_complete};
-use crate::proc::{proc, procid, Trapframe, cpuid};
+use crate::proc::{exit, killed, proc, procid, Trapframe, cpuid};
 use crate::riscv::{
     intr_get, intr_off, intr_on, r_satp, r_scause, r_sepc, r_sstatus, r_stval, r_tp, w_sepc,
     w_sstatus, w_stvec, PGSIZE, SATP_SV39, SSTATUS_SPIE, SSTATUS_SPP, w_sip, r_sip,
 };
 use crate::syscall::syscall;
-use crate::uart::uart_intr;
-use crate::virtio::virtio_blk::virtio_disk_intr;
 use crate::{println, MAKE_SATP};
 
 
@@ -63,7 +62,6 @@ pub fn usertrapret() {
 
 pub fn usertrap() {
     let mut intr_type = DevintrState::NotRecognized;
-    let mut proc_killed = false;
     // when a exception occurs, before we disable exception, another excpetion occurs
     // does this program can handle this?
     if (r_sstatus() & SSTATUS_SPP) != 0 {
@@ -78,10 +76,9 @@ pub fn usertrap() {
     trapfram.epc = r_sepc();
     if r_scause() == 8 {
         //syscall
-        proc_killed = p.killed;
-        if proc_killed {
+        if p.killed {
             //process killed
-            loop {}
+            exit(-1);
         }
         // sepc points to the ecall instruction,
         // but we want to return to the next instruction.
@@ -99,13 +96,12 @@ pub fn usertrap() {
                 println!("usertrap(): unexpected scause {} pid={}", r_scause(), p.pid);
                 println!("            sepc={} stval={}", r_sepc(), r_stval());
                 p.killed = true;
-                proc_killed = p.killed;
             }
             _ => {}
         }
     }
-    if proc_killed {
-        //not implement
+    if killed(proc_index) {
+        exit(-1);
     }
     if matches!(intr_type, DevintrState::TimerIntr) {
         // yield, but not implememnt
@@ -151,15 +147,16 @@ enum DevintrState {
 fn devintr() -> DevintrState {
     let scause = r_scause();
     if (scause & 0x8000000000000000) != 0 && (scause & 0xff) == 9 {
-        // this is a supervisor external interrupt, via PLIC.
+        // this is a supervisor external interrupt, via the IMSIC
+        // for msis, or else via PLIC.
+        if imsic_intr() {
+            return DevintrState::OtherDev;
+        }
 
         // irq indicates which device interrupted.
         let irq = plic_claim();
-        if irq == VIRTIO0_IRQ as u32 {
-            virtio_disk_intr();
-        } else if irq == UART_IRQ as u32 {
-            uart_intr();
-            // println!("unexpected interrupt irq={irq}");
+        if irq != 0 && !handle_irq(irq as usize) {
+            println!("unexpected interrupt irq={irq}");
         }
         // the PLIC allows each device to raise at most one
         // interrupt at a time; tell the PLIC the device is
@@ -1,17 +1,19 @@
 use core::fmt;
-use spin::Mutex;
-
-use crate::memolayout::UART;
-// use lazy_static::lazy_static;
-// use uart_16550::MmioSerialPort;
-// use spin::Mutex;
-// use crate::memolayout;
-
-// lazy_static!{
-//     pub static ref TERMINAL_WRITER: Mutex<MmioSerialPort> = Mutex::new(
-//        unsafe { MmioSerialPort::new(memolayout::UART)
-//     });
-// }
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console::console_intr;
+use crate::irq::request_irq;
+use crate::memolayout::{UART, UART_IRQ};
+use crate::ns16550::{
+    dtb_uart_config, dtb_uart_regs, Ns16550, UartErrors, UartEvent, IER_LINE_STATUS, IER_MODEM_STATUS,
+    IER_RX_ENABLE, IER_TX_ENABLE, LSR_BREAK, LSR_FRAMING, LSR_OVERRUN, LSR_PARITY,
+    MSR_CTS, MSR_DCD, MSR_DELTA_CTS, MSR_DELTA_DCD, MSR_DELTA_DSR, MSR_DSR, MSR_TRAILING_RI,
+};
+use crate::proc::{myproc, sleep, wakeup};
+use crate::riscv::intr_get;
+use crate::spin_lock::{pop_off, push_off, SpinMutex};
+use crate::{debug, info, warn};
 
 #[macro_export]
 macro_rules! print {
@@ -27,94 +29,207 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    get_uart_ref().write_fmt(args).unwrap();
+    UartWriter.write_fmt(args).unwrap();
 }
 
-const IER_RX_ENABLE: u8 = 1 << 0;
-const IER_TX_ENABLE: u8 = 1 << 1;
-
-const FCR_FIFO_ENABLE: u8 = 1 << 0;
-const FCR_FIFO_CLEAR: u8 = 3 << 1;
+// for panic(): flush what is buffered and write the message straight
+// to the uart. output from other harts stops for good.
+pub fn _print_panic(args: fmt::Arguments) {
+    use core::fmt::Write;
+    PANICKED.store(true, Ordering::Relaxed);
+    // the buffer may be locked by whoever panicked; then it's lost.
+    if let Some(mut tx) = UART_TX.try_lock() {
+        while tx.r != tx.w {
+            let c = tx.buf[tx.r % UART_TX_BUF_SIZE];
+            tx.r += 1;
+            uartputc_sync(c);
+        }
+    }
+    let _ = UartSyncWriter.write_fmt(args);
+}
 
-const LCR_EIGHT_BITS: u8 = 3 << 0;
-const LCR_BAUD_LATCH: u8 = 1 << 7;
+// the console uart, at the address qemu's virt machine puts it.
+static UART0: Ns16550 = Ns16550::new(UART, 0);
 
-const LSR_RX_READY: u8 = 1 << 0;
-const LSR_TX_IDLE: u8 = 1 << 5;
-static mut SERIAL_PORT: Mutex<*mut UartMimo> = Mutex::new(UART as *mut UartMimo);
+// the transmit output buffer. print! appends at w, uartstart() sends
+// from r, both only ever grow.
+const UART_TX_BUF_SIZE: usize = 512;
 
-struct UartMimo {
-    rhr_thr: u8,
-    ier: u8,
-    fcr_isr: u8,
-    lcr: u8,
-    __padding: u8,
-    lsr: u8,
+struct UartTx {
+    buf: [u8; UART_TX_BUF_SIZE],
+    w: usize, // write next to buf[w % UART_TX_BUF_SIZE]
+    r: usize, // read next from buf[r % UART_TX_BUF_SIZE]
 }
 
-pub fn console_init() {
-    uart_init();
-}
+static UART_TX: SpinMutex<UartTx> = SpinMutex::new(UartTx {
+    buf: [0; UART_TX_BUF_SIZE],
+    w: 0,
+    r: 0,
+});
 
-fn get_uart_ref<'a>() -> &'a mut UartMimo {
-    let mut uart_ptr = unsafe { SERIAL_PORT.lock() };
-    unsafe { &mut **uart_ptr }
+// writers waiting for room in the buffer sleep on this.
+fn tx_chan() -> usize {
+    addr_of!(UART_TX) as usize
 }
 
-fn uart_init() {
-    let uart_ref = get_uart_ref();
-    uart_ref.ier = 0; //disable interrupts
-    uart_ref.lcr = LCR_BAUD_LATCH;
-    uart_ref.fcr_isr = 0x03; //LSB for baud rate 38.4k
-    uart_ref.ier = 0x00; //MSB for baud rate 38.4k
+static PANICKED: AtomicBool = AtomicBool::new(false);
 
-    uart_ref.lcr = LCR_EIGHT_BITS; //leave set-baud mode and set word length to 8 bit
-    uart_ref.fcr_isr = FCR_FIFO_ENABLE | FCR_FIFO_CLEAR; //reset and clear FIFOs
-    uart_ref.ier = IER_TX_ENABLE | IER_RX_ENABLE; //enable transmit and receive interrupts
+pub fn uart_init() {
+    let (reg_shift, reg_io_width) = dtb_uart_regs(UART);
+    UART0.set_layout(reg_shift, reg_io_width);
+    let (clock, config) = dtb_uart_config(UART);
+    UART0.init(clock, &config);
+    UART0.enable_interrupts(IER_RX_ENABLE | IER_TX_ENABLE | IER_LINE_STATUS | IER_MODEM_STATUS);
+
+    request_irq(UART_IRQ, uart_intr, 0).expect("uart_init: uart irq");
+    info!(
+        "{} baud, {} data bits, parity {:?}, {} stop bits, {} Hz clock",
+        config.baud, config.data_bits, config.parity, config.stop_bits, clock
+    );
 }
 
-impl UartMimo {
-    fn _write_char(&mut self, c: u8) {
-        while self.lsr & LSR_TX_IDLE == 0 {}
-        self.rhr_thr = c;
+// add a character to the output buffer and tell the uart to start
+// sending if it isn't already. blocks if the buffer is full: a process
+// sleeps until the interrupt makes room, anything else (boot, interrupt
+// handlers) pushes bytes out by hand.
+pub fn uartputc(c: u8) {
+    let can_sleep = intr_get() && myproc().is_some();
+    let mut tx = UART_TX.lock();
+    if PANICKED.load(Ordering::Relaxed) {
+        loop {
+            core::hint::spin_loop()
+        }
     }
+    while tx.w == tx.r + UART_TX_BUF_SIZE {
+        if can_sleep {
+            // buffer is full. wait for uartstart() to open up space.
+            tx = sleep(tx_chan(), tx);
+        } else {
+            while !UART0.tx_idle() {}
+            uartstart(&mut tx);
+        }
+    }
+    let w = tx.w;
+    tx.buf[w % UART_TX_BUF_SIZE] = c;
+    tx.w += 1;
+    uartstart(&mut tx);
 }
 
+// alternate version of uartputc() that doesn't use interrupts or the
+// buffer, for echoing characters and for panic(). spins waiting for
+// the uart's output register to be empty.
 pub fn uartputc_sync(c: u8) {
-    let uart_ref = get_uart_ref();
-    while uart_ref.lsr & LSR_TX_IDLE == 0 {}
-    uart_ref.rhr_thr = c;
+    push_off();
+    UART0.putc_sync(c);
+    pop_off();
+}
+
+// if the uart is idle and a character is waiting in the transmit
+// buffer, send it. called from both the top and bottom half, with
+// UART_TX held.
+fn uartstart(tx: &mut UartTx) {
+    loop {
+        if tx.w == tx.r {
+            // transmit buffer is empty. reading isr acknowledges the
+            // THR-empty interrupt.
+            UART0.ack_tx();
+            return;
+        }
+        if !UART0.tx_idle() {
+            // the uart transmit holding register is full,
+            // so we cannot give it another byte.
+            // it will interrupt when it's ready for a new byte.
+            return;
+        }
+        let c = tx.buf[tx.r % UART_TX_BUF_SIZE];
+        tx.r += 1;
+        // maybe uartputc() is waiting for space in the buffer.
+        wakeup(tx_chan());
+        UART0.putc(c);
+    }
 }
 
-impl fmt::Write for UartMimo {
+struct UartWriter;
+
+impl fmt::Write for UartWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.bytes() {
-            self._write_char(c);
+            uartputc(c);
         }
         Ok(())
     }
 }
 
-pub fn uart_intr() {
-    loop {
-        let c_opt = uart_getc();
-        match c_opt {
-            None => break,
-            Some(c) => console_intr(c),
+struct UartSyncWriter;
+
+impl fmt::Write for UartSyncWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            uartputc_sync(c);
         }
+        Ok(())
     }
 }
 
-pub fn uart_getc() -> Option<u8> {
-    let uart_ref = get_uart_ref();
-    if uart_ref.lsr & 0x01 != 0 {
-        // input data is ready
-        Some(uart_ref.rhr_thr)
-    } else {
-        None
+// handle a uart interrupt, raised because input has arrived, or the
+// uart is ready for more output, or a character came in damaged, or a
+// modem control line changed.
+fn uart_intr(_ctx: usize) {
+    while let Some(event) = UART0.next_event() {
+        match event {
+            UartEvent::RxReady => {
+                while let Some(c) = uart_getc() {
+                    console_intr(c);
+                }
+            }
+            // reading ISR acknowledged it; refilled below.
+            UartEvent::TxEmpty => {}
+            UartEvent::LineStatus(lsr) => report_line_status(lsr),
+            UartEvent::ModemStatus(msr) => report_modem_status(msr),
+        }
+    }
+    // send buffered characters.
+    uartstart(&mut UART_TX.lock());
+}
+
+fn report_line_status(lsr: u8) {
+    let errors = &UART0.errors;
+    if lsr & LSR_OVERRUN != 0 {
+        warn!("receive overrun, input lost ({} so far)", errors.overrun.load(Ordering::Relaxed));
+    }
+    if lsr & LSR_PARITY != 0 {
+        warn!("parity error ({} so far)", errors.parity.load(Ordering::Relaxed));
+    }
+    if lsr & LSR_FRAMING != 0 {
+        warn!("framing error ({} so far)", errors.framing.load(Ordering::Relaxed));
+    }
+    if lsr & LSR_BREAK != 0 {
+        debug!("break received");
+    }
+}
+
+fn report_modem_status(msr: u8) {
+    let lines = [
+        (MSR_DELTA_CTS, MSR_CTS, "cts"),
+        (MSR_DELTA_DSR, MSR_DSR, "dsr"),
+        (MSR_DELTA_DCD, MSR_DCD, "dcd"),
+    ];
+    for (delta, line, name) in lines {
+        if msr & delta != 0 {
+            let state = if msr & line != 0 { "up" } else { "down" };
+            info!("{} {}", name, state);
+        }
+    }
+    if msr & MSR_TRAILING_RI != 0 {
+        info!("ring");
     }
 }
 
-fn console_intr(c: u8) {
-    uartputc_sync(c);
+// receive errors of the console uart since boot.
+pub fn uart_errors() -> &'static UartErrors {
+    &UART0.errors
+}
+
+pub fn uart_getc() -> Option<u8> {
+    UART0.getc()
 }
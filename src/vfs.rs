@@ -0,0 +1,339 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ext2::EXT2;
+use crate::fat::FAT32;
+use crate::file::files_on;
+use crate::fs::layout::T_DIR;
+use crate::fs::vnode::XV6FS;
+use crate::info;
+use crate::params::NPROC;
+use crate::proc::{myproc, proc};
+use crate::spin_lock::SpinMutex;
+use crate::stat::Stat;
+use crate::tmpfs::TMPFS;
+
+// virtual file system: every file system implements these traits, and
+// the rest of the kernel (open files, path lookup, the cwd) only sees
+// them. file systems are mounted on directories of one another; path
+// lookup walks through the mount table to stitch them into one tree.
+//
+// file types in Stat.typ are the native ones (T_DIR, T_FILE, T_DEVICE)
+// for every file system.
+
+// operations on a mounted file system, what unix calls the superblock.
+pub trait SuperOps: Send + Sync {
+    // the root directory.
+    fn root(&self) -> InodeRef;
+    // write back anything cached; called before unmount.
+    fn sync(&self) {}
+}
+
+// one directory entry, as readdir() returns it.
+pub struct DirEntry {
+    pub ino: u64,
+    pub name: Vec<u8>,
+}
+
+// reading and writing the contents of a file. addresses are user
+// virtual addresses if user is true, else kernel addresses. both return
+// the number of bytes moved, or -1.
+pub trait FileOps: Send + Sync {
+    fn read_at(&self, user_dst: bool, dst: usize, off: u64, n: usize) -> isize;
+    fn write_at(&self, user_src: bool, src: usize, off: u64, n: usize) -> isize;
+    // the index'th entry of a directory, "." and ".." included.
+    fn readdir(&self, index: usize) -> Option<DirEntry>;
+}
+
+// operations on a file system object.
+pub trait InodeOps: FileOps {
+    // identifies the inode: the file system's fsid and its inode number.
+    // called with the mount table locked, so it must not sleep.
+    fn id(&self) -> (usize, u64);
+    fn stat(&self) -> Stat;
+    // (major, minor) of a T_DEVICE inode.
+    fn device(&self) -> Option<(usize, usize)> {
+        None
+    }
+    // look name up in this directory. "." and ".." of a mounted file
+    // system's root are handled above, by lookup().
+    fn lookup(&self, name: &[u8]) -> Option<InodeRef>;
+    // make a new inode of type typ called name in this directory.
+    fn create(
+        &self,
+        name: &[u8],
+        typ: i16,
+        major: i16,
+        minor: i16,
+    ) -> Result<InodeRef, &'static str>;
+    // discard the contents of a file.
+    fn truncate(&self) -> Result<(), &'static str>;
+}
+
+pub type InodeRef = Arc<dyn InodeOps>;
+
+pub fn same_inode(a: &InodeRef, b: &InodeRef) -> bool {
+    a.id() == b.id()
+}
+
+pub fn is_dir(ip: &InodeRef) -> bool {
+    ip.stat().typ == T_DIR
+}
+
+// every file system gets a distinct fsid for InodeOps::id().
+static NEXT_FSID: AtomicUsize = AtomicUsize::new(1);
+
+pub fn alloc_fsid() -> usize {
+    NEXT_FSID.fetch_add(1, Ordering::Relaxed)
+}
+
+// a kind of file system that can be mounted.
+pub trait FsType: Sync {
+    fn name(&self) -> &'static str;
+    // true for file systems that don't live on a disk.
+    fn nodev(&self) -> bool {
+        false
+    }
+    // set up the file system on disk dev. nodev file systems ignore dev.
+    fn mount(&self, dev: usize) -> Result<Arc<dyn SuperOps>, &'static str>;
+}
+
+// every file system type in the kernel. a new one only needs an entry
+// here.
+static FS_TYPES: &[&dyn FsType] = &[&XV6FS, &FAT32, &EXT2, &TMPFS];
+
+struct Mount {
+    covered: Option<InodeRef>, // the directory mounted on, None for /
+    root: InodeRef,
+    sb: Arc<dyn SuperOps>,
+    fstype: &'static str,
+}
+
+// the mount table; entry 0 is /.
+// InodeRefs must not be dropped while holding this lock: dropping one
+// may need the disk.
+static MOUNTS: SpinMutex<Vec<Mount>> = SpinMutex::new(Vec::new());
+
+fn find_fstype(name: &[u8]) -> Option<&'static dyn FsType> {
+    FS_TYPES
+        .iter()
+        .copied()
+        .find(|t| t.name().as_bytes() == name)
+}
+
+// mount the root file system. called once, by the first process,
+// before it looks up a path.
+pub fn mount_root(fstype: &str, dev: usize) {
+    let fstype = find_fstype(fstype.as_bytes()).expect("mount_root: unknown file system");
+    let sb = fstype.mount(dev).expect("mount_root");
+    let mut mounts = MOUNTS.lock();
+    if !mounts.is_empty() {
+        panic!("mount_root: already mounted");
+    }
+    mounts.push(Mount {
+        covered: None,
+        root: sb.root(),
+        sb,
+        fstype: fstype.name(),
+    });
+}
+
+fn root() -> InodeRef {
+    MOUNTS
+        .lock()
+        .first()
+        .expect("no root file system")
+        .root
+        .clone()
+}
+
+// if a file system is mounted on ip, its root.
+fn mounted_on(ip: &InodeRef) -> Option<InodeRef> {
+    MOUNTS
+        .lock()
+        .iter()
+        .rev()
+        .find(|m| m.covered.as_ref().is_some_and(|c| same_inode(c, ip)))
+        .map(|m| m.root.clone())
+}
+
+// if ip is the root of a mounted file system, the directory it covers.
+fn covered_by(ip: &InodeRef) -> Option<InodeRef> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|m| same_inode(&m.root, ip))
+        .and_then(|m| m.covered.clone())
+}
+
+// look name up in directory dp, crossing mount points both ways.
+pub fn lookup(dp: &InodeRef, name: &[u8]) -> Option<InodeRef> {
+    if name == b"." {
+        return Some(dp.clone());
+    }
+    let mut dp = dp.clone();
+    if name == b".." {
+        if same_inode(&dp, &root()) {
+            return Some(dp);
+        }
+        // ".." of a mounted root is the parent of what it covers.
+        while let Some(covered) = covered_by(&dp) {
+            dp = covered;
+        }
+    }
+    let mut ip = dp.lookup(name)?;
+    // step onto whatever is mounted there.
+    while let Some(root) = mounted_on(&ip) {
+        ip = root;
+    }
+    Some(ip)
+}
+
+// split off the next path element, returning it and the rest of the
+// path with leading slashes removed. elements are passed on whole;
+// each file system decides how much of a long name it keeps. None if
+// there is no element.
+//
+// examples:
+//   skipelem("a/bb/c") = ("a", "bb/c")
+//   skipelem("///a//bb") = ("a", "bb")
+//   skipelem("a") = ("a", "")
+//   skipelem("") = skipelem("////") = None
+fn skipelem(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let start = path.iter().position(|&c| c != b'/')?;
+    let path = &path[start..];
+    let len = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+    let rest = &path[len..];
+    let rest = &rest[rest.iter().position(|&c| c != b'/').unwrap_or(rest.len())..];
+    Some((&path[..len], rest))
+}
+
+// the directory relative paths start from.
+fn cwd() -> InodeRef {
+    let cwd = myproc().and_then(|i| unsafe { proc[i].cwd.clone() });
+    cwd.unwrap_or_else(root)
+}
+
+// look up and return the inode for a path name.
+// if parent is true, return the inode for the parent and the final
+// path element.
+fn namex(path: &[u8], parent: bool) -> Option<(InodeRef, &[u8])> {
+    let mut ip = if path.first() == Some(&b'/') {
+        root()
+    } else {
+        cwd()
+    };
+
+    let mut path = path;
+    let mut last: &[u8] = &[];
+    while let Some((name, rest)) = skipelem(path) {
+        if !is_dir(&ip) {
+            return None;
+        }
+        if parent && rest.is_empty() {
+            // stop one level early.
+            return Some((ip, name));
+        }
+        ip = lookup(&ip, name)?;
+        path = rest;
+        last = name;
+    }
+    if parent {
+        return None;
+    }
+    Some((ip, last))
+}
+
+pub fn namei(path: &[u8]) -> Option<InodeRef> {
+    namex(path, false).map(|(ip, _)| ip)
+}
+
+// the parent directory of path and the last element of path.
+pub fn nameiparent(path: &[u8]) -> Option<(InodeRef, &[u8])> {
+    namex(path, true)
+}
+
+// mount a file system of type fstype from disk dev on directory path.
+pub fn mount(dev: usize, path: &[u8], fstype: &[u8]) -> Result<(), &'static str> {
+    let fstype = find_fstype(fstype).ok_or("unknown file system type")?;
+    let covered = namei(path).ok_or("no such directory")?;
+    if !is_dir(&covered) {
+        return Err("not a directory");
+    }
+    let sb = fstype.mount(dev)?;
+    let root = sb.root();
+    // check under the same lock as the push, so two mounts on one
+    // directory can't both get in. the directory may be a mount point
+    // either way: the root of a mounted file system, or covered by one.
+    let mut mounts = MOUNTS.lock();
+    let busy = mounts.iter().any(|m| {
+        same_inode(&m.root, &covered) || m.covered.as_ref().is_some_and(|c| same_inode(c, &covered))
+    });
+    if busy {
+        // sb and the inodes are dropped after the lock, as that may
+        // need the disk.
+        drop(mounts);
+        return Err("already a mount point");
+    }
+    mounts.push(Mount {
+        covered: Some(covered),
+        root,
+        sb,
+        fstype: fstype.name(),
+    });
+    drop(mounts);
+    let path = core::str::from_utf8(path).unwrap_or("?");
+    if fstype.nodev() {
+        info!("mounted {} on {}", fstype.name(), path);
+    } else {
+        info!("mounted {} from disk {} on {}", fstype.name(), dev, path);
+    }
+    Ok(())
+}
+
+// put a tmpfs on /tmp, making the directory first if the root file
+// system lacks it. a read-only root, like ext2, can't make it, so its
+// image must come with /tmp; ext2_img adds one. boot stops if /tmp
+// can't be had.
+pub fn mount_tmp() {
+    if namei(b"/tmp").is_none() {
+        root()
+            .create(b"tmp", T_DIR, 0, 0)
+            .expect("mount_tmp: no /tmp and can't make it");
+    }
+    mount(0, b"/tmp", b"tmpfs").expect("mount_tmp");
+}
+
+// unmount the file system whose root is at path. fails while anything
+// on it is open, is a cwd, or has something mounted on it.
+pub fn umount(path: &[u8]) -> Result<(), &'static str> {
+    let id = namei(path).ok_or("no such directory")?.id();
+    let fsid = id.0;
+
+    let mount = {
+        let mut mounts = MOUNTS.lock();
+        let i = mounts
+            .iter()
+            .position(|m| m.root.id() == id)
+            .ok_or("not a mount point")?;
+        if i == 0 {
+            return Err("can't unmount /");
+        }
+        let busy = mounts
+            .iter()
+            .any(|m| m.covered.as_ref().is_some_and(|c| c.id().0 == fsid))
+            || files_on(fsid)
+            || (0..NPROC)
+                .any(|p| unsafe { proc[p].cwd.as_ref() }.is_some_and(|c| c.id().0 == fsid));
+        if busy {
+            return Err("busy");
+        }
+        mounts.remove(i)
+    };
+
+    mount.sb.sync();
+    info!("unmounted {}", mount.fstype);
+    // the Mount is dropped here, without the lock.
+    Ok(())
+}
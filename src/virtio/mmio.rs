@@ -0,0 +1,115 @@
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+
+use super::virtqueue::Virtqueue;
+use super::{check_virtio_device_is_valid, MMIODeviceLagacyRegisterLayout, Transport};
+
+// virtio over memory mapped registers, section 4.2 of the virtio spec.
+pub struct MmioTransport {
+    regs: *mut MMIODeviceLagacyRegisterLayout,
+}
+
+unsafe impl Send for MmioTransport {}
+
+macro_rules! mmio_read {
+    ($self: expr, $field: ident) => {
+        unsafe { read_volatile(addr_of!((*$self.regs).$field)) }
+    };
+}
+
+macro_rules! mmio_write {
+    ($self: expr, $field: ident, $val: expr) => {
+        unsafe { write_volatile(addr_of_mut!((*$self.regs).$field), $val) }
+    };
+}
+
+impl MmioTransport {
+    pub fn new(base: usize) -> Option<Self> {
+        if !check_virtio_device_is_valid(base as *const u8) {
+            return None;
+        }
+        Some(MmioTransport {
+            regs: base as *mut MMIODeviceLagacyRegisterLayout,
+        })
+    }
+
+    pub fn base(&self) -> usize {
+        self.regs as usize
+    }
+
+    pub fn vendor_id(&self) -> u32 {
+        mmio_read!(self, vendor_id)
+    }
+}
+
+impl Transport for MmioTransport {
+    fn device_id(&self) -> u32 {
+        mmio_read!(self, device_id)
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        mmio_write!(self, device_features_sel, 0);
+        let low = mmio_read!(self, device_features);
+        mmio_write!(self, device_features_sel, 1);
+        let high = mmio_read!(self, device_features);
+        ((high as u64) << 32) | low as u64
+    }
+
+    fn write_driver_features(&mut self, features: u64) {
+        mmio_write!(self, driver_features_sel, 0);
+        mmio_write!(self, driver_features, features as u32);
+        mmio_write!(self, driver_features_sel, 1);
+        mmio_write!(self, driver_features, (features >> 32) as u32);
+    }
+
+    fn get_status(&self) -> u32 {
+        mmio_read!(self, status)
+    }
+
+    fn set_status(&mut self, status: u32) {
+        mmio_write!(self, status, status);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        mmio_write!(self, queue_sel, queue as u32);
+        mmio_read!(self, queue_num_max) as u16
+    }
+
+    fn queue_ready(&mut self, queue: u16) -> bool {
+        mmio_write!(self, queue_sel, queue as u32);
+        mmio_read!(self, queue_ready) != 0
+    }
+
+    fn setup_queue(&mut self, vq: &Virtqueue) {
+        mmio_write!(self, queue_sel, vq.index() as u32);
+        mmio_write!(self, queue_num, vq.size() as u32);
+
+        // write physical addresses
+        mmio_write!(self, queue_desc_low, vq.desc_addr() as u32);
+        mmio_write!(self, queue_desc_high, (vq.desc_addr() >> 32) as u32);
+        mmio_write!(self, queue_driver_low, vq.avail_addr() as u32);
+        mmio_write!(self, queue_driver_high, (vq.avail_addr() >> 32) as u32);
+        mmio_write!(self, queue_device_low, vq.used_addr() as u32);
+        mmio_write!(self, queue_device_high, (vq.used_addr() >> 32) as u32);
+
+        // queue is ready
+        mmio_write!(self, queue_ready, 0x1);
+    }
+
+    fn notify(&mut self, queue: u16) {
+        mmio_write!(self, queue_notify, queue as u32);
+    }
+
+    fn ack_interrupt(&mut self) -> u32 {
+        let status = mmio_read!(self, interrupt_status);
+        mmio_write!(self, interrupt_ack, status & 0x3);
+        status
+    }
+
+    fn config_space(&self) -> *mut u8 {
+        unsafe { addr_of_mut!((*self.regs).config) as *mut u8 }
+    }
+
+    fn config_generation(&self) -> u32 {
+        mmio_read!(self, config_generation)
+    }
+}
@@ -1,11 +1,15 @@
-use crate::mem_utils::memset;
-// use crate::println;
-use crate::riscv::PGSIZE;
-use crate::virtio::virtio_blk::{VirtqAvail, VirtqDesc, VirtqUsed, QUEUE_NUM};
-use crate::vm::kalloc;
-use virtio_blk::DISK;
+use mmio::MmioTransport;
+use virtqueue::Virtqueue;
 
+use crate::irq::request_irq;
+use crate::memolayout::{virtio_mmio, virtio_mmio_irq, VIRTIO_MMIO_NUM};
+use crate::println;
+use crate::spin_lock::SpinMutex;
+
+pub mod mmio;
 pub mod virtio_blk;
+pub mod virtio_console;
+pub mod virtqueue;
 
 pub const MAGIC_VALUE: u32 = 0x74726976;
 pub const DEVICE_VERSION: u32 = 0x2; //use force qemu to use new virtio standard
@@ -19,6 +23,77 @@ const STATUS_FAILED: u32 = 128;
 
 const VIRTIO_F_INDIRECT_DESC: u32 = 1 << 28;
 const VIRTIO_F_EVENT_IDX: u32 = 1 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// what a virtio driver needs from the bus the device sits on.
+// implemented by the mmio register block (mmio.rs) and by the
+// modern virtio-pci capabilities (pci/virtio.rs).
+pub trait Transport {
+    fn device_id(&self) -> u32;
+    fn read_device_features(&mut self) -> u64;
+    fn write_driver_features(&mut self, features: u64);
+    fn get_status(&self) -> u32;
+    fn set_status(&mut self, status: u32);
+    fn max_queue_size(&mut self, queue: u16) -> u16;
+    fn queue_ready(&mut self, queue: u16) -> bool;
+    // hand the rings of vq to the device and enable the queue.
+    fn setup_queue(&mut self, vq: &Virtqueue);
+    fn notify(&mut self, queue: u16);
+    // acknowledge the interrupt, return the interrupt status bits.
+    fn ack_interrupt(&mut self) -> u32;
+    // device specific configuration space.
+    fn config_space(&self) -> *mut u8;
+    fn config_generation(&self) -> u32;
+
+    // steps 1 to 6 of the driver initialization sequence in section 3.1.1.
+    // negotiate gets the device's feature bits and returns the ones the
+    // driver accepts. returns the negotiated features.
+    fn begin_init(&mut self, negotiate: fn(u64) -> u64) -> u64 {
+        let mut status = 0;
+        self.set_status(status); //1. reset device
+        status |= STATUS_ACKNOWLEDGE;
+        self.set_status(status); //2. set ACKNOWLEDGE bit
+        status |= STATUS_DRIVER;
+        self.set_status(status); //3. set DRIVER bit
+
+        let features = negotiate(self.read_device_features()); //4. read features bit
+        self.write_driver_features(features);
+        status |= STATUS_FEATURES_OK;
+        self.set_status(status); //5. set FEATURES_OK bit
+
+        if self.get_status() & STATUS_FEATURES_OK == 0 {
+            //6. check FEATURES_OK
+            self.set_status(status | STATUS_FAILED);
+            panic!("can't set FEATURES_OK");
+        }
+        features
+    }
+
+    // step 8: the device is live after this.
+    fn finish_init(&mut self) {
+        let status = self.get_status();
+        self.set_status(status | STATUS_DRIVER_OK);
+    }
+
+    // step 7, once per queue: allocate a virtqueue of at most size
+    // entries and hand it to the device.
+    fn create_queue(&mut self, index: u16, size: u16) -> Virtqueue {
+        // ensure queue is not in use
+        if self.queue_ready(index) {
+            panic!("virtio queue {} should not be ready", index);
+        }
+        //check maximum queue size
+        let max = self.max_queue_size(index);
+        if max == 0 {
+            panic!("virtio device has no queue {}", index);
+        }
+        // queue sizes are powers of 2; shrink to fit the device.
+        let size = if max < size { 1 << (15 - max.leading_zeros()) } else { size };
+        let vq = Virtqueue::new(index, size);
+        self.setup_queue(&vq);
+        vq
+    }
+}
 
 #[repr(C, align(4096))]
 struct MMIODeviceLagacyRegisterLayout {
@@ -72,95 +147,51 @@ pub fn check_virtio_device_is_valid(reg_addr: *const u8) -> bool {
         && dev_reg_ref.device_id != 0x0
 }
 
-pub fn init_virtio_blk_device(dev_addr: *const u8) {
-    if !check_virtio_device_is_valid(dev_addr) {
-        panic!("not valid device");
-    }
-    let mut status;
-    let dev_reg_ref = unsafe { &mut *(dev_addr as u64 as *mut MMIODeviceLagacyRegisterLayout) };
-    if dev_reg_ref.device_id != virtio_blk::DEVICE_ID
-        && dev_reg_ref.vendor_id != virtio_blk::VENDOR_ID
-    {
-        panic!("this device is not we want!");
-    }
-
-    status = 0;
-    dev_reg_ref.status = status; //1. reset device
-    status |= STATUS_ACKNOWLEDGE;
-    dev_reg_ref.status = status; //2. set ACKNOWLEDGE bit
-    status |= STATUS_DRIVER;
-    dev_reg_ref.status = status; //3. set DRIVER bit
-
-    let mut feature_bits: u32 = dev_reg_ref.device_features; //4. read features bit
-    feature_bits &= !virtio_blk::VIRTIO_BLK_F_RO;
-    feature_bits &= !virtio_blk::VIRTIO_BLK_F_SCSI;
-    feature_bits &= !virtio_blk::VIRTIO_BLK_F_CONFIG_WCE;
-    feature_bits &= !virtio_blk::VIRTIO_BLK_F_MQ;
-    feature_bits &= !virtio_blk::VIRTIO_F_ANY_LAYOUT;
-    feature_bits &= !VIRTIO_F_EVENT_IDX;
-    feature_bits &= !VIRTIO_F_INDIRECT_DESC;
-
-    dev_reg_ref.driver_features = feature_bits; //4. set features bit
-    status |= STATUS_FEATURES_OK;
-    dev_reg_ref.status = status; //5. set FEATURES_OK bit
-
-    status = dev_reg_ref.status; // we have to use explicity write this line, so that compiler can generate 'lw' instruction, rather than 'lbu' instruction.
-
-    if status & STATUS_FEATURES_OK == 0 {
-        //6. check FEATURES_OK
-        panic!("can't set FEATURES_OK");
-    }
-
-    // initialize queue 0
-    dev_reg_ref.queue_sel = 0;
-
-    // ensure queue 0 is not in use
-    if dev_reg_ref.queue_ready != 0 {
-        panic!("virtio disk should not be ready");
-    }
-
-    //check maximum queue size
-    let max = dev_reg_ref.queue_num_max;
-    if max == 0 {
-        panic!("virtio disk has no queue 0");
-    }
-    if max < QUEUE_NUM as u32 {
-        panic!("virtio disk max queue too short");
-    }
-
-    let disk_ref = &mut DISK.lock();
-
-    disk_ref.desc = kalloc() as *mut VirtqDesc;
-    disk_ref.avail = kalloc() as *mut VirtqAvail;
-    disk_ref.used = kalloc() as *mut VirtqUsed;
+// the driver bound to each virtio-mmio slot.
+#[derive(Clone, Copy)]
+pub enum MmioDriver {
+    Blk(usize), // index into virtio_blk::DISKS
+    Console,
+}
 
-    unsafe {
-        memset(disk_ref.desc as *mut u8, 0, PGSIZE);
-        memset(disk_ref.avail as *mut u8, 0, PGSIZE);
-        memset(disk_ref.used as *mut u8, 0, PGSIZE);
+static MMIO_DRIVERS: SpinMutex<[Option<MmioDriver>; VIRTIO_MMIO_NUM]> =
+    SpinMutex::new([None; VIRTIO_MMIO_NUM]);
+
+// look at every virtio-mmio slot, start a driver for each device we know,
+// and take that slot's irq.
+pub fn probe_mmio_devices() {
+    for slot in 0..VIRTIO_MMIO_NUM {
+        let transport = match MmioTransport::new(virtio_mmio(slot)) {
+            Some(transport) => transport,
+            None => continue, // nothing attached to this slot
+        };
+        let device_id = transport.device_id();
+        let driver = match device_id {
+            virtio_blk::DEVICE_ID => MmioDriver::Blk(virtio_blk::virtio_disk_init(transport)),
+            virtio_console::DEVICE_ID => match virtio_console::virtio_console_init(transport) {
+                Ok(()) => MmioDriver::Console,
+                Err(err) => {
+                    println!("virtio-mmio slot {}: {}", slot, err);
+                    continue;
+                }
+            },
+            _ => {
+                println!("virtio-mmio slot {}: no driver for device id {}", slot, device_id);
+                continue;
+            }
+        };
+        MMIO_DRIVERS.lock()[slot] = Some(driver);
+        if let Err(err) = request_irq(virtio_mmio_irq(slot), virtio_mmio_intr, slot) {
+            println!("virtio-mmio slot {}: {}", slot, err);
+        }
     }
+}
 
-    // set queue size
-    dev_reg_ref.queue_num = QUEUE_NUM as u32;
-
-    // write physical addresses
-    dev_reg_ref.queue_desc_low = disk_ref.desc as *const u8 as u32;
-    dev_reg_ref.queue_desc_high = (disk_ref.desc as *const u8 as u64 >> 32) as u32;
-    dev_reg_ref.queue_driver_low = disk_ref.avail as *const u8 as u32;
-    dev_reg_ref.queue_driver_high = (disk_ref.avail as *const u8 as u64 >> 32) as u32;
-    dev_reg_ref.queue_device_low = disk_ref.used as *const u8 as u32;
-    dev_reg_ref.queue_device_high = (disk_ref.used as *const u8 as u64 >> 32) as u32;
-
-    // queue is ready
-    dev_reg_ref.queue_ready = 0x1;
-
-    // ALL NUM descriptors start out unused
-    for i in 0..QUEUE_NUM{
-        disk_ref.free[i] = false;
+fn virtio_mmio_intr(slot: usize) {
+    let driver = MMIO_DRIVERS.lock()[slot];
+    match driver {
+        Some(MmioDriver::Blk(disk)) => virtio_blk::virtio_disk_intr(disk),
+        Some(MmioDriver::Console) => virtio_console::virtio_console_intr(),
+        None => {}
     }
-
-    status |= STATUS_DRIVER_OK;
-    dev_reg_ref.status = status;
-    // plic.rs and trap.rs arrange for interrupts from VIRTIO0_IRQ.
-
 }
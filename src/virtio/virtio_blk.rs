@@ -1,43 +1,28 @@
 use core::mem::size_of;
-use core::sync::atomic::AtomicBool;
-use core::sync::atomic::Ordering::Relaxed;
+use core::ptr::addr_of;
 
-use super::MMIODeviceLagacyRegisterLayout;
-use crate::memolayout::{self, VIRTIO0};
-use crate::println;
+use super::mmio::MmioTransport;
+use super::virtqueue::{VirtqBuffer, Virtqueue};
+use super::Transport;
+use crate::memolayout::VIRTIO_MMIO_NUM;
+use crate::params::BSIZE;
+use crate::proc::{sleep, wakeup};
 use crate::riscv::PGSIZE;
-use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::spin_lock::SpinMutex;
 
-use super::{VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC};
+use super::{VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_VERSION_1};
 
-lazy_static! {
-    pub static ref DISK: Mutex<Disk> = Mutex::new(Disk {
-        desc: 0 as *mut VirtqDesc,
-        avail: 0 as *mut VirtqAvail,
-        used: 0 as *mut VirtqUsed,
-        free: [true; QUEUE_NUM],
-        used_idx: 0,
-        info: [DiskInfo {
-            b: 0 as *mut DiskBuffer,
-            status: 0
-        }; QUEUE_NUM],
-        ops: [VirtqBlkReq {
-            type_filed: 0,
-            reserved: 0,
-            sector: 0,
-        }; QUEUE_NUM],
-    });
-}
-pub static mut is_finish_rw: AtomicBool = AtomicBool::new(true);
-
-pub const BSIZE: usize = 1024;
+// one per virtio-blk device, in the order probe_mmio_devices() found them.
+pub static DISKS: [SpinMutex<Option<Disk>>; NDISK] =
+    [const { SpinMutex::new(None) }; NDISK];
 
 pub const DEVICE_ID: u32 = 0x2;
 pub const VENDOR_ID: u32 = 0x554d4551;
 
 pub const QUEUE_NUM: usize = 8;
 
+pub const NDISK: usize = VIRTIO_MMIO_NUM;
+
 pub const DISK_PAGES_LEN: usize = 2 * PGSIZE;
 
 pub const VIRTIO_BLK_T_IN: u32 = 0; //read the disk
@@ -124,48 +109,13 @@ pub fn list_feature(feature_bits: u32) {
     }
 }
 
-#[repr(C)]
 pub struct Disk {
-    pub desc: *mut VirtqDesc,
-    pub avail: *mut VirtqAvail,
-    pub used: *mut VirtqUsed,
-    pub free: [bool; QUEUE_NUM],
-    pub used_idx: u16,
+    pub transport: MmioTransport,
+    pub vq: Virtqueue,
     pub info: [DiskInfo; QUEUE_NUM],
     pub ops: [VirtqBlkReq; QUEUE_NUM],
 }
 
-unsafe impl Send for Disk {}
-
-#[repr(C)]
-pub struct VirtqDesc {
-    pub addr: u64,
-    pub len: u32,
-    pub flags: u16,
-    pub next: u16,
-}
-
-#[repr(C)]
-pub struct VirtqAvail {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [u16; QUEUE_NUM],
-    pub unused: u16,
-}
-
-#[repr(C)]
-pub struct VirtqUsedElement {
-    pub id: u32,
-    pub len: u32,
-}
-
-#[repr(C)]
-pub struct VirtqUsed {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [VirtqUsedElement; QUEUE_NUM],
-}
-
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct VirtqBlkReq {
@@ -174,83 +124,167 @@ pub struct VirtqBlkReq {
     pub sector: u64,
 }
 
-#[repr(C)]
 #[derive(Clone, Copy)]
 pub struct DiskInfo {
-    pub b: *mut DiskBuffer,
-    pub status: u8,
+    pub in_use: bool, // this slot's ops[] entry is part of a request
+    pub head: u16,    // head descriptor of the request
+    pub done: bool,   // set by virtio_disk_intr()
+    pub status: u8,   // written by the device
 }
 
-pub struct DiskBuffer {
-    valid: bool,
-    disk: bool, // does disk "own" buf?
-    // some filed omit
-    data: [u8; BSIZE],
+// virtio_disk_rw() waits for its request on its info slot.
+fn request_chan(info: &DiskInfo) -> usize {
+    info as *const DiskInfo as usize
 }
 
-pub fn virtio_disk_intr() {
-    // let _disk = DISK.lock();
+// and for a free slot or descriptors on the disk.
+fn free_chan(n: usize) -> usize {
+    addr_of!(DISKS[n]) as usize
+}
+
+// start the disk behind transport, return its disk number.
+pub fn virtio_disk_init(mut transport: MmioTransport) -> usize {
+    let n = DISKS
+        .iter()
+        .position(|disk| disk.lock().is_none())
+        .expect("virtio_disk_init: too many disks");
+
+    transport.begin_init(|mut feature_bits| {
+        feature_bits &= !(VIRTIO_BLK_F_RO as u64);
+        feature_bits &= !(VIRTIO_BLK_F_SCSI as u64);
+        feature_bits &= !(VIRTIO_BLK_F_CONFIG_WCE as u64);
+        feature_bits &= !(VIRTIO_BLK_F_MQ as u64);
+        feature_bits &= !(VIRTIO_F_ANY_LAYOUT as u64);
+        feature_bits &= !(VIRTIO_F_EVENT_IDX as u64);
+        feature_bits &= !(VIRTIO_F_INDIRECT_DESC as u64);
+        feature_bits & (0xffff_ffff | VIRTIO_F_VERSION_1)
+    });
+
+    // initialize queue 0
+    let vq = transport.create_queue(0, QUEUE_NUM as u16);
+    if vq.size() < 3 {
+        panic!("virtio disk max queue too short");
+    }
+    transport.finish_init();
+
+    *DISKS[n].lock() = Some(Disk {
+        transport,
+        vq,
+        info: [DiskInfo {
+            in_use: false,
+            head: 0,
+            done: false,
+            status: 0,
+        }; QUEUE_NUM],
+        ops: [VirtqBlkReq {
+            type_filed: 0,
+            reserved: 0,
+            sector: 0,
+        }; QUEUE_NUM],
+    });
+    n
+}
+
+// is there a disk n?
+pub fn virtio_disk_exists(n: usize) -> bool {
+    n < NDISK && DISKS[n].lock().is_some()
+}
+
+pub fn virtio_disk_intr(n: usize) {
+    let mut guard = DISKS[n].lock();
+    let disk = match guard.as_mut() {
+        Some(disk) => disk,
+        None => return,
+    };
     // the device won't raise another interrupt until we tell it
     // we've seen this interrupt, which the following line does.
     // this may race with the device writing new entries to
     // the "used" ring, in which case we may process the new
     // completion entries in this interrupt, and have nothing to do
     // in the next interrupt, which is harmless.
-    let _dev_reg_ref = unsafe { &mut *(VIRTIO0 as u64 as *mut MMIODeviceLagacyRegisterLayout) };
+    disk.transport.ack_interrupt();
 
-    unsafe {
-        is_finish_rw.store(true, Relaxed);
+    while let Some((head, _len)) = disk.vq.poll_used() {
+        let info = disk
+            .info
+            .iter_mut()
+            .find(|info| info.in_use && info.head == head)
+            .expect("virtio_disk_intr: unknown request");
+        if info.status != 0 {
+            panic!("virtio_disk_intr status");
+        }
+        info.done = true;
+        wakeup(request_chan(info));
     }
 }
 
-pub fn virtio_disk_rw(data: [u8; BSIZE], write: bool) {
-    let sector = 0;
-    let mut disk_ref = DISK.lock();
+// read or write block blockno of disk n, sleeping until it is done.
+pub fn virtio_disk_rw(n: usize, blockno: u32, data: &mut [u8; BSIZE], write: bool) {
+    let sector = blockno as u64 * (BSIZE / 512) as u64;
+    let mut guard = DISKS[n].lock();
 
-    // format the three descriptors.
-    // qemu's virtio-blk.c reads them.
-    let buf0 = &mut disk_ref.ops[0];
-    let buf0_addr = buf0 as *const VirtqBlkReq as u64;
+    // a request needs a slot for its header and three descriptors.
+    let slot = loop {
+        let disk = guard.as_mut().expect("virtio disk not initialized");
+        let slot = disk.info.iter().position(|info| !info.in_use);
+        match slot {
+            Some(slot) if disk.vq.num_free() >= 3 => break slot,
+            _ => guard = sleep(free_chan(n), guard),
+        }
+    };
+    let disk = guard.as_mut().unwrap();
+
+    // the spec's Section 5.2 says that legacy block operations use
+    // three descriptors: one for type/reserved/sector, one for the
+    // data, one for a 1-byte status result.
+    let buf0 = &mut disk.ops[slot];
     if write {
         buf0.type_filed = VIRTIO_BLK_T_OUT; // write the disk
     } else {
         buf0.type_filed = VIRTIO_BLK_T_IN; // read the disk
     }
-
-    // the spec's Section 5.2 says that legacy block operations use
-    // three descriptors: one for type/reserved/sector, one for the
-    // data, one for a 1-byte status result.
-
     buf0.reserved = 0;
     buf0.sector = sector;
-    let desc_array = unsafe { &mut *(disk_ref.desc as *mut [VirtqDesc; QUEUE_NUM]) };
-    desc_array[0].addr = buf0_addr;
-    desc_array[0].len = size_of::<VirtqBlkReq>() as u32;
-    desc_array[0].flags = 1;
-    desc_array[0].next = 1;
-
-    desc_array[1].addr = &data as *const [u8; BSIZE] as u64;
-    desc_array[1].len = BSIZE as u32;
-    if write {
-        desc_array[1].flags = 0;
-    }
-    desc_array[1].flags |= 1;
-    desc_array[1].next = 2;
 
-    disk_ref.info[0].status = 0xff;
-    desc_array[2].addr = &disk_ref.info[0].status as *const u8 as u64;
-    desc_array[2].len = 1;
-    desc_array[2].flags = 2;
-    desc_array[2].next = 0;
+    let bufs = [
+        VirtqBuffer {
+            addr: buf0 as *const VirtqBlkReq as u64,
+            len: size_of::<VirtqBlkReq>() as u32,
+            write: false,
+        },
+        VirtqBuffer {
+            addr: data.as_ptr() as u64,
+            len: BSIZE as u32,
+            write: !write, // device writes the data when reading the disk
+        },
+        VirtqBuffer {
+            addr: &disk.info[slot].status as *const u8 as u64,
+            len: 1,
+            write: true,
+        },
+    ];
+    let head = disk
+        .vq
+        .add(&bufs)
+        .expect("virtio_disk_rw: no free descriptors");
+    disk.info[slot] = DiskInfo {
+        in_use: true,
+        head,
+        done: false,
+        status: 0xff, // device writes 0 on success
+    };
+    disk.vq.submit(head);
+    disk.transport.notify(0); // start device r/w operation
 
-    let mut avail_ref = unsafe { &mut *disk_ref.avail };
-    avail_ref.ring[avail_ref.idx as usize % QUEUE_NUM] = 0;
-    avail_ref.idx += 1;
-    let dev_reg_ref =
-        unsafe { &mut *(memolayout::VIRTIO0 as u64 as *mut MMIODeviceLagacyRegisterLayout) };
-    unsafe {
-        is_finish_rw.store(false, Relaxed);
+    // wait for virtio_disk_intr() to say request has finished.
+    loop {
+        let info = &guard.as_ref().unwrap().info[slot];
+        if info.done {
+            break;
+        }
+        let chan = request_chan(info);
+        guard = sleep(chan, guard);
     }
-    dev_reg_ref.queue_notify = 0; // start device r/w operation
-    unsafe { while !is_finish_rw.load(Relaxed) {} }
+    guard.as_mut().unwrap().info[slot].in_use = false;
+    wakeup(free_chan(n));
 }
@@ -0,0 +1,521 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::{addr_of, read_volatile};
+
+use super::mmio::MmioTransport;
+use super::virtqueue::{VirtqBuffer, Virtqueue};
+use super::{Transport, VIRTIO_F_VERSION_1};
+use crate::file::{Devsw, DEVSW, VIRTIO_CONSOLE};
+use crate::proc::{either_copyin, either_copyout, killed, myproc, sleep, wakeup};
+use crate::spin_lock::SpinMutex;
+use crate::{debug, info, warn};
+
+// virtio-console, section 5.3 of the virtio spec. with the MULTIPORT
+// feature a device carries several ports, each a byte stream to some
+// host chardev, and a pair of control queues over which the device
+// announces ports (with names) and both ends say when they open or
+// close them. port n is minor n of the VIRTIO_CONSOLE major, so
+// mknod("/dev/<name>", VIRTIO_CONSOLE, n) makes a named port a device
+// file; the log says which name each port has.
+
+pub const DEVICE_ID: u32 = 3;
+
+const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+// control message events.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// queue numbers. port 0 has 0 and 1, the control queues come next,
+// then the other ports two by two.
+const CTRL_RX_QUEUE: u16 = 2;
+const CTRL_TX_QUEUE: u16 = 3;
+
+fn port_rx_queue(port: usize) -> u16 {
+    if port == 0 {
+        0
+    } else {
+        (2 * port + 2) as u16
+    }
+}
+
+fn port_tx_queue(port: usize) -> u16 {
+    port_rx_queue(port) + 1
+}
+
+// ports we drive; the device may offer more.
+pub const NPORT: usize = 8;
+
+const QUEUE_NUM: u16 = 8;
+const PORT_RX_BUFS: usize = 4;
+const PORT_BUF_SIZE: usize = 256;
+const PORT_INPUT_SIZE: usize = 1024;
+const CTRL_RX_BUFS: usize = 8;
+const CTRL_BUF_SIZE: usize = 128; // a header and a port name
+const CTRL_TX_BUFS: usize = 8;
+const PORT_NAME_MAX: usize = 32;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtioConsoleControl {
+    id: u32, // port number
+    event: u16,
+    value: u16,
+}
+
+// the device specific part of the register block.
+#[repr(C)]
+struct VirtioConsoleConfig {
+    cols: u16,
+    rows: u16,
+    max_nr_ports: u32,
+    emerg_wr: u32,
+}
+
+struct Port {
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_bufs: Box<[[u8; PORT_BUF_SIZE]; PORT_RX_BUFS]>,
+    rx_heads: [u16; PORT_RX_BUFS], // descriptor each rx buffer sits in
+    rx_posted: bool,               // the rx buffers are with the device
+    // bytes the device sent and nobody read yet.
+    input: Box<[u8; PORT_INPUT_SIZE]>,
+    r: usize,
+    w: usize,
+    // one write in flight at a time.
+    tx_buf: Box<[u8; PORT_BUF_SIZE]>,
+    tx_busy: bool,
+    name: [u8; PORT_NAME_MAX],
+    name_len: usize,
+    added: bool,     // the device announced this port
+    host_open: bool, // something is connected at the host end
+    console: bool,   // the device wants this port to be a console
+}
+
+impl Port {
+    fn new(rx: Virtqueue, tx: Virtqueue) -> Self {
+        Port {
+            rx,
+            tx,
+            rx_bufs: Box::new([[0; PORT_BUF_SIZE]; PORT_RX_BUFS]),
+            rx_heads: [0; PORT_RX_BUFS],
+            rx_posted: false,
+            input: Box::new([0; PORT_INPUT_SIZE]),
+            r: 0,
+            w: 0,
+            tx_buf: Box::new([0; PORT_BUF_SIZE]),
+            tx_busy: false,
+            name: [0; PORT_NAME_MAX],
+            name_len: 0,
+            added: false,
+            host_open: false,
+            console: false,
+        }
+    }
+
+    // readers sleep on the input buffer, writers on the output buffer.
+    fn rx_chan(&self) -> usize {
+        self.input.as_ptr() as usize
+    }
+
+    fn tx_chan(&self) -> usize {
+        self.tx_buf.as_ptr() as usize
+    }
+
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    fn post_rx(&mut self, i: usize) {
+        let buf = VirtqBuffer {
+            addr: self.rx_bufs[i].as_ptr() as u64,
+            len: PORT_BUF_SIZE as u32,
+            write: true,
+        };
+        let head = self.rx.add(&[buf]).expect("virtio_console: rx queue full");
+        self.rx_heads[i] = head;
+        self.rx.submit(head);
+    }
+
+    // give the device every rx buffer, when the port is first added.
+    // they stay in the queue from then on, across removal.
+    fn post_all_rx(&mut self) {
+        if self.rx_posted {
+            return;
+        }
+        for i in 0..PORT_RX_BUFS {
+            self.post_rx(i);
+        }
+        self.rx_posted = true;
+    }
+
+    // move what the device sent into the input buffer and hand the
+    // buffers back. input that doesn't fit is dropped. true if the
+    // device had sent anything.
+    fn drain_rx(&mut self) -> bool {
+        let mut got = false;
+        while let Some((head, len)) = self.rx.poll_used() {
+            let i = match self.rx_heads.iter().position(|&h| h == head) {
+                Some(i) => i,
+                None => continue,
+            };
+            let len = (len as usize).min(PORT_BUF_SIZE);
+            for k in 0..len {
+                if self.w - self.r == PORT_INPUT_SIZE {
+                    break;
+                }
+                self.input[self.w % PORT_INPUT_SIZE] = self.rx_bufs[i][k];
+                self.w += 1;
+            }
+            got = true;
+            self.post_rx(i);
+        }
+        got
+    }
+}
+
+struct VirtioConsole {
+    transport: MmioTransport,
+    multiport: bool,
+    ports: Vec<Port>,
+    ctrl_rx: Option<Virtqueue>,
+    ctrl_tx: Option<Virtqueue>,
+    ctrl_rx_bufs: Box<[[u8; CTRL_BUF_SIZE]; CTRL_RX_BUFS]>,
+    ctrl_rx_heads: [u16; CTRL_RX_BUFS],
+    ctrl_tx_bufs: Box<[VirtioConsoleControl; CTRL_TX_BUFS]>,
+    ctrl_tx_heads: [Option<u16>; CTRL_TX_BUFS], // None if the slot is free
+}
+
+// there is one; a second device is left alone.
+static VCONSOLE: SpinMutex<Option<VirtioConsole>> = SpinMutex::new(None);
+
+impl VirtioConsole {
+    fn post_ctrl_rx(&mut self, i: usize) {
+        let vq = self.ctrl_rx.as_mut().unwrap();
+        let buf = VirtqBuffer {
+            addr: self.ctrl_rx_bufs[i].as_ptr() as u64,
+            len: CTRL_BUF_SIZE as u32,
+            write: true,
+        };
+        let head = vq.add(&[buf]).expect("virtio_console: control queue full");
+        self.ctrl_rx_heads[i] = head;
+        vq.submit(head);
+    }
+
+    // free the slots of control messages the device has read.
+    fn reclaim_ctrl_tx(&mut self) {
+        let vq = self.ctrl_tx.as_mut().unwrap();
+        while let Some((head, _)) = vq.poll_used() {
+            for slot in self.ctrl_tx_heads.iter_mut() {
+                if *slot == Some(head) {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        if !self.multiport {
+            return;
+        }
+        let slot = loop {
+            self.reclaim_ctrl_tx();
+            // qemu handles control messages as soon as it is notified,
+            // so a full queue drains quickly.
+            if let Some(slot) = self.ctrl_tx_heads.iter().position(|h| h.is_none()) {
+                break slot;
+            }
+        };
+        self.ctrl_tx_bufs[slot] = VirtioConsoleControl { id, event, value };
+        let buf = VirtqBuffer {
+            addr: &self.ctrl_tx_bufs[slot] as *const VirtioConsoleControl as u64,
+            len: size_of::<VirtioConsoleControl>() as u32,
+            write: false,
+        };
+        let vq = self.ctrl_tx.as_mut().unwrap();
+        let head = vq.add(&[buf]).expect("virtio_console: control queue full");
+        self.ctrl_tx_heads[slot] = Some(head);
+        vq.submit(head);
+        self.transport.notify(CTRL_TX_QUEUE);
+    }
+
+    // act on one message from the control receive queue.
+    fn handle_control(&mut self, msg: &[u8]) {
+        if msg.len() < size_of::<VirtioConsoleControl>() {
+            return;
+        }
+        let id = u32::from_le_bytes([msg[0], msg[1], msg[2], msg[3]]) as usize;
+        let event = u16::from_le_bytes([msg[4], msg[5]]);
+        let value = u16::from_le_bytes([msg[6], msg[7]]);
+        if id >= self.ports.len() {
+            if event == VIRTIO_CONSOLE_DEVICE_ADD {
+                warn!("port {}: only {} ports supported", id, self.ports.len());
+                self.send_control(id as u32, VIRTIO_CONSOLE_PORT_READY, 0);
+            }
+            return;
+        }
+        match event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                let port = &mut self.ports[id];
+                if !port.added {
+                    port.added = true;
+                    port.post_all_rx();
+                    self.transport.notify(port_rx_queue(id));
+                }
+                self.send_control(id as u32, VIRTIO_CONSOLE_PORT_READY, 1);
+                // there is no open() on devices, so the guest end of
+                // every port is open from the start.
+                self.send_control(id as u32, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => {
+                let port = &mut self.ports[id];
+                port.added = false;
+                port.host_open = false;
+                info!("port {} removed", id);
+                wakeup(port.rx_chan());
+                wakeup(port.tx_chan());
+            }
+            VIRTIO_CONSOLE_CONSOLE_PORT => {
+                self.ports[id].console = true;
+                info!("port {} is a console", id);
+            }
+            VIRTIO_CONSOLE_PORT_NAME => {
+                let port = &mut self.ports[id];
+                let name = &msg[size_of::<VirtioConsoleControl>()..];
+                let name = name.split(|&c| c == 0).next().unwrap_or(&[]);
+                let len = name.len().min(PORT_NAME_MAX);
+                port.name[..len].copy_from_slice(&name[..len]);
+                port.name_len = len;
+                info!(
+                    "port {} is {}",
+                    id,
+                    core::str::from_utf8(port.name()).unwrap_or("?")
+                );
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                let port = &mut self.ports[id];
+                port.host_open = value != 0;
+                debug!(
+                    "port {} {} at the host",
+                    id,
+                    if port.host_open { "opened" } else { "closed" }
+                );
+                // a reader waiting on a closed port gets end-of-file.
+                wakeup(port.rx_chan());
+            }
+            VIRTIO_CONSOLE_RESIZE => {}
+            _ => warn!("port {}: unknown control event {}", id, event),
+        }
+    }
+}
+
+// start the console device behind transport.
+pub fn virtio_console_init(mut transport: MmioTransport) -> Result<(), &'static str> {
+    if VCONSOLE.lock().is_some() {
+        return Err("already have a virtio console");
+    }
+    let features = transport
+        .begin_init(|features| features & (VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_F_VERSION_1));
+    let multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    let nports = if multiport {
+        let config = transport.config_space() as *const VirtioConsoleConfig;
+        let max = unsafe { read_volatile(addr_of!((*config).max_nr_ports)) } as usize;
+        max.clamp(1, NPORT)
+    } else {
+        1
+    };
+
+    // every queue has to exist before the device goes live, so set up
+    // those of ports that may only show up later, too.
+    let mut ports = Vec::with_capacity(nports);
+    let mut ctrl_rx = None;
+    let mut ctrl_tx = None;
+    for port in 0..nports {
+        let rx = transport.create_queue(port_rx_queue(port), QUEUE_NUM);
+        let tx = transport.create_queue(port_tx_queue(port), QUEUE_NUM);
+        ports.push(Port::new(rx, tx));
+        if port == 0 && multiport {
+            ctrl_rx = Some(transport.create_queue(CTRL_RX_QUEUE, CTRL_RX_BUFS as u16));
+            ctrl_tx = Some(transport.create_queue(CTRL_TX_QUEUE, CTRL_TX_BUFS as u16));
+        }
+    }
+
+    let mut guard = VCONSOLE.lock();
+    let console = guard.insert(VirtioConsole {
+        transport,
+        multiport,
+        ports,
+        ctrl_rx,
+        ctrl_tx,
+        ctrl_rx_bufs: Box::new([[0; CTRL_BUF_SIZE]; CTRL_RX_BUFS]),
+        ctrl_rx_heads: [0; CTRL_RX_BUFS],
+        ctrl_tx_bufs: Box::new(
+            [VirtioConsoleControl {
+                id: 0,
+                event: 0,
+                value: 0,
+            }; CTRL_TX_BUFS],
+        ),
+        ctrl_tx_heads: [None; CTRL_TX_BUFS],
+    });
+    if multiport {
+        for i in 0..CTRL_RX_BUFS {
+            console.post_ctrl_rx(i);
+        }
+    } else {
+        // without multiport, port 0 just exists, and is always open.
+        console.ports[0].added = true;
+        console.ports[0].host_open = true;
+        console.ports[0].post_all_rx();
+    }
+    console.transport.finish_init();
+
+    if multiport {
+        console.transport.notify(CTRL_RX_QUEUE);
+        // ask the device to announce its ports.
+        console.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+    } else {
+        console.transport.notify(port_rx_queue(0));
+    }
+    info!(
+        "{} port(s){}",
+        nports,
+        if multiport { ", multiport" } else { "" }
+    );
+    drop(guard);
+
+    DEVSW.lock()[VIRTIO_CONSOLE] = Some(Devsw {
+        read: port_read,
+        write: port_write,
+    });
+    Ok(())
+}
+
+pub fn virtio_console_intr() {
+    let mut guard = VCONSOLE.lock();
+    let console = match guard.as_mut() {
+        Some(console) => console,
+        None => return,
+    };
+    console.transport.ack_interrupt();
+
+    if console.multiport {
+        let mut msg = [0u8; CTRL_BUF_SIZE];
+        while let Some((head, len)) = console.ctrl_rx.as_mut().unwrap().poll_used() {
+            let i = match console.ctrl_rx_heads.iter().position(|&h| h == head) {
+                Some(i) => i,
+                None => continue,
+            };
+            let len = (len as usize).min(CTRL_BUF_SIZE);
+            msg[..len].copy_from_slice(&console.ctrl_rx_bufs[i][..len]);
+            console.post_ctrl_rx(i);
+            console.handle_control(&msg[..len]);
+        }
+        console.transport.notify(CTRL_RX_QUEUE);
+        console.reclaim_ctrl_tx();
+    }
+
+    for id in 0..console.ports.len() {
+        let port = &mut console.ports[id];
+        if port.drain_rx() {
+            wakeup(port.rx_chan());
+            // the buffers went back into the queue.
+            console.transport.notify(port_rx_queue(id));
+        }
+        let port = &mut console.ports[id];
+        if port.tx.poll_used().is_some() {
+            port.tx_busy = false;
+            wakeup(port.tx_chan());
+        }
+    }
+}
+
+// user read()s from a port go here. returns what has arrived, waiting
+// for at least one byte; 0 at end-of-file, once the port is gone or
+// closed at the host.
+fn port_read(minor: usize, user_dst: bool, dst: usize, n: usize) -> isize {
+    let proc_index = match myproc() {
+        Some(i) => i,
+        None => return -1,
+    };
+    let mut guard = VCONSOLE.lock();
+    loop {
+        let port = match guard.as_mut().and_then(|c| c.ports.get_mut(minor)) {
+            Some(port) => port,
+            None => return -1,
+        };
+        if port.r != port.w {
+            break;
+        }
+        if !port.added || !port.host_open {
+            return 0;
+        }
+        if killed(proc_index) {
+            return -1;
+        }
+        let chan = port.rx_chan();
+        guard = sleep(chan, guard);
+    }
+    let port = &mut guard.as_mut().unwrap().ports[minor];
+    let mut done = 0;
+    while done < n && port.r != port.w {
+        // copy out the contiguous run up to the end of the buffer.
+        let start = port.r % PORT_INPUT_SIZE;
+        let len = (port.w - port.r).min(PORT_INPUT_SIZE - start).min(n - done);
+        if !either_copyout(user_dst, dst + done, &port.input[start..start + len]) {
+            break;
+        }
+        port.r += len;
+        done += len;
+    }
+    done as isize
+}
+
+// user write()s to a port go here.
+fn port_write(minor: usize, user_src: bool, src: usize, n: usize) -> isize {
+    let mut guard = VCONSOLE.lock();
+    let mut done = 0;
+    while done < n {
+        let console = match guard.as_mut() {
+            Some(console) => console,
+            None => return -1,
+        };
+        let port = match console.ports.get_mut(minor).filter(|port| port.added) {
+            Some(port) => port,
+            None => return -1,
+        };
+        if port.tx_busy {
+            // wait for virtio_console_intr() to say the last write went out.
+            if myproc().is_some_and(killed) {
+                return -1;
+            }
+            let chan = port.tx_chan();
+            guard = sleep(chan, guard);
+            continue;
+        }
+        let len = (n - done).min(PORT_BUF_SIZE);
+        if !either_copyin(&mut port.tx_buf[..len], user_src, src + done) {
+            break;
+        }
+        let buf = VirtqBuffer {
+            addr: port.tx_buf.as_ptr() as u64,
+            len: len as u32,
+            write: false,
+        };
+        let head = port.tx.add(&[buf]).expect("virtio_console: tx queue full");
+        port.tx.submit(head);
+        port.tx_busy = true;
+        console.transport.notify(port_tx_queue(minor));
+        done += len;
+    }
+    done as isize
+}
@@ -0,0 +1,217 @@
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use crate::mem_utils::memset;
+use crate::riscv::PGSIZE;
+use crate::vm::kalloc_n_pages;
+
+// split virtqueue, see section 2.7 of the virtio 1.2 spec.
+// the three parts live in their own pages, so the only alignment
+// requirement left is the queue size being a power of 2.
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1; // chained with another descriptor
+pub const VIRTQ_DESC_F_WRITE: u16 = 2; // device writes (vs read)
+
+#[repr(C)]
+pub struct VirtqDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+// layout of the available ring:
+//   flags: u16, idx: u16, ring: [u16; size], used_event: u16
+#[repr(C)]
+pub struct VirtqAvail {
+    pub flags: u16,
+    pub idx: u16,
+}
+
+#[repr(C)]
+pub struct VirtqUsedElement {
+    pub id: u32,
+    pub len: u32,
+}
+
+// layout of the used ring:
+//   flags: u16, idx: u16, ring: [VirtqUsedElement; size], avail_event: u16
+#[repr(C)]
+pub struct VirtqUsed {
+    pub flags: u16,
+    pub idx: u16,
+}
+
+// one buffer of a request; a request is a chain of these.
+#[derive(Clone, Copy)]
+pub struct VirtqBuffer {
+    pub addr: u64,
+    pub len: u32,
+    pub write: bool, // the device writes into this buffer
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    desc: *mut VirtqDesc,
+    avail: *mut VirtqAvail,
+    used: *mut VirtqUsed,
+    free_head: u16, // free descriptors are chained through desc.next
+    num_free: u16,
+    last_used_idx: u16, // we've looked this far in used->ring
+}
+
+unsafe impl Send for Virtqueue {}
+
+fn alloc_zeroed_pages(len: usize) -> *mut u8 {
+    let n = len.div_ceil(PGSIZE);
+    let mem = kalloc_n_pages(n);
+    unsafe { memset(mem, 0, n * PGSIZE) };
+    mem
+}
+
+impl Virtqueue {
+    pub fn new(index: u16, size: u16) -> Self {
+        if size == 0 || !size.is_power_of_two() {
+            panic!("virtqueue: size must be a power of 2");
+        }
+        let n = size as usize;
+        let desc = alloc_zeroed_pages(size_of::<VirtqDesc>() * n) as *mut VirtqDesc;
+        let avail = alloc_zeroed_pages(size_of::<u16>() * (3 + n)) as *mut VirtqAvail;
+        let used = alloc_zeroed_pages(size_of::<u16>() * 3 + size_of::<VirtqUsedElement>() * n)
+            as *mut VirtqUsed;
+
+        // all descriptors start out unused.
+        for i in 0..n {
+            unsafe { (*desc.add(i)).next = (i + 1) as u16 };
+        }
+        Virtqueue {
+            index,
+            size,
+            desc,
+            avail,
+            used,
+            free_head: 0,
+            num_free: size,
+            last_used_idx: 0,
+        }
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    // physical addresses handed to the transport; the kernel is direct mapped.
+    pub fn desc_addr(&self) -> u64 {
+        self.desc as u64
+    }
+
+    pub fn avail_addr(&self) -> u64 {
+        self.avail as u64
+    }
+
+    pub fn used_addr(&self) -> u64 {
+        self.used as u64
+    }
+
+    fn desc(&mut self, i: u16) -> &mut VirtqDesc {
+        unsafe { &mut *self.desc.add(i as usize) }
+    }
+
+    fn avail_ring(&self, i: u16) -> *mut u16 {
+        unsafe { (self.avail.add(1) as *mut u16).add((i % self.size) as usize) }
+    }
+
+    fn used_ring(&self, i: u16) -> *const VirtqUsedElement {
+        unsafe { (self.used.add(1) as *const VirtqUsedElement).add((i % self.size) as usize) }
+    }
+
+    // chain the buffers into free descriptors.
+    // returns the head descriptor index, which identifies the request
+    // until it comes back from poll_used(). the request is not visible
+    // to the device before submit().
+    pub fn add(&mut self, bufs: &[VirtqBuffer]) -> Option<u16> {
+        if bufs.is_empty() || bufs.len() > self.num_free as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut last = head;
+        let mut i = head;
+        for (n, buf) in bufs.iter().enumerate() {
+            let next = self.desc(i).next;
+            let d = self.desc(i);
+            d.addr = buf.addr;
+            d.len = buf.len;
+            d.flags = if buf.write { VIRTQ_DESC_F_WRITE } else { 0 };
+            if n + 1 < bufs.len() {
+                d.flags |= VIRTQ_DESC_F_NEXT;
+            }
+            last = i;
+            i = next;
+        }
+        self.free_head = i;
+        self.num_free -= bufs.len() as u16;
+        self.desc(last).next = 0;
+        Some(head)
+    }
+
+    // tell the device about a chain returned by add().
+    // the caller still has to notify the device through its transport.
+    pub fn submit(&mut self, head: u16) {
+        unsafe {
+            let idx = read_volatile(&(*self.avail).idx);
+            write_volatile(self.avail_ring(idx), head);
+            // the device must see the ring entry before the new index.
+            fence(Ordering::SeqCst);
+            write_volatile(&mut (*self.avail).idx, idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+    }
+
+    // return the next request the device has finished with, as
+    // (head descriptor, bytes written by the device), and free its descriptors.
+    pub fn poll_used(&mut self) -> Option<(u16, u32)> {
+        fence(Ordering::SeqCst);
+        let used_idx = unsafe { read_volatile(&(*self.used).idx) };
+        if self.last_used_idx == used_idx {
+            return None;
+        }
+        // the device has updated the index; read the element only after it.
+        fence(Ordering::SeqCst);
+        let elem = unsafe { &*self.used_ring(self.last_used_idx) };
+        let head = unsafe { read_volatile(&elem.id) } as u16;
+        let len = unsafe { read_volatile(&elem.len) };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        self.free_chain(head);
+        Some((head, len))
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let mut i = head;
+        loop {
+            let flags = self.desc(i).flags;
+            let next = self.desc(i).next;
+            self.num_free += 1;
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                let free_head = self.free_head;
+                let d = self.desc(i);
+                d.addr = 0;
+                d.len = 0;
+                d.flags = 0;
+                d.next = free_head;
+                break;
+            }
+            i = next;
+        }
+        self.free_head = head;
+    }
+}
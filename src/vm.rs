@@ -1,11 +1,15 @@
 use core::alloc::Layout;
 use core::panic;
+use core::ptr::NonNull;
 
+use crate::aplic::aplic_window;
 use crate::mem_utils::memmove;
 use crate::memolayout::{
     get_etext, get_trampoline, KERNELBASE, PCI_BASE, PHYSTOP, PLIC, TRAMPOLINE, UART, VIRTIO0,
+    VIRTIO_MMIO_NUM, VIRTIO_MMIO_STRIDE,
 };
 use crate::params::NPROC;
+use crate::pci::pci_mapped_windows;
 use crate::{println, riscv::*, ALLOCATOR};
 use crate::{MAKE_SATP, PA2PTE, PGROUNDDOWN, PTE2PA, PX};
 #[repr(C)]
@@ -30,8 +34,14 @@ fn kvmmake(pgtbl: &mut PageTable) {
     // uart registers
     kvmmap(pgtbl, UART, UART, PGSIZE, PTE_R | PTE_W);
 
-    // virtio mmio disk interface
-    kvmmap(pgtbl, VIRTIO0, VIRTIO0, PGSIZE, PTE_R | PTE_W);
+    // virtio mmio interfaces
+    kvmmap(
+        pgtbl,
+        VIRTIO0,
+        VIRTIO0,
+        VIRTIO_MMIO_NUM * VIRTIO_MMIO_STRIDE,
+        PTE_R | PTE_W,
+    );
 
     // PLIC
     kvmmap(pgtbl, PLIC, PLIC, 0x400000, PTE_R | PTE_W);
@@ -64,6 +74,14 @@ fn kvmmake(pgtbl: &mut PageTable) {
         (1 << 12) * (1 << 16),
         PTE_R | PTE_W,
     );
+    // where pci bars get assigned
+    for (base, size) in pci_mapped_windows().into_iter().flatten() {
+        kvmmap(pgtbl, base, base, size, PTE_R | PTE_W);
+    }
+    // the aplic's supervisor domain, if there is one.
+    if let Some((base, size)) = aplic_window() {
+        kvmmap(pgtbl, base, base, size, PTE_R | PTE_W);
+    }
     // kvmmap(pgtbl, va, pa, sz, perm)
     proc_mapstack(pgtbl);
 }
@@ -143,6 +161,27 @@ pub fn kalloc() -> *mut u8 {
     }
 }
 
+// like kalloc(), but None when memory runs out.
+pub fn try_kalloc() -> Option<*mut u8> {
+    unsafe {
+        ALLOCATOR
+            .lock()
+            .allocate_first_fit(Layout::from_size_align_unchecked(PGSIZE, PGSIZE))
+            .ok()
+            .map(|pa| pa.as_ptr())
+    }
+}
+
+// free the page at pa, which kalloc() or try_kalloc() gave out.
+pub fn kfree(pa: *mut u8) {
+    let pa = NonNull::new(pa).expect("kfree");
+    unsafe {
+        ALLOCATOR
+            .lock()
+            .deallocate(pa, Layout::from_size_align_unchecked(PGSIZE, PGSIZE))
+    };
+}
+
 pub fn kalloc_n_pages(n: usize) -> *mut u8 {
     unsafe {
         ALLOCATOR
@@ -178,3 +217,94 @@ pub fn uvminit(pgtbl: &mut PageTable, initcode: &[u8]) {
     );
     unsafe { memmove(mem, initcode.as_ptr(), sz) };
 }
+
+// given a parent process's page table, copy
+// its memory into a child's page table.
+// copies both the page table and the
+// physical memory.
+pub fn uvmcopy(old: &mut PageTable, new: &mut PageTable, sz: usize) {
+    for va in (0..sz).step_by(PGSIZE) {
+        let pte = *walk(old, va, false).expect("uvmcopy: pte should exist");
+        if pte & PTE_V == 0 {
+            panic!("uvmcopy: page not present");
+        }
+        let pa = PTE2PA!(pte) as usize;
+        let flags = pte & 0x3ff;
+        let mem = kalloc();
+        unsafe { memmove(mem, pa as *const u8, PGSIZE) };
+        mappages(new, va, mem as usize, PGSIZE, flags & !PTE_V);
+    }
+}
+
+// look up a virtual address, return the physical address,
+// or None if not mapped.
+// can only be used to look up user pages.
+pub fn walkaddr(pgtbl: &mut PageTable, va: usize) -> Option<usize> {
+    if va >= MAXVA as usize {
+        return None;
+    }
+    let pte = walk(pgtbl, va, false).ok()?;
+    if *pte & PTE_V == 0 || *pte & PTE_U == 0 {
+        return None;
+    }
+    Some(PTE2PA!(*pte) as usize)
+}
+
+// copy from kernel to user.
+// copy src to virtual address dstva in a given page table.
+// return false on error.
+pub fn copyout(pgtbl: &mut PageTable, mut dstva: usize, mut src: &[u8]) -> bool {
+    while !src.is_empty() {
+        let va0 = PGROUNDDOWN!(dstva);
+        let pa0 = match walkaddr(pgtbl, va0) {
+            Some(pa0) => pa0,
+            None => return false,
+        };
+        let n = (PGSIZE - (dstva - va0)).min(src.len());
+        unsafe { memmove((pa0 + (dstva - va0)) as *mut u8, src.as_ptr(), n) };
+        src = &src[n..];
+        dstva = va0 + PGSIZE;
+    }
+    true
+}
+
+// copy from user to kernel.
+// copy to dst from virtual address srcva in a given page table.
+// return false on error.
+pub fn copyin(pgtbl: &mut PageTable, mut dst: &mut [u8], mut srcva: usize) -> bool {
+    while !dst.is_empty() {
+        let va0 = PGROUNDDOWN!(srcva);
+        let pa0 = match walkaddr(pgtbl, va0) {
+            Some(pa0) => pa0,
+            None => return false,
+        };
+        let n = (PGSIZE - (srcva - va0)).min(dst.len());
+        unsafe { memmove(dst.as_mut_ptr(), (pa0 + (srcva - va0)) as *const u8, n) };
+        dst = &mut dst[n..];
+        srcva = va0 + PGSIZE;
+    }
+    true
+}
+
+// copy a null-terminated string from user to kernel.
+// copy bytes to dst from virtual address srcva in a given page table,
+// until a '\0', or dst is full. returns the length without the '\0',
+// or None on error or if the string doesn't fit.
+pub fn copyinstr(pgtbl: &mut PageTable, dst: &mut [u8], mut srcva: usize) -> Option<usize> {
+    let mut got = 0;
+    while got < dst.len() {
+        let va0 = PGROUNDDOWN!(srcva);
+        let pa0 = walkaddr(pgtbl, va0)?;
+        let n = (PGSIZE - (srcva - va0)).min(dst.len() - got);
+        let src = unsafe { core::slice::from_raw_parts((pa0 + (srcva - va0)) as *const u8, n) };
+        for &c in src {
+            if c == 0 {
+                return Some(got);
+            }
+            dst[got] = c;
+            got += 1;
+        }
+        srcva = va0 + PGSIZE;
+    }
+    None
+}